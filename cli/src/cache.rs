@@ -0,0 +1,244 @@
+use std::{
+    cmp::Reverse,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use nix_dev_env::{NixProfileCacheMetadata, has_cached_profile_files, is_cache_dir_name};
+
+use crate::{config::get_cache_dir, constants::CLI_NAME};
+
+pub fn list(provided_cache_dir: Option<&Path>) -> anyhow::Result<()> {
+    let cache_dir = get_cache_dir(provided_cache_dir)?;
+    let rows = get_cached_profiles(&cache_dir)?
+        .into_iter()
+        .map(|cached_profile| {
+            let (flake_reference, size, gcroots) = match &cached_profile.metadata {
+                Some(metadata) => (
+                    metadata.flake_reference.clone(),
+                    metadata
+                        .pinned_size()
+                        .map(format_size)
+                        .unwrap_or_else(|_| String::from("-")),
+                    format!(
+                        "{}: {} ({})",
+                        metadata.gcroots_mode,
+                        metadata.gcroots.len(),
+                        if metadata.gcroots_alive() {
+                            "ok"
+                        } else {
+                            "missing"
+                        }
+                    ),
+                ),
+                None => (String::from("-"), String::from("-"), String::from("-")),
+            };
+            [
+                flake_reference,
+                size,
+                format_last_used(cached_profile.last_used_at),
+                gcroots,
+                cached_profile.dir.display().to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let header = ["FLAKE REFERENCE", "SIZE", "LAST USED", "GCROOTS", "PATH"].map(String::from);
+    let widths = rows
+        .iter()
+        .fold(header.clone().map(|h| h.len()), |acc, row| {
+            let mut widths = acc;
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+            widths
+        });
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Prints the metadata of every profile cached for the flake reference, e.g. both the pure and
+/// impure builds of it.
+pub fn inspect(provided_cache_dir: Option<&Path>, flake_reference: &str) -> anyhow::Result<()> {
+    let cache_dir = get_cache_dir(provided_cache_dir)?;
+    let metadata = get_cached_profiles(&cache_dir)?
+        .into_iter()
+        .filter_map(|cached_profile| cached_profile.metadata)
+        .filter(|metadata| {
+            metadata.flake_reference == flake_reference
+                || metadata
+                    .cache_key
+                    .as_ref()
+                    .is_some_and(|cache_key| cache_key.flake_reference == flake_reference)
+        })
+        .collect::<Vec<_>>();
+    if metadata.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No cached profile found for `{flake_reference}`."
+        ));
+    }
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+    Ok(())
+}
+
+pub fn prune(
+    provided_cache_dir: Option<&Path>,
+    older_than: Duration,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let cache_dir = get_cache_dir(provided_cache_dir)?;
+    let cutoff = SystemTime::now()
+        .checked_sub(older_than)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    for cached_profile in get_cached_profiles(&cache_dir)? {
+        if cached_profile.last_used_at >= cutoff {
+            continue;
+        }
+        if !dry_run {
+            fs::remove_dir_all(&cached_profile.dir)?;
+        }
+        println!(
+            "{} {}",
+            if dry_run { "Would remove" } else { "Removed" },
+            cached_profile.display_name()
+        );
+    }
+    Ok(())
+}
+
+pub fn clear(provided_cache_dir: Option<&Path>) -> anyhow::Result<()> {
+    let cache_dir = get_cache_dir(provided_cache_dir)?;
+    let cached_profiles = get_cached_profiles(&cache_dir)?;
+    // NB: Profiles whose metadata can't be read are cleared too, as they're of no use anymore
+    for dir in get_dirs(&cache_dir)? {
+        if !is_cached_profile_dir(&dir) {
+            println!("Skipped {}, as it isn't a cached profile", dir.display());
+            continue;
+        }
+        fs::remove_dir_all(&dir)?;
+        match cached_profiles
+            .iter()
+            .find(|cached_profile| cached_profile.dir == dir)
+        {
+            Some(cached_profile) => println!("Removed {}", cached_profile.display_name()),
+            None => println!("Removed {}", dir.display()),
+        }
+    }
+    Ok(())
+}
+
+struct CachedProfile {
+    dir: PathBuf,
+    metadata: Option<NixProfileCacheMetadata>,
+    last_used_at: SystemTime,
+}
+
+impl CachedProfile {
+    fn display_name(&self) -> String {
+        match &self.metadata {
+            Some(metadata) => format!("{} ({})", metadata.flake_reference, self.dir.display()),
+            None => self.dir.display().to_string(),
+        }
+    }
+}
+
+/// Profiles whose metadata can't be read are skipped with a warning, so that a single broken
+/// profile doesn't hide all of the others.
+fn get_cached_profiles(cache_dir: &Path) -> anyhow::Result<Vec<CachedProfile>> {
+    let mut cached_profiles = vec![];
+    for dir in get_cached_profile_dirs(cache_dir)? {
+        let metadata = match NixProfileCacheMetadata::find(&dir) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                eprintln!(
+                    "{CLI_NAME}: skipping {}, as its metadata can't be read: {err}",
+                    dir.display()
+                );
+                continue;
+            }
+        };
+        // Profiles cached before metadata was recorded fall back to when they were written
+        let last_used_at = match &metadata {
+            Some(metadata) => metadata.last_used_at,
+            None => fs::metadata(&dir)?.modified()?,
+        };
+        cached_profiles.push(CachedProfile {
+            dir,
+            metadata,
+            last_used_at,
+        });
+    }
+    cached_profiles.sort_by_key(|cached_profile| Reverse(cached_profile.last_used_at));
+    Ok(cached_profiles)
+}
+
+/// Directories of the cache dir that profiles are cached in, leaving out anything else that's kept
+/// there.
+fn get_cached_profile_dirs(cache_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = get_dirs(cache_dir)?;
+    dirs.retain(|dir| is_cached_profile_dir(dir));
+    Ok(dirs)
+}
+
+fn is_cached_profile_dir(dir: &Path) -> bool {
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(is_cache_dir_name)
+        || has_cached_profile_files(dir)
+}
+
+fn get_dirs(cache_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let read_dir = match fs::read_dir(cache_dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(read_dir
+        .filter_map(Result::ok)
+        .filter(|dir_entry| dir_entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|dir_entry| dir_entry.path())
+        .collect())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_last_used(last_used_at: SystemTime) -> String {
+    let elapsed = SystemTime::now()
+        .duration_since(last_used_at)
+        .unwrap_or_default()
+        .as_secs();
+    let (amount, unit) = match elapsed {
+        0..60 => return String::from("just now"),
+        60..3_600 => (elapsed / 60, "minute"),
+        3_600..86_400 => (elapsed / 3_600, "hour"),
+        _ => (elapsed / 86_400, "day"),
+    };
+    format!("{amount} {unit}{} ago", if amount == 1 { "" } else { "s" })
+}
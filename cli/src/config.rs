@@ -3,9 +3,12 @@ use std::{
     ffi::OsStr,
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use duct::cmd;
+use env_hooks::{DelimitedEnvVar, DelimitedEnvVars, EnvVarsFilter};
+use nix_dev_env::GcRootsMode;
 use path_clean::PathClean;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -33,14 +36,22 @@ pub fn add_entry(
     pattern: String,
     flake_reference: String,
     pattern_adjacent: Option<String>,
+    name: Option<String>,
     impure: Option<bool>,
+    gcroots: Option<GcRootsMode>,
 ) -> anyhow::Result<()> {
     let entry = ConfigEntry {
+        name,
         pattern: Regex::new(&pattern)?,
         pattern_adjacent: pattern_adjacent.and_then(|s| Regex::new(&s).ok()),
         config: Config {
             flake_reference,
             impure,
+            gcroots,
+            nix_args: None,
+            hook_output: None,
+            on_enter: None,
+            on_leave: None,
         },
     };
     let config_path = get_config_path(provided_config_path)?;
@@ -67,9 +78,35 @@ pub fn print_matching_entries(
     Ok(())
 }
 
+const DEFAULT_PATH_VARS: &[&str] = &["PATH", "XDG_DATA_DIRS"];
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EnvoluntaryConfig {
     entries: Option<Vec<ConfigEntry>>,
+    /// Paths under which no entry gets activated, e.g. huge monorepos or network mounts.
+    deny: Option<Vec<DenyEntry>>,
+    /// Default for which store paths get pinned by entries that don't set `gcroots` themselves.
+    pub gcroots: Option<GcRootsMode>,
+    /// Default for how the output of a devshell's shell hook gets shown by entries that don't set
+    /// `hook_output` themselves.
+    pub hook_output: Option<HookOutput>,
+    /// Whether to evaluate flakes without network access, reusing whatever profiles are cached.
+    pub offline: Option<bool>,
+    /// How long loading an environment may take before it's given up on, e.g. `2m`.
+    #[serde(default, with = "humantime_duration")]
+    pub timeout: Option<Duration>,
+    /// Whether to hide the progress of Nix builds.
+    pub quiet: Option<bool>,
+    /// Variables holding lists of entries, which get merged with the values already set instead
+    /// of being replaced. Added to, or overriding, the defaults for `PATH` and `XDG_DATA_DIRS`.
+    pub path_vars: Option<DelimitedEnvVars>,
+    /// Patterns for variables that never get imported from a flake's environment, on top of the
+    /// ones that are always ignored.
+    pub ignored_env_vars: Option<Vec<String>>,
+    /// When set, only variables matching one of these patterns get imported.
+    pub allowed_env_vars: Option<Vec<String>>,
+    /// Where the state needed to unload an environment is kept.
+    pub state_store: Option<StateStoreKind>,
 }
 
 impl EnvoluntaryConfig {
@@ -97,6 +134,44 @@ impl EnvoluntaryConfig {
         Ok(())
     }
 
+    pub fn delimited_env_vars(&self) -> DelimitedEnvVars {
+        let mut delimited_env_vars = DEFAULT_PATH_VARS
+            .iter()
+            .map(|path_var| (String::from(*path_var), DelimitedEnvVar::default()))
+            .collect::<DelimitedEnvVars>();
+        delimited_env_vars.extend(self.path_vars.clone().unwrap_or_default());
+        delimited_env_vars
+    }
+
+    pub fn env_vars_filter(&self) -> anyhow::Result<EnvVarsFilter> {
+        let mut env_vars_filter =
+            EnvVarsFilter::new().with_ignored(self.ignored_env_vars.as_deref().unwrap_or(&[]))?;
+        if let Some(allowed_env_vars) = &self.allowed_env_vars {
+            env_vars_filter = env_vars_filter.with_allowed(allowed_env_vars)?;
+        }
+        Ok(env_vars_filter)
+    }
+
+    /// The config for a manually loaded profile, which is either the name of an entry or a flake
+    /// reference.
+    pub fn profile_config(&self, profile: &str) -> Config {
+        self.entries
+            .as_deref()
+            .unwrap_or(&[])
+            .iter()
+            .find(|entry| entry.name.as_deref() == Some(profile))
+            .map(|entry| entry.config.clone())
+            .unwrap_or_else(|| Config {
+                flake_reference: String::from(profile),
+                impure: None,
+                gcroots: None,
+                nix_args: None,
+                hook_output: None,
+                on_enter: None,
+                on_leave: None,
+            })
+    }
+
     pub fn matching_entries(&self, path: &Path) -> anyhow::Result<Vec<ConfigEntry>> {
         let absolute_path = if path.is_absolute() {
             path.to_path_buf()
@@ -106,6 +181,22 @@ impl EnvoluntaryConfig {
         .clean();
         let absolute_path_string = absolute_path.to_string_lossy();
         let absolute_path_string_with_tilde = replace_home_with_tilde(&absolute_path_string);
+        // NB: Deny rules are checked before any entry, so that no adjacent files get looked for
+        if self
+            .deny
+            .as_deref()
+            .unwrap_or(&[])
+            .iter()
+            .any(|deny_entry| {
+                path_is_match_with_or_without_home_tilde(
+                    &absolute_path_string,
+                    absolute_path_string_with_tilde.as_ref(),
+                    &deny_entry.pattern,
+                )
+            })
+        {
+            return Ok(vec![]);
+        }
         let matched_entries = self
             .entries
             .as_deref()
@@ -181,8 +272,38 @@ fn replace_home_with_tilde(path_string: impl AsRef<str>) -> Option<String> {
     })
 }
 
+/// (De)serializes durations in the format `humantime` uses, e.g. `1m 30s`.
+mod humantime_duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => {
+                serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|duration| humantime::parse_duration(&duration).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigEntry {
+    /// Name to load the entry by with `envoluntary load`, wherever the shell is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     #[serde(with = "serde_regex")]
     pub pattern: Regex,
     #[serde(with = "serde_regex", default)]
@@ -191,10 +312,53 @@ pub struct ConfigEntry {
     pub config: Config,
 }
 
+/// Blocks activation in every directory matching the pattern, whatever entries match it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DenyEntry {
+    #[serde(with = "serde_regex")]
+    pub pattern: Regex,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub flake_reference: String,
     pub impure: Option<bool>,
+    pub gcroots: Option<GcRootsMode>,
+    /// Extra arguments passed to Nix when evaluating the flake.
+    pub nix_args: Option<Vec<String>>,
+    pub hook_output: Option<HookOutput>,
+    /// Commands run in the shell after the environment is loaded.
+    pub on_enter: Option<Vec<String>>,
+    /// Commands run in the shell after the environment is unloaded.
+    pub on_leave: Option<Vec<String>>,
+}
+
+/// How the output of a devshell's shell hook, e.g. the devshell motd, gets shown.
+///
+/// The shell hook only runs when the profile is built, so its output is replayed from the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookOutput {
+    /// Shown every time the environment is loaded.
+    #[default]
+    Show,
+    /// Never shown.
+    Quiet,
+    /// Only shown the first time the environment is loaded in a shell session.
+    OncePerSession,
+}
+
+/// Where the state needed to unload an environment is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StateStoreKind {
+    /// In an env var of the shell, which every child process inherits.
+    #[default]
+    Env,
+    /// In a file per shell session under `$XDG_RUNTIME_DIR`, with only a token pointing at it kept
+    /// in the env var. Falls back to `env` when `$XDG_RUNTIME_DIR` isn't set, or the shell's hook
+    /// was evaluated before session ids were generated.
+    File,
 }
 
 pub fn get_config_path(provided_config_path: Option<&Path>) -> anyhow::Result<PathBuf> {
@@ -229,6 +393,6 @@ fn get_cache_home_dir() -> anyhow::Result<PathBuf> {
     Ok(home.join(".cache"))
 }
 
-fn get_home_dir() -> anyhow::Result<PathBuf> {
+pub fn get_home_dir() -> anyhow::Result<PathBuf> {
     env::home_dir().ok_or_else(|| anyhow::anyhow!("Couldn't find $HOME for config."))
 }
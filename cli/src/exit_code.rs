@@ -0,0 +1,67 @@
+pub const FAILURE: u8 = 1;
+/// The config file, or an entry in it, is invalid.
+pub const CONFIG_INVALID: u8 = 3;
+pub const NIX_NOT_FOUND: u8 = 4;
+pub const NIX_TOO_OLD: u8 = 5;
+/// A flake didn't evaluate, or its dev shell didn't build.
+pub const EVAL_FAILED: u8 = 6;
+/// A flake, one of its inputs or a store path couldn't be fetched.
+pub const FETCH_FAILED: u8 = 7;
+/// Sourcing a profile's rc file in bash failed.
+pub const SHELL_HOOK_FAILED: u8 = 8;
+/// The state of the current shell couldn't be decoded, or its state file is gone.
+pub const STATE_DECODE_FAILED: u8 = 9;
+/// The same as `timeout(1)` exits with.
+pub const TIMED_OUT: u8 = 124;
+/// What shells exit with when a command is interrupted by `SIGINT`.
+pub const CANCELLED: u8 = 130;
+
+/// Finds the exit code for the first error in the chain with a known cause, so that scripts and
+/// tools wrapping the CLI can tell the reasons for failing apart.
+pub fn for_error(err: &anyhow::Error) -> u8 {
+    err.chain()
+        .find_map(|cause| {
+            if let Some(nix_dev_env_error) = cause.downcast_ref::<nix_dev_env::Error>() {
+                for_nix_dev_env_error(nix_dev_env_error)
+            } else if let Some(env_hooks_error) = cause.downcast_ref::<env_hooks::Error>() {
+                for_env_hooks_error(env_hooks_error)
+            } else if cause.is::<::config::ConfigError>() || cause.is::<regex::Error>() {
+                Some(CONFIG_INVALID)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(FAILURE)
+}
+
+fn for_nix_dev_env_error(err: &nix_dev_env::Error) -> Option<u8> {
+    match err {
+        nix_dev_env::Error::NixNotFound { .. } => Some(NIX_NOT_FOUND),
+        nix_dev_env::Error::NixTooOld { .. } | nix_dev_env::Error::NixVersionUnknown { .. } => {
+            Some(NIX_TOO_OLD)
+        }
+        nix_dev_env::Error::EvalFailed { .. } => Some(EVAL_FAILED),
+        nix_dev_env::Error::FetchFailed { .. } => Some(FETCH_FAILED),
+        nix_dev_env::Error::Timeout { .. } => Some(TIMED_OUT),
+        nix_dev_env::Error::Cancelled { .. } => Some(CANCELLED),
+        nix_dev_env::Error::InvalidFlakeReference { .. }
+        | nix_dev_env::Error::FlakeNotFound { .. }
+        | nix_dev_env::Error::InvalidGcRootsMode { .. } => Some(CONFIG_INVALID),
+        _ => None,
+    }
+}
+
+fn for_env_hooks_error(err: &env_hooks::Error) -> Option<u8> {
+    match err {
+        env_hooks::Error::BashNotFound | env_hooks::Error::BashFailed { .. } => {
+            Some(SHELL_HOOK_FAILED)
+        }
+        env_hooks::Error::StateDecode(_)
+        | env_hooks::Error::StateVersionMismatch { .. }
+        | env_hooks::Error::StateFileNotFound { .. } => Some(STATE_DECODE_FAILED),
+        env_hooks::Error::InvalidSessionId { .. } => Some(CONFIG_INVALID),
+        env_hooks::Error::Timeout => Some(TIMED_OUT),
+        env_hooks::Error::Cancelled => Some(CANCELLED),
+        _ => None,
+    }
+}
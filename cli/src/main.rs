@@ -1,17 +1,31 @@
+mod cache;
 mod config;
 mod constants;
+mod exit_code;
 mod opt;
+mod progress;
 mod shell;
 
+use std::process::ExitCode;
+
 use clap::Parser;
 
 use crate::opt::{
-    Envoluntary, EnvoluntaryCommands, EnvoluntaryConfigCommands, EnvoluntaryShellCommands,
+    Envoluntary, EnvoluntaryCacheCommands, EnvoluntaryCommands, EnvoluntaryConfigCommands,
+    EnvoluntaryShellCommands,
 };
 
-fn main() -> anyhow::Result<()> {
-    let opt = Envoluntary::parse();
+fn main() -> ExitCode {
+    match run(Envoluntary::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code::for_error(&err))
+        }
+    }
+}
 
+fn run(opt: Envoluntary) -> anyhow::Result<()> {
     match opt.command {
         EnvoluntaryCommands::Config { config } => match config {
             EnvoluntaryConfigCommands::PrintPath => {
@@ -26,7 +40,9 @@ fn main() -> anyhow::Result<()> {
                     args.pattern,
                     args.flake_reference,
                     args.pattern_adjacent,
+                    args.name,
                     args.impure,
+                    args.gcroots,
                 )?;
             }
             EnvoluntaryConfigCommands::PrintMatchingEntries(args) => {
@@ -43,10 +59,57 @@ fn main() -> anyhow::Result<()> {
             EnvoluntaryShellCommands::Export(args) => {
                 shell::print_export(args)?;
             }
+            EnvoluntaryShellCommands::Reset(args) => {
+                shell::print_reset(args)?;
+            }
+            EnvoluntaryShellCommands::Diff(args) => {
+                shell::print_diff(args)?;
+            }
+            EnvoluntaryShellCommands::Enter(args) => {
+                shell::enter(args)?;
+            }
             EnvoluntaryShellCommands::PrintCachePath(args) => {
                 shell::print_cache_path(args)?;
             }
         },
+        EnvoluntaryCommands::Load(args) => {
+            shell::print_manual_change(shell::ManualChange::Load(args.profile), args.shell_args)?;
+        }
+        EnvoluntaryCommands::Unload(args) => {
+            shell::print_manual_change(shell::ManualChange::Unload(args.profile), args.shell_args)?;
+        }
+        EnvoluntaryCommands::Reload(args) => {
+            shell::print_manual_change(shell::ManualChange::Reload, args.shell_args)?;
+        }
+        EnvoluntaryCommands::Pause(args) => {
+            shell::print_manual_change(shell::ManualChange::Pause, args.shell_args)?;
+        }
+        EnvoluntaryCommands::Resume(args) => {
+            shell::print_manual_change(shell::ManualChange::Resume, args.shell_args)?;
+        }
+        EnvoluntaryCommands::Status(args) => {
+            shell::print_status(args)?;
+        }
+        EnvoluntaryCommands::Exec(args) => {
+            shell::exec(args)?;
+        }
+        EnvoluntaryCommands::Env(args) => {
+            shell::print_env(args)?;
+        }
+        EnvoluntaryCommands::Cache { cache } => match cache {
+            EnvoluntaryCacheCommands::List(args) => {
+                cache::list(args.cache_dir.as_deref())?;
+            }
+            EnvoluntaryCacheCommands::Inspect(args) => {
+                cache::inspect(args.cache_dir.as_deref(), &args.flake_reference)?;
+            }
+            EnvoluntaryCacheCommands::Prune(args) => {
+                cache::prune(args.cache_dir.as_deref(), args.older_than, args.dry_run)?;
+            }
+            EnvoluntaryCacheCommands::Clear(args) => {
+                cache::clear(args.cache_dir.as_deref())?;
+            }
+        },
     };
 
     Ok(())
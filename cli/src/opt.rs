@@ -1,6 +1,7 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{ffi::OsString, path::PathBuf, time::Duration};
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use nix_dev_env::GcRootsMode;
 
 /// A Nix flake-based development environment manager for automatic shell integration.
 ///
@@ -32,6 +33,55 @@ pub enum EnvoluntaryCommands {
         #[command(subcommand)]
         shell: EnvoluntaryShellCommands,
     },
+    /// Manage cached Nix profiles.
+    ///
+    /// These commands show what the cached profiles are, how much of the Nix store they keep
+    /// alive through garbage collector roots, and remove the ones that are no longer needed.
+    Cache {
+        #[command(subcommand)]
+        cache: EnvoluntaryCacheCommands,
+    },
+
+    /// Load an environment into the current shell, whatever directory it's in.
+    ///
+    /// The environment stays loaded across directory changes until it's unloaded. The shell hook
+    /// defines a function wrapping this command, so that it changes the current shell.
+    Load(EnvoluntaryLoadArgs),
+
+    /// Unload environments that were loaded with `envoluntary load`.
+    ///
+    /// Environments loaded for the current directory by the shell hook stay loaded.
+    Unload(EnvoluntaryUnloadArgs),
+
+    /// Rebuild and reload every environment loaded in the current shell.
+    Reload(EnvoluntaryReloadArgs),
+
+    /// Stop loading environments in the current shell, until it's resumed.
+    ///
+    /// Every environment loaded in the shell is unloaded, and changing directories loads none.
+    Pause(EnvoluntaryPauseArgs),
+
+    /// Load environments in the current shell again after it was paused.
+    Resume(EnvoluntaryResumeArgs),
+
+    /// Describe the environments loaded in the current shell.
+    ///
+    /// Shows the flake references loaded, the entries matching the current directory, when each
+    /// profile was built and whether it's stale, and the variables the environments added,
+    /// changed or removed.
+    Status(EnvoluntaryStatusArgs),
+
+    /// Run a command with the environment of a directory, without a shell hook.
+    ///
+    /// Entries are matched and their profiles built like the shell hook does, and the command
+    /// runs with the resulting variables, e.g. in CI jobs, editors, cron jobs or git hooks.
+    Exec(EnvoluntaryExecArgs),
+
+    /// Print the environment of a directory, e.g. for editors to start language servers with.
+    ///
+    /// Entries are matched and their profiles built like the shell hook does. Prints the
+    /// variables the environment sets, or every variable with `--full`.
+    Env(EnvoluntaryEnvArgs),
 }
 
 /// Configuration management subcommands.
@@ -105,6 +155,10 @@ pub struct EnvoluntaryConfigAddEntryArgs {
     #[arg(long)]
     pub pattern_adjacent: Option<String>,
 
+    /// A name to load the entry by with `envoluntary load`, in any directory.
+    #[arg(long)]
+    pub name: Option<String>,
+
     /// Whether to evaluate the flake in impure mode.
     ///
     /// If set to `true`, Nix will evaluate the flake with `--impure`, allowing access to environment variables
@@ -112,6 +166,12 @@ pub struct EnvoluntaryConfigAddEntryArgs {
     #[arg(long)]
     pub impure: Option<bool>,
 
+    /// Which store paths to protect from garbage collection: `none`, `profile` or `profile+inputs`.
+    ///
+    /// If not provided, uses the global `gcroots` setting, which defaults to `profile+inputs`.
+    #[arg(long)]
+    pub gcroots: Option<GcRootsMode>,
+
     /// Path to the configuration file (overrides default location).
     ///
     /// If not provided, uses the default configuration path.
@@ -154,6 +214,27 @@ pub enum EnvoluntaryShellCommands {
     /// configuration entries for the current directory. This is called by the shell hook.
     Export(EnvoluntaryShellExportArgs),
 
+    /// Unload every environment and clear the state of the current shell.
+    ///
+    /// Generates shell commands that undo what the loaded environments changed, even when their
+    /// state can't be read anymore, e.g. after upgrading Envoluntary. Evaluate the output in the
+    /// shell, e.g. `eval "$(envoluntary shell reset bash)"`.
+    Reset(EnvoluntaryShellResetArgs),
+
+    /// Preview the variables that `shell export` would change in the current shell.
+    ///
+    /// Matches entries and builds their profiles like the shell hook does, then prints which
+    /// variables would be added, changed or removed, and the entries of path-like variables
+    /// added, removed or reordered. Nothing is printed for the shell to evaluate.
+    Diff(EnvoluntaryShellDiffArgs),
+
+    /// Start a shell with the environment of a directory loaded.
+    ///
+    /// Starts `$SHELL` in the directory, with the environment loaded after its startup files
+    /// and `ENVOLUNTARY_SHELL` set to the directory, e.g. for prompts. The environment ends
+    /// when the shell exits, so no shell hook is needed.
+    Enter(EnvoluntaryShellEnterArgs),
+
     /// Print the cache path for a given Nix flake reference.
     ///
     /// Shows where Envoluntary caches the compiled profiles for a specific flake reference.
@@ -211,6 +292,9 @@ pub struct EnvoluntaryShellExportArgs {
     #[arg(long)]
     pub force_update: bool,
 
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+
     /// The directory path to check for matching configuration entries (for testing).
     ///
     /// If not provided, uses the current working directory.
@@ -219,6 +303,71 @@ pub struct EnvoluntaryShellExportArgs {
     pub current_dir: Option<PathBuf>,
 }
 
+/// Arguments for the `shell reset` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellResetArgs {
+    /// The shell for which to generate reset code.
+    ///
+    /// The export syntax varies by shell
+    pub shell: EnvoluntaryShell,
+
+    /// Path to the configuration file (overrides default location).
+    ///
+    /// If not provided, uses the default configuration path.
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+}
+
+/// Arguments for the `shell diff` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellDiffArgs {
+    /// Path to the configuration file (overrides default location).
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+
+    /// The directory to preview the environment of.
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub current_dir: Option<PathBuf>,
+
+    /// How to print the changes.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: EnvoluntaryOutputFormat,
+}
+
+/// Arguments for the `shell enter` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellEnterArgs {
+    /// The directory whose matching entries get loaded, and the shell starts in.
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+
+    /// The shell to start (overrides `$SHELL`).
+    #[arg(long)]
+    pub shell: Option<EnvoluntaryShell>,
+
+    /// Path to the configuration file (overrides default location).
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+}
+
 /// Arguments for the `shell print-cache-path` command.
 #[derive(Debug, Clone, Args)]
 pub struct EnvoluntaryShellPrintCachePathArgs {
@@ -228,6 +377,61 @@ pub struct EnvoluntaryShellPrintCachePathArgs {
     #[arg(long)]
     pub flake_reference: String,
 
+    /// Get the cache path for the flake evaluated in impure mode.
+    #[arg(long)]
+    pub impure: bool,
+
+    /// Extra argument passed to Nix when evaluating the flake (can be repeated).
+    #[arg(long = "nix-arg", allow_hyphen_values = true)]
+    pub nix_args: Vec<String>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Cache management subcommands.
+#[derive(Debug, Clone, Subcommand)]
+pub enum EnvoluntaryCacheCommands {
+    /// List every cached profile.
+    ///
+    /// Shows the flake reference each profile was built from, the size of the Nix store paths it
+    /// keeps alive, when it was last used, and whether its garbage collector roots are intact.
+    List(EnvoluntaryCacheListArgs),
+
+    /// Print the recorded metadata of the cached profile for a flake reference.
+    Inspect(EnvoluntaryCacheInspectArgs),
+
+    /// Remove cached profiles that haven't been used for a while.
+    ///
+    /// Removing a cached profile also removes its garbage collector roots, which allows
+    /// `nix store gc` to reclaim the store paths it kept alive.
+    Prune(EnvoluntaryCachePruneArgs),
+
+    /// Remove every cached profile.
+    Clear(EnvoluntaryCacheClearArgs),
+}
+
+/// Arguments for the `cache list` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryCacheListArgs {
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Arguments for the `cache inspect` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryCacheInspectArgs {
+    /// The Nix flake reference of the cached profile to inspect.
+    ///
+    /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
+    pub flake_reference: String,
+
     /// Directory for caching Nix profiles (overrides default cache location).
     ///
     /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
@@ -235,6 +439,248 @@ pub struct EnvoluntaryShellPrintCachePathArgs {
     pub cache_dir: Option<PathBuf>,
 }
 
+/// Arguments for the `cache prune` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryCachePruneArgs {
+    /// Remove cached profiles that haven't been used within this duration (e.g. `30days`).
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub older_than: Duration,
+
+    /// Only print the cached profiles that would be removed.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Arguments for the `cache clear` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryCacheClearArgs {
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Arguments for the `load` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryLoadArgs {
+    /// The name of a configuration entry, or a Nix flake reference, to load.
+    ///
+    /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
+    pub profile: String,
+
+    #[command(flatten)]
+    pub shell_args: EnvoluntaryShellCommandArgs,
+}
+
+/// Arguments for the `unload` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryUnloadArgs {
+    /// The name of a configuration entry, or a Nix flake reference, to unload.
+    ///
+    /// If not provided, every environment loaded with `envoluntary load` is unloaded.
+    pub profile: Option<String>,
+
+    #[command(flatten)]
+    pub shell_args: EnvoluntaryShellCommandArgs,
+}
+
+/// Arguments for the `reload` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryReloadArgs {
+    #[command(flatten)]
+    pub shell_args: EnvoluntaryShellCommandArgs,
+}
+
+/// Arguments for the `pause` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryPauseArgs {
+    #[command(flatten)]
+    pub shell_args: EnvoluntaryShellCommandArgs,
+}
+
+/// Arguments for the `resume` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryResumeArgs {
+    #[command(flatten)]
+    pub shell_args: EnvoluntaryShellCommandArgs,
+}
+
+/// Arguments for the `status` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryStatusArgs {
+    /// Path to the configuration file (overrides default location).
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// The directory the shell is in (for testing).
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub current_dir: Option<PathBuf>,
+
+    /// How to print the status.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: EnvoluntaryOutputFormat,
+}
+
+/// Arguments for the `exec` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryExecArgs {
+    /// The directory whose matching entries get loaded.
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+
+    /// Nix flake references to load instead of the entries matching the directory.
+    ///
+    /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
+    #[arg(long = "flake-reference")]
+    pub flake_references: Vec<String>,
+
+    /// Path to the configuration file (overrides default location).
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+
+    /// The command to run, and its arguments.
+    #[arg(last = true, required = true)]
+    pub command: Vec<OsString>,
+}
+
+/// Arguments for the `env` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryEnvArgs {
+    /// The directory whose matching entries get loaded.
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub dir: Option<PathBuf>,
+
+    /// How to print the environment.
+    ///
+    /// JSON also holds the matched entries, and whether their profiles were fresh in the cache.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: EnvoluntaryEnvFormat,
+
+    /// Print every variable of the resulting environment, not only the ones it sets.
+    #[arg(long)]
+    pub full: bool,
+
+    /// Path to the configuration file (overrides default location).
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+}
+
+/// Arguments shared by the commands that change the environment of the current shell.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellCommandArgs {
+    /// The shell for which to generate export code.
+    ///
+    /// Passed by the function the shell hook defines, which evaluates the output.
+    #[arg(long)]
+    pub shell: EnvoluntaryShell,
+
+    /// Path to the configuration file (overrides default location).
+    ///
+    /// If not provided, uses the default configuration path.
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub build_args: EnvoluntaryBuildArgs,
+
+    /// The directory the shell is in (for testing).
+    ///
+    /// If not provided, uses the current working directory.
+    #[arg(long)]
+    pub current_dir: Option<PathBuf>,
+}
+
+/// Arguments shared by the commands that load environments, about how they get built.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryBuildArgs {
+    /// Don't access the network when evaluating flakes.
+    ///
+    /// Cached profiles are reused even if the flake changed since they were built, and entries
+    /// that can't be built without the network are skipped with a warning.
+    /// Can also be enabled with `offline = true` in the configuration file.
+    #[arg(long, env = "ENVOLUNTARY_OFFLINE")]
+    pub offline: bool,
+
+    /// Give up on loading the environment if it takes longer than this (e.g. `2m`).
+    ///
+    /// Every Nix command and shell hook still running is stopped, and the shell's environment is
+    /// left as it was. Can also be set with `timeout` in the configuration file.
+    #[arg(long, env = "ENVOLUNTARY_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Don't show the progress of Nix builds.
+    ///
+    /// Progress is only shown when stderr is a terminal. Can also be enabled with `quiet = true`
+    /// in the configuration file.
+    #[arg(long, env = "ENVOLUNTARY_QUIET")]
+    pub quiet: bool,
+}
+
+/// Output formats for commands describing the state of the shell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum EnvoluntaryOutputFormat {
+    /// Readable by people.
+    #[default]
+    Human,
+    /// JSON output format (useful for machine parsing).
+    Json,
+}
+
+/// Formats the environment of a directory can be printed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum EnvoluntaryEnvFormat {
+    /// JSON output format (useful for machine parsing).
+    #[default]
+    Json,
+    /// `KEY=value` lines, as read from `.env` files.
+    Dotenv,
+    /// `KEY=value` lines, as read from a systemd `EnvironmentFile`.
+    Systemd,
+    /// `KEY=value` lines, as read by `docker run --env-file`.
+    ///
+    /// Variables holding newlines can't be written, so they're left out.
+    Docker,
+    /// The format of the file `$GITHUB_ENV` points to in GitHub Actions.
+    GithubActions,
+}
+
 /// Supported shells for hook and export code generation.
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum EnvoluntaryShell {
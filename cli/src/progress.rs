@@ -0,0 +1,65 @@
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use nix_dev_env::NixProgress;
+
+use crate::constants::CLI_NAME;
+
+/// Moves to the start of the line and clears it.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// A single line on stderr showing what Nix is busy with, redrawn in place.
+pub struct ProgressLine {
+    started_at: Instant,
+    drawn: AtomicBool,
+}
+
+impl ProgressLine {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            drawn: AtomicBool::new(false),
+        }
+    }
+
+    pub fn draw(&self, progress: &NixProgress) {
+        let elapsed =
+            humantime::format_duration(Duration::from_secs(self.started_at.elapsed().as_secs()));
+        let mut activities = vec![];
+        if progress.builds_running > 0 || progress.builds_done > 0 {
+            activities.push(format!(
+                "building {} ({} done)",
+                progress.builds_running, progress.builds_done
+            ));
+        }
+        if progress.downloads_running > 0 || progress.downloads_done > 0 {
+            activities.push(format!(
+                "downloading {} ({} done)",
+                progress.downloads_running, progress.downloads_done
+            ));
+        }
+        if activities.is_empty() {
+            activities.push(String::from("evaluating"));
+        }
+        let mut stderr = io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "{CLEAR_LINE}{CLI_NAME}: {}, {elapsed}",
+            activities.join(", ")
+        );
+        let _ = stderr.flush();
+        self.drawn.store(true, Ordering::Relaxed);
+    }
+
+    /// Removes the line, so that whatever gets printed next starts on a clean line.
+    pub fn clear(&self) {
+        if self.drawn.swap(false, Ordering::Relaxed) {
+            let mut stderr = io::stderr().lock();
+            let _ = write!(stderr, "{CLEAR_LINE}");
+            let _ = stderr.flush();
+        }
+    }
+}
@@ -1,195 +1,465 @@
-use std::collections::HashSet;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::fmt;
 use std::path::Path;
-use std::{io::Read, os::unix::ffi::OsStrExt, path::PathBuf};
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{self, DirBuilder},
+    io::{self, IsTerminal, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::DirBuilderExt,
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
 
-use base64::{Engine, prelude::BASE64_STANDARD};
-use bstr::B;
+use anyhow::Context;
+use bstr::{B, BString, ByteSlice};
 use env_hooks::{
-    BashSource, EnvVars, EnvVarsState, get_env_vars_from_bash, get_env_vars_from_current_process,
-    get_env_vars_reset, get_old_env_vars_to_be_updated, merge_delimited_env_var,
-    remove_ignored_env_vars, shells,
+    BashEnv, BashLimits, BashSource, DelimitedEnvVars, EnvVars, EnvVarsFilter, EnvVarsState,
+    ShellDefinitions, ShellDefinitionsState,
+    codec::{ChunkedEnvVar, Compression, PlainStateCodec, StateCodec, VersionedStateCodec},
+    get_bash_env_in_dir, get_bash_env_within_limits, get_env_vars_from_current_process,
+    get_env_vars_reset, get_env_vars_restore, get_old_env_vars_to_be_updated,
+    merge_delimited_env_vars, remove_filtered_env_vars, remove_ignored_env_vars, shells,
     state::{self, GetEnvStateVar, MatchRcs},
+    store::{self, EnvStateStore, FileStateStore, StateStore},
+};
+use nix_dev_env::{
+    EvaluationMode, NixCacheKey, NixProfileCache, NixProfileCacheMetadata, NixProgressReporter,
+    check_nix_version, remove_stale_cache_dirs,
 };
-use nix_dev_env::{EvaluationMode, NixProfileCache, check_nix_version};
-use once_cell::sync::Lazy;
+use semver::Version;
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 use shell_quote::{Bash, Fish, Zsh};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 
-use crate::config::{Config, EnvoluntaryConfig, get_cache_dir, get_config_path};
+use crate::config::{
+    Config, ConfigEntry, EnvoluntaryConfig, HookOutput, StateStoreKind, get_cache_dir,
+    get_config_path, get_home_dir,
+};
 use crate::constants::CLI_NAME;
+use crate::exit_code;
 use crate::opt::{
-    EnvoluntaryShell, EnvoluntaryShellExportArgs, EnvoluntaryShellPrintCachePathArgs,
+    EnvoluntaryEnvArgs, EnvoluntaryEnvFormat, EnvoluntaryExecArgs, EnvoluntaryOutputFormat,
+    EnvoluntaryShell, EnvoluntaryShellCommandArgs, EnvoluntaryShellDiffArgs,
+    EnvoluntaryShellEnterArgs, EnvoluntaryShellExportArgs, EnvoluntaryShellPrintCachePathArgs,
+    EnvoluntaryShellResetArgs, EnvoluntaryStatusArgs,
 };
+use crate::progress::ProgressLine;
 
 const ENVOLUNTARY_ENV_STATE_VAR_KEY: &str = "ENVOLUNTARY_ENV_STATE";
 
-const ENV_VAR_KEY_PATH: &str = "PATH";
-const ENV_VAR_KEY_XDG_DATA_DIRS: &str = "XDG_DATA_DIRS";
+/// Bumped whenever the fields of [`EnvoluntaryEnvState`] change incompatibly.
+const ENVOLUNTARY_ENV_STATE_VERSION: u32 = 1;
+
+/// Flake references whose shell hook output was shown in this shell session, one per line. It's
+/// kept when environments are unloaded, so it lasts as long as the session.
+const ENVOLUNTARY_HOOKS_RUN_VAR_KEY: &str = "ENVOLUNTARY_HOOKS_RUN";
+
+/// Starts the name of the files in the cache dir holding the system each version of Nix builds
+/// for, followed by the version.
+const NIX_SYSTEM_FILE_PREFIX: &str = "nix-system-";
+
+/// Where snapshots of the state of each shell session are kept, within the runtime dir.
+const STATE_SNAPSHOTS_DIR: &str = "snapshots";
 
-static SEMICOLON_DELIMITED_ENV_VARS: Lazy<HashSet<String>> = Lazy::new(|| {
-    let mut semicolon_delimited_env_vars = HashSet::new();
-    semicolon_delimited_env_vars.insert(String::from(ENV_VAR_KEY_PATH));
-    semicolon_delimited_env_vars.insert(String::from(ENV_VAR_KEY_XDG_DATA_DIRS));
-    semicolon_delimited_env_vars
-});
+/// Commands whose output the function the hook defines evaluates in the shell.
+const MANUAL_CHANGE_SUBCOMMANDS: &[&str] = &["load", "unload", "reload", "pause", "resume"];
+
+/// Set to the id of the shell session loading environments is paused in, so that shells started
+/// from it aren't paused as well.
+const ENVOLUNTARY_PAUSED_VAR_KEY: &str = "ENVOLUNTARY_PAUSED";
+
+/// Set to the directory whose environment a shell started with `envoluntary shell enter` has.
+const ENVOLUNTARY_SHELL_VAR_KEY: &str = "ENVOLUNTARY_SHELL";
+
+/// Generated by the hook for every shell session, and used to name the session's state files.
+const ENVOLUNTARY_SESSION_ID_VAR_KEY: &str = "ENVOLUNTARY_SESSION_ID";
+
+const ENV_VAR_KEY_PATH: &str = "PATH";
 
 pub fn print_hook(shell: EnvoluntaryShell) -> anyhow::Result<()> {
     let current_exe = env::current_exe()?;
 
+    // NB: The hook also defines a function wrapping the CLI, so that the commands changing the
+    // environment can change the current shell
     let hook = match shell {
-        EnvoluntaryShell::Bash => shells::bash::hook(
-            CLI_NAME,
-            bstr::join(
-                " ",
-                [&Bash::quote_vec(&current_exe), B("shell export bash")],
-            ),
-        ),
-        EnvoluntaryShell::Fish => shells::fish::hook(
-            CLI_NAME,
-            bstr::join(
-                " ",
-                [&Fish::quote_vec(&current_exe), B("shell export fish")],
-            ),
-        ),
+        EnvoluntaryShell::Bash => {
+            let program = Bash::quote_vec(&current_exe);
+            [
+                shells::bash::session_id(ENVOLUNTARY_SESSION_ID_VAR_KEY),
+                shells::bash::hook(
+                    CLI_NAME,
+                    bstr::join(" ", [&program, B("shell export bash")]),
+                ),
+                shells::bash::wrapper(CLI_NAME, &program, MANUAL_CHANGE_SUBCOMMANDS),
+            ]
+        }
+        EnvoluntaryShell::Fish => {
+            let program = Fish::quote_vec(&current_exe);
+            [
+                shells::fish::session_id(ENVOLUNTARY_SESSION_ID_VAR_KEY),
+                shells::fish::hook(
+                    CLI_NAME,
+                    bstr::join(" ", [&program, B("shell export fish")]),
+                ),
+                shells::fish::wrapper(CLI_NAME, &program, MANUAL_CHANGE_SUBCOMMANDS),
+            ]
+        }
         EnvoluntaryShell::Json => {
             return Err(anyhow::anyhow!(
                 "JSON isn't is a shell, so there's no hook to use."
             ));
         }
-        EnvoluntaryShell::Nushell => shells::nushell::hook(bstr::join(
-            " ",
+        EnvoluntaryShell::Nushell => {
+            let program = B(current_exe.to_str().unwrap_or_default());
             [
-                B(current_exe.to_str().unwrap_or_default()),
-                B("shell export nushell"),
-            ],
-        )),
-        EnvoluntaryShell::Zsh => shells::zsh::hook(
-            CLI_NAME,
-            bstr::join(" ", [&Zsh::quote_vec(&current_exe), B("shell export zsh")]),
-        ),
+                shells::nushell::session_id(ENVOLUNTARY_SESSION_ID_VAR_KEY),
+                shells::nushell::hook(bstr::join(" ", [program, B("shell export nushell")])),
+                shells::nushell::wrapper(CLI_NAME, program, MANUAL_CHANGE_SUBCOMMANDS),
+            ]
+        }
+        EnvoluntaryShell::Zsh => {
+            let program = Zsh::quote_vec(&current_exe);
+            [
+                shells::zsh::session_id(ENVOLUNTARY_SESSION_ID_VAR_KEY),
+                shells::zsh::hook(CLI_NAME, bstr::join(" ", [&program, B("shell export zsh")])),
+                shells::zsh::wrapper(CLI_NAME, &program, MANUAL_CHANGE_SUBCOMMANDS),
+            ]
+        }
     };
 
-    println!("{}", hook);
+    println!("{}", bstr::concat(hook).as_bstr());
 
     Ok(())
 }
 
 pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
+    export(args, None)
+}
+
+/// A change to the environments loaded with `envoluntary load` in the current shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManualChange {
+    Load(String),
+    /// Unloads the given profile, or every profile when there isn't one.
+    Unload(Option<String>),
+    /// Rebuilds every environment, and loads them again.
+    Reload,
+    /// Unloads every environment, and stops loading any until resumed.
+    Pause,
+    Resume,
+}
+
+pub fn print_manual_change(
+    manual_change: ManualChange,
+    shell_args: EnvoluntaryShellCommandArgs,
+) -> anyhow::Result<()> {
+    let args = EnvoluntaryShellExportArgs {
+        shell: shell_args.shell,
+        config_path: shell_args.config_path,
+        cache_dir: shell_args.cache_dir,
+        flake_references: None,
+        impure: None,
+        force_update: manual_change == ManualChange::Reload,
+        build_args: shell_args.build_args,
+        current_dir: shell_args.current_dir,
+    };
+    export(args, Some(manual_change))
+}
+
+fn export(
+    args: EnvoluntaryShellExportArgs,
+    manual_change: Option<ManualChange>,
+) -> anyhow::Result<()> {
     let config_path = get_config_path(args.config_path.as_deref())?;
     let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    if get_paused(manual_change.as_ref())? && manual_change.is_none() {
+        return Ok(());
+    }
+    let state_store = get_state_store(&envoluntary_config)?;
+    let shell_exports = get_shell_exports(
+        &args,
+        manual_change.as_ref(),
+        &envoluntary_config,
+        &state_store,
+    )?;
+
+    let delimited_env_vars = envoluntary_config.delimited_env_vars();
+    let mut export = Vec::new();
+    for shell_export in shell_exports {
+        write_shell_export(&mut export, args.shell, shell_export, &delimited_env_vars)?;
+    }
+    io::stdout().write_all(&export)?;
+
+    Ok(())
+}
+
+/// Whether loading environments is paused once the manual change is made. Changes other than
+/// pausing and resuming can't be made while paused.
+fn get_paused(manual_change: Option<&ManualChange>) -> anyhow::Result<bool> {
+    let paused = match manual_change {
+        Some(ManualChange::Pause) => true,
+        Some(ManualChange::Resume) => false,
+        _ => is_paused(),
+    };
+    if paused && manual_change.is_some_and(|manual_change| manual_change != &ManualChange::Pause) {
+        anyhow::bail!(
+            "loading environments is paused in this shell, run `{CLI_NAME} resume` first"
+        );
+    }
+    Ok(paused)
+}
+
+/// Whether loading environments was paused in this shell session, rather than in the one it was
+/// started from.
+fn is_paused() -> bool {
+    env::var_os(ENVOLUNTARY_PAUSED_VAR_KEY).is_some_and(|paused| paused == get_paused_value())
+}
+
+/// What the paused var gets set to, i.e. the session id, or `1` in shells without one.
+fn get_paused_value() -> OsString {
+    env::var_os(ENVOLUNTARY_SESSION_ID_VAR_KEY).unwrap_or_else(|| OsString::from("1"))
+}
+
+/// Everything to apply to the shell for the current directory and the manual change, in order.
+fn get_shell_exports(
+    args: &EnvoluntaryShellExportArgs,
+    manual_change: Option<&ManualChange>,
+    envoluntary_config: &EnvoluntaryConfig,
+    state_store: &EnvoluntaryStateStore,
+) -> anyhow::Result<Vec<ShellExport>> {
     let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let offline = args.build_args.offline || envoluntary_config.offline.unwrap_or_default();
+    let delimited_env_vars = envoluntary_config.delimited_env_vars();
+
+    let timeout = args.build_args.timeout.or(envoluntary_config.timeout);
+    let quiet = args.build_args.quiet || envoluntary_config.quiet.unwrap_or_default();
+
+    let nix_version = check_nix_version()?;
+    let current_dir = match &args.current_dir {
+        Some(current_dir) => current_dir.clone(),
+        None => env::current_dir()?,
+    };
+
+    // NB: Signals stop whatever is running, and nothing gets printed for the shell to evaluate
+    let cancelled = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM, SIGHUP] {
+        signal_hook::flag::register(signal, Arc::clone(&cancelled))?;
+    }
 
-    check_nix_version()?;
+    let export_options = ExportOptions {
+        cache_dir: &cache_dir,
+        current_dir: &current_dir,
+        nix_version: &nix_version,
+        delimited_env_vars: &delimited_env_vars,
+        env_vars_filter: &envoluntary_config.env_vars_filter()?,
+        force_update: args.force_update,
+        offline,
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+        cancelled: &cancelled,
+        progress_line: (!quiet && io::stderr().is_terminal())
+            .then(|| Arc::new(ProgressLine::new())),
+        state_store,
+    };
+    // NB: Everything is printed at once, so that an activation that's cancelled or fails partway
+    // through leaves the shell's environment as it was
+    let export = RefCell::new(Vec::new());
+
+    if matches!(
+        manual_change,
+        Some(ManualChange::Pause | ManualChange::Resume)
+    ) {
+        export.borrow_mut().push(ShellExport {
+            env_vars_state: EnvVarsState::from_iter([(
+                BString::from(ENVOLUNTARY_PAUSED_VAR_KEY),
+                (manual_change == Some(&ManualChange::Pause))
+                    .then(|| BString::from(get_paused_value().into_encoded_bytes())),
+            )]),
+            shell_definitions_state: ShellDefinitionsState::new(),
+            commands: vec![],
+        });
+    }
 
-    let current_dir_state = state::ShellPromptState::get_current_dir(args.current_dir)?;
+    let result = export_env_state(
+        args,
+        manual_change,
+        envoluntary_config,
+        &export_options,
+        &export,
+    );
+
+    if cancelled.load(Ordering::Relaxed) {
+        eprintln!("{CLI_NAME}: activation cancelled, the environment was left as it was");
+        process::exit(i32::from(exit_code::CANCELLED));
+    }
+    if let Some(timeout) = timeout
+        && export_options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        result.with_context(|| {
+            format!(
+                "activation timed out after {}, the environment was left as it was",
+                humantime::format_duration(timeout)
+            )
+        })?;
+    } else {
+        result?;
+    }
 
-    let match_rcs = current_dir_state.match_rcs(|current_dir| {
-        let config_values = if let Some(ref flake_references) = args.flake_references {
+    Ok(export.into_inner())
+}
+
+fn export_env_state(
+    args: &EnvoluntaryShellExportArgs,
+    manual_change: Option<&ManualChange>,
+    envoluntary_config: &EnvoluntaryConfig,
+    export_options: &ExportOptions,
+    export: &RefCell<Vec<ShellExport>>,
+) -> anyhow::Result<()> {
+    let delimited_env_vars = export_options.delimited_env_vars;
+    let loaded_manually = get_loaded_manually(export_options.state_store, manual_change)?;
+    let reload = manual_change == Some(&ManualChange::Reload);
+    let pause = manual_change == Some(&ManualChange::Pause);
+
+    let current_dir_state = state::ShellPromptState::get_current_dir(args.current_dir.clone())?;
+
+    let match_rcs = current_dir_state.match_rcs(|current_dir| -> anyhow::Result<_> {
+        if pause {
+            return Ok(vec![]);
+        }
+        let mut config_values = if let Some(ref flake_references) = args.flake_references {
             flake_references
                 .iter()
                 .map(|flake_reference| Config {
                     flake_reference: String::from(flake_reference),
                     impure: args.impure,
+                    gcroots: envoluntary_config.gcroots,
+                    nix_args: None,
+                    hook_output: envoluntary_config.hook_output,
+                    on_enter: None,
+                    on_leave: None,
                 })
                 .collect()
         } else {
             envoluntary_config
                 .matching_entries(current_dir)?
                 .into_iter()
-                .map(|entry| entry.config)
-                .collect()
+                .map(|entry| Config {
+                    impure: args.impure.or(entry.config.impure),
+                    gcroots: entry.config.gcroots.or(envoluntary_config.gcroots),
+                    hook_output: entry.config.hook_output.or(envoluntary_config.hook_output),
+                    ..entry.config
+                })
+                .collect::<Vec<_>>()
         };
+        // NB: Profiles loaded manually are added after the entries matching the directory
+        for profile in &loaded_manually {
+            let config = envoluntary_config.profile_config(profile);
+            if config_values
+                .iter()
+                .any(|config_value| config_value.flake_reference == config.flake_reference)
+            {
+                continue;
+            }
+            config_values.push(Config {
+                impure: args.impure.or(config.impure),
+                gcroots: config.gcroots.or(envoluntary_config.gcroots),
+                hook_output: config.hook_output.or(envoluntary_config.hook_output),
+                ..config
+            });
+        }
         Ok(config_values)
     })?;
 
     match match_rcs {
         MatchRcs::NoRcs(no_rcs_state) => {
             if let Some(ready_for_full_reset_state) =
-                no_rcs_state.get_env_state_var(ENVOLUNTARY_ENV_STATE_VAR_KEY)
+                no_rcs_state.get_env_state_var(export_options.state_store)?
             {
-                ready_for_full_reset_state.reset_env_vars(|env_state_var_value| {
-                    let env_state = EnvoluntaryEnvState::decode(env_state_var_value.as_bytes())?;
-                    print_shell_export(args.shell, env_state.env_vars_reset);
-                    Ok(())
-                })?;
+                ready_for_full_reset_state.reset_env_vars(
+                    |env_state_var_value| -> anyhow::Result<()> {
+                        let env_state =
+                            EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy())?;
+                        let shell_export_restore = env_state.restore(
+                            &get_current_env_vars(),
+                            delimited_env_vars,
+                            export_options.state_store,
+                        );
+                        export.borrow_mut().push(shell_export_restore);
+                        Ok(())
+                    },
+                )?;
             }
         }
         MatchRcs::Rcs(rcs_state) => {
-            let get_env_state_var = rcs_state.get_env_state_var(ENVOLUNTARY_ENV_STATE_VAR_KEY);
+            let get_env_state_var = rcs_state.get_env_state_var(export_options.state_store)?;
             match get_env_state_var {
                 GetEnvStateVar::NoEnvStateVar(no_env_state_var_state) => {
-                    no_env_state_var_state.set_new_env_state_var(|rcs| {
-                        let env_vars_state = rcs.into_iter().try_fold(
-                            EnvVarsState::new(),
-                            |mut acc, config| -> anyhow::Result<EnvVarsState> {
-                                let cache_profile = get_cache_profile(
-                                    &cache_dir,
-                                    &config.flake_reference,
-                                    args.force_update,
-                                    args.impure.or(config.impure),
-                                )?;
-                                acc.extend(get_export_env_vars_state(
-                                    config.flake_reference,
-                                    &cache_profile,
-                                )?);
-                                Ok(acc)
-                            },
+                    no_env_state_var_state.set_new_env_state_var(|rcs| -> anyhow::Result<()> {
+                        let shell_export = get_export_env_vars_state(
+                            rcs,
+                            get_current_env_vars(),
+                            &loaded_manually,
+                            export_options,
                         )?;
 
-                        print_shell_export(args.shell, env_vars_state);
+                        export.borrow_mut().push(shell_export);
 
                         Ok(())
                     })?;
                 }
                 GetEnvStateVar::EnvStateVar(env_state_var_state) => {
                     env_state_var_state.reset_and_set_new_env_state_var(
-                        |rcs, env_state_var_value| {
-                            let env_state =
-                                EnvoluntaryEnvState::decode(env_state_var_value.as_bytes())?;
-
-                            if rcs
-                                .iter()
-                                .map(|config| String::from(&config.flake_reference))
-                                .collect::<Vec<_>>()
-                                == env_state.flake_references
+                        |rcs, env_state_var_value| -> anyhow::Result<_> {
+                            let env_state = EnvoluntaryEnvState::decode(
+                                &env_state_var_value.to_string_lossy(),
+                            )?;
+
+                            if !reload
+                                && env_state.is_loaded(&rcs, export_options.offline)
+                                && loaded_manually == env_state.loaded_manually
                             {
-                                return Ok((rcs, env_state.flake_references));
+                                return Ok((rcs, None));
                             }
 
-                            print_shell_export(args.shell, env_state.env_vars_reset);
+                            // NB: The new environment is built on top of the restored environment,
+                            // rather than on top of the one being unloaded
+                            let mut current_env_vars = get_current_env_vars();
+                            let shell_export_restore = env_state.restore(
+                                &current_env_vars,
+                                delimited_env_vars,
+                                export_options.state_store,
+                            );
+                            current_env_vars.apply(&shell_export_restore.env_vars_state);
+
+                            export.borrow_mut().push(shell_export_restore);
 
-                            Ok((rcs, env_state.flake_references))
+                            Ok((rcs, Some(current_env_vars)))
                         },
-                        |(rcs, env_state_flake_references)| {
-                            if rcs
-                                .iter()
-                                .map(|config| String::from(&config.flake_reference))
-                                .collect::<Vec<_>>()
-                                == env_state_flake_references
-                            {
+                        |(rcs, restored_env_vars)| -> anyhow::Result<()> {
+                            let Some(restored_env_vars) = restored_env_vars else {
                                 return Ok(());
-                            }
+                            };
 
-                            let env_vars_state = rcs.into_iter().try_fold(
-                                EnvVarsState::new(),
-                                |mut acc, config| -> anyhow::Result<EnvVarsState> {
-                                    let cache_profile = get_cache_profile(
-                                        &cache_dir,
-                                        &config.flake_reference,
-                                        args.force_update,
-                                        args.impure.or(config.impure),
-                                    )?;
-                                    acc.extend(get_export_env_vars_state(
-                                        config.flake_reference,
-                                        &cache_profile,
-                                    )?);
-                                    Ok(acc)
-                                },
+                            let shell_export = get_export_env_vars_state(
+                                rcs,
+                                restored_env_vars,
+                                &loaded_manually,
+                                export_options,
                             )?;
 
-                            print_shell_export(args.shell, env_vars_state);
+                            export.borrow_mut().push(shell_export);
 
                             Ok(())
                         },
@@ -202,166 +472,1532 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+pub fn print_reset(args: EnvoluntaryShellResetArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    let delimited_env_vars = envoluntary_config.delimited_env_vars();
+    let state_store = get_state_store(&envoluntary_config)?;
+    let current_env_vars = get_current_env_vars();
+
+    let mut shell_export = if let Some(env_state_var_value) = state_store.load()? {
+        EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy())?.restore(
+            &current_env_vars,
+            &delimited_env_vars,
+            &state_store,
+        )
+    } else {
+        ShellExport {
+            env_vars_state: state_store.clear(&current_env_vars),
+            shell_definitions_state: ShellDefinitionsState::new(),
+            commands: vec![],
+        }
+    };
+    // NB: Hook output is shown again and loading is resumed, as if the shell session just started
+    for key in [ENVOLUNTARY_HOOKS_RUN_VAR_KEY, ENVOLUNTARY_PAUSED_VAR_KEY] {
+        shell_export.env_vars_state.insert(BString::from(key), None);
+    }
+
+    write_shell_export(
+        &mut io::stdout(),
+        args.shell,
+        shell_export,
+        &delimited_env_vars,
+    )?;
+
+    Ok(())
+}
+
+pub fn print_diff(args: EnvoluntaryShellDiffArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    let delimited_env_vars = envoluntary_config.delimited_env_vars();
+    // NB: The state isn't saved anywhere, so the shell is left as it was
+    let state_store = get_state_store(&envoluntary_config)?.with_dry_run(true);
+    let export_args = EnvoluntaryShellExportArgs {
+        shell: EnvoluntaryShell::Json,
+        config_path: args.config_path,
+        cache_dir: args.cache_dir,
+        flake_references: None,
+        impure: None,
+        force_update: false,
+        build_args: args.build_args,
+        current_dir: args.current_dir,
+    };
+    let shell_exports = if get_paused(None)? {
+        vec![]
+    } else {
+        get_shell_exports(&export_args, None, &envoluntary_config, &state_store)?
+    };
+
+    let current_env_vars = get_current_env_vars();
+    let new_env_vars = get_env_vars_exported(&current_env_vars, &shell_exports);
+    let [current_env_vars, new_env_vars] =
+        [current_env_vars, new_env_vars].map(remove_internal_env_vars);
+    let env_vars_diff = EnvVarsDiff::new(&current_env_vars, &new_env_vars, &delimited_env_vars);
+
+    match args.format {
+        EnvoluntaryOutputFormat::Human if env_vars_diff.is_empty() => println!("No changes"),
+        EnvoluntaryOutputFormat::Human => env_vars_diff.write_human(
+            &mut io::stdout(),
+            io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        )?,
+        EnvoluntaryOutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&env_vars_diff)?)
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the process with the command, with the environment of the directory loaded.
+pub fn exec(args: EnvoluntaryExecArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    // NB: Only the command gets the environment, so the state isn't saved anywhere
+    let state_store = get_state_store(&envoluntary_config)?.with_dry_run(true);
+    let export_args = EnvoluntaryShellExportArgs {
+        shell: EnvoluntaryShell::Json,
+        config_path: args.config_path,
+        cache_dir: args.cache_dir,
+        flake_references: (!args.flake_references.is_empty()).then_some(args.flake_references),
+        impure: None,
+        force_update: false,
+        build_args: args.build_args,
+        current_dir: args.dir,
+    };
+    let shell_exports = get_shell_exports(&export_args, None, &envoluntary_config, &state_store)?;
+    let env_vars = get_env_vars_exported(&get_current_env_vars(), &shell_exports);
+
+    let (program, program_args) = args
+        .command
+        .split_first()
+        .context("no command to run was given")?;
+    let err = process::Command::new(program)
+        .args(program_args)
+        .env_clear()
+        .envs(
+            env_vars
+                .iter()
+                .map(|(key, value)| (OsStr::from_bytes(key), OsStr::from_bytes(value))),
+        )
+        .exec();
+    Err(err).with_context(|| format!("couldn't run `{}`", program.to_string_lossy()))
+}
+
+/// Starts a shell with the environment of the directory loaded, and exits with its exit code once
+/// it exits.
+pub fn enter(args: EnvoluntaryShellEnterArgs) -> anyhow::Result<()> {
+    let shell = match args.shell {
+        Some(shell) => shell,
+        None => get_user_shell()?,
+    };
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    let delimited_env_vars = envoluntary_config.delimited_env_vars();
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    // NB: Only the new shell gets the environment, so the state isn't saved anywhere
+    let state_store = get_state_store(&envoluntary_config)?.with_dry_run(true);
+    let export_args = EnvoluntaryShellExportArgs {
+        shell,
+        config_path: args.config_path,
+        cache_dir: args.cache_dir,
+        flake_references: None,
+        impure: None,
+        force_update: false,
+        build_args: args.build_args,
+        current_dir: Some(dir.clone()),
+    };
+    let shell_exports = get_shell_exports(&export_args, None, &envoluntary_config, &state_store)?;
+
+    let mut export = Vec::new();
+    for shell_export in shell_exports {
+        write_shell_export(&mut export, shell, shell_export, &delimited_env_vars)?;
+    }
+    let startup_dir = tempfile::tempdir()?;
+    let export_path = startup_dir.path().join("export");
+    fs::write(&export_path, export)?;
+
+    let mut command = match shell {
+        EnvoluntaryShell::Bash => {
+            let rc_path = startup_dir.path().join("bashrc");
+            fs::write(
+                &rc_path,
+                bstr::concat([
+                    B("if [ -f ~/.bashrc ]; then . ~/.bashrc; fi\n. "),
+                    &Bash::quote_vec(&export_path),
+                    B("\n"),
+                ]),
+            )?;
+            let mut command = process::Command::new("bash");
+            command.arg("--rcfile").arg(rc_path).arg("-i");
+            command
+        }
+        EnvoluntaryShell::Fish => {
+            let mut command = process::Command::new("fish");
+            command
+                .arg("--init-command")
+                .arg(OsString::from_vec(bstr::concat([
+                    B("source "),
+                    &Fish::quote_vec(&export_path),
+                ])));
+            command
+        }
+        EnvoluntaryShell::Json => {
+            anyhow::bail!("JSON isn't a shell, so it can't be entered.");
+        }
+        EnvoluntaryShell::Nushell => {
+            let mut command = process::Command::new("nu");
+            command.arg("--execute").arg(format!(
+                "open --raw r#'{}'# | from json --objects | default {{}} | reduce --fold {{}} {{|row, acc| $acc | merge $row}} | load-env",
+                export_path.display()
+            ));
+            command
+        }
+        EnvoluntaryShell::Zsh => {
+            // NB: zsh reads its startup files from `$ZDOTDIR`, so the user's ones get sourced from
+            // files there that point it back at the user's dir
+            let user_zdotdir = match env::var_os("ZDOTDIR") {
+                Some(zdotdir) => PathBuf::from(zdotdir),
+                None => get_home_dir()?,
+            };
+            let user_zdotdir = Zsh::quote_vec(&user_zdotdir);
+            let startup_zdotdir = Zsh::quote_vec(startup_dir.path());
+            fs::write(
+                startup_dir.path().join(".zshenv"),
+                bstr::concat([
+                    B("ZDOTDIR="),
+                    &user_zdotdir,
+                    B(
+                        "\nif [ -f \"$ZDOTDIR/.zshenv\" ]; then . \"$ZDOTDIR/.zshenv\"; fi\nZDOTDIR=",
+                    ),
+                    &startup_zdotdir,
+                    B("\n"),
+                ]),
+            )?;
+            fs::write(
+                startup_dir.path().join(".zshrc"),
+                bstr::concat([
+                    B("ZDOTDIR="),
+                    &user_zdotdir,
+                    B("\nif [ -f \"$ZDOTDIR/.zshrc\" ]; then . \"$ZDOTDIR/.zshrc\"; fi\n. "),
+                    &Zsh::quote_vec(&export_path),
+                    B("\n"),
+                ]),
+            )?;
+            let mut command = process::Command::new("zsh");
+            command.arg("-i").env("ZDOTDIR", startup_dir.path());
+            command
+        }
+    };
+    // NB: The shell gets a session of its own, so that a hook in it doesn't touch the state files of
+    // this one
+    let status = command
+        .current_dir(&dir)
+        .env(ENVOLUNTARY_SHELL_VAR_KEY, &dir)
+        .env_remove(ENVOLUNTARY_SESSION_ID_VAR_KEY)
+        .status()
+        .context("couldn't start the shell")?;
+    drop(startup_dir);
+    process::exit(status.code().unwrap_or(i32::from(exit_code::FAILURE)));
+}
+
+/// The shell `$SHELL` points at.
+fn get_user_shell() -> anyhow::Result<EnvoluntaryShell> {
+    let shell_path = env::var_os("SHELL").context("`$SHELL` isn't set, pass `--shell`")?;
+    match Path::new(&shell_path)
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+    {
+        Some("bash") => Ok(EnvoluntaryShell::Bash),
+        Some("fish") => Ok(EnvoluntaryShell::Fish),
+        Some("nu") => Ok(EnvoluntaryShell::Nushell),
+        Some("zsh") => Ok(EnvoluntaryShell::Zsh),
+        _ => Err(anyhow::anyhow!(
+            "`{}` isn't a supported shell, pass `--shell`",
+            shell_path.to_string_lossy()
+        )),
+    }
+}
+
+pub fn print_env(args: EnvoluntaryEnvArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let offline = args.build_args.offline || envoluntary_config.offline.unwrap_or_default();
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    let matched_entries = envoluntary_config.matching_entries(&dir)?;
+
+    // NB: Profiles are checked before they're built, so that it's known whether they were fresh
+    let nix_version = check_nix_version()?;
+    let profiles = matched_entries
+        .iter()
+        .map(|entry| {
+            let config = Config {
+                gcroots: entry.config.gcroots.or(envoluntary_config.gcroots),
+                ..entry.config.clone()
+            };
+            ProfileStatus::new(&config, &cache_dir, Some(&nix_version), offline)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // NB: Only the output gets the environment, so the state isn't saved anywhere
+    let state_store = get_state_store(&envoluntary_config)?.with_dry_run(true);
+    let export_args = EnvoluntaryShellExportArgs {
+        shell: EnvoluntaryShell::Json,
+        config_path: args.config_path,
+        cache_dir: args.cache_dir,
+        flake_references: None,
+        impure: None,
+        force_update: false,
+        build_args: args.build_args,
+        current_dir: Some(dir.clone()),
+    };
+    let shell_exports = get_shell_exports(&export_args, None, &envoluntary_config, &state_store)?;
+    let env_vars = get_env_vars_exported(&get_current_env_vars(), &shell_exports);
+    let env_vars = if args.full {
+        remove_internal_env_vars(env_vars)
+    } else {
+        // NB: Without exports, the environment loaded in the shell already is the one for the dir
+        let env_state_var_value = if shell_exports.is_empty() {
+            state_store.load()?
+        } else {
+            ChunkedEnvVar::new(ENVOLUNTARY_ENV_STATE_VAR_KEY)
+                .get(|key| env_vars.get(key).cloned())?
+                .map(|value| OsString::from_vec(value.into()))
+        };
+        match env_state_var_value {
+            Some(env_state_var_value) => {
+                EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy())?.env_vars_set
+            }
+            None => EnvVars::new(),
+        }
+    };
+
+    match args.format {
+        EnvoluntaryEnvFormat::Json => {
+            let env = EnvoluntaryEnv {
+                dir,
+                fresh: profiles.iter().all(|profile| !profile.stale),
+                matched_entries,
+                profiles,
+                env: env_vars,
+            };
+            println!("{}", serde_json::to_string_pretty(&env)?);
+        }
+        EnvoluntaryEnvFormat::Dotenv => {
+            io::stdout().write_all(&shells::dotenv::export(&env_vars))?;
+        }
+        EnvoluntaryEnvFormat::Systemd => {
+            warn_about_skipped_env_vars("systemd", &shells::systemd::skipped_keys(&env_vars));
+            io::stdout().write_all(&shells::systemd::export(&env_vars))?;
+        }
+        EnvoluntaryEnvFormat::Docker => {
+            warn_about_skipped_env_vars("docker", &shells::docker::skipped_keys(&env_vars));
+            io::stdout().write_all(&shells::docker::export(&env_vars))?;
+        }
+        EnvoluntaryEnvFormat::GithubActions => {
+            io::stdout().write_all(&shells::github_actions::export(&env_vars))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The environment of a directory, as printed by `envoluntary env`.
+#[derive(Debug, Serialize)]
+struct EnvoluntaryEnv {
+    dir: PathBuf,
+    matched_entries: Vec<ConfigEntry>,
+    /// Whether every profile was cached and up to date, so nothing had to be built.
+    fresh: bool,
+    profiles: Vec<ProfileStatus>,
+    env: EnvVars,
+}
+
+fn warn_about_skipped_env_vars(format: &str, keys: &[&BString]) {
+    if keys.is_empty() {
+        return;
+    }
+    eprintln!(
+        "{CLI_NAME}: warning: skipped variables the {format} format can't hold: {}",
+        bstr::join(", ", keys).to_str_lossy()
+    );
+}
+
+pub fn print_status(args: EnvoluntaryStatusArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let offline = envoluntary_config.offline.unwrap_or_default();
+    let current_dir = match args.current_dir {
+        Some(current_dir) => current_dir,
+        None => env::current_dir()?,
+    };
+    let state_store = get_state_store(&envoluntary_config)?;
+
+    // NB: Unlike exporting, state that can't be read is an error rather than being dropped
+    let env_state = state_store
+        .store
+        .load()?
+        .map(|env_state_var_value| {
+            EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy())
+        })
+        .transpose()?;
+    let matched_entries = envoluntary_config.matching_entries(&current_dir)?;
+
+    let mut status = EnvoluntaryStatus {
+        paused: is_paused(),
+        flake_references: vec![],
+        loaded_manually: vec![],
+        skipped_offline: vec![],
+        matched_entries,
+        profiles: vec![],
+        env_vars: EnvVarsDiff::default(),
+    };
+    if let Some(env_state) = env_state {
+        let nix_version = if env_state.flake_references.is_empty() {
+            None
+        } else {
+            Some(check_nix_version()?)
+        };
+        for flake_reference in &env_state.flake_references {
+            let config = status
+                .matched_entries
+                .iter()
+                .map(|entry| entry.config.clone())
+                .chain(
+                    env_state
+                        .loaded_manually
+                        .iter()
+                        .map(|profile| envoluntary_config.profile_config(profile)),
+                )
+                .find(|config| &config.flake_reference == flake_reference)
+                .unwrap_or_else(|| envoluntary_config.profile_config(flake_reference));
+            status.profiles.push(ProfileStatus::new(
+                &config,
+                &cache_dir,
+                nix_version.as_ref(),
+                offline,
+            )?);
+        }
+
+        let current_env_vars = get_current_env_vars();
+        for (key, old_value) in env_state.env_vars_reset.iter() {
+            if key == ENVOLUNTARY_ENV_STATE_VAR_KEY {
+                continue;
+            }
+            let key_string = key.to_str_lossy().into_owned();
+            let new_value = env_state
+                .env_vars_set
+                .get(key)
+                .or_else(|| current_env_vars.get(key))
+                .map(|value| value.to_str_lossy().into_owned())
+                .unwrap_or_default();
+            match old_value {
+                None => {
+                    status.env_vars.added.insert(key_string, new_value);
+                }
+                Some(old_value) if !current_env_vars.contains_key(key) => {
+                    status
+                        .env_vars
+                        .removed
+                        .insert(key_string, old_value.to_str_lossy().into_owned());
+                }
+                Some(old_value) => {
+                    status.env_vars.changed.insert(
+                        key_string,
+                        EnvVarChange {
+                            old: old_value.to_str_lossy().into_owned(),
+                            new: new_value,
+                        },
+                    );
+                }
+            }
+        }
+        status.flake_references = env_state.flake_references;
+        status.loaded_manually = env_state.loaded_manually;
+        status.skipped_offline = env_state.skipped_offline;
+    }
+
+    match args.format {
+        EnvoluntaryOutputFormat::Human => print!("{status}"),
+        EnvoluntaryOutputFormat::Json => println!("{}", serde_json::to_string_pretty(&status)?),
+    }
+
+    Ok(())
+}
+
+/// What's loaded in the current shell, as printed by `envoluntary status`.
+#[derive(Debug, Serialize)]
+struct EnvoluntaryStatus {
+    paused: bool,
+    flake_references: Vec<String>,
+    loaded_manually: Vec<String>,
+    /// Profiles that couldn't be built while offline, so aren't part of the environment.
+    skipped_offline: Vec<String>,
+    /// Entries matching the current directory, which may differ from the ones loaded when the
+    /// hook hasn't run since the directory changed.
+    matched_entries: Vec<ConfigEntry>,
+    profiles: Vec<ProfileStatus>,
+    env_vars: EnvVarsDiff,
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileStatus {
+    flake_reference: String,
+    /// When the cached profile was built, or `None` when there isn't one.
+    built_at: Option<String>,
+    /// Whether the profile would be rebuilt the next time it's loaded.
+    stale: bool,
+}
+
+impl ProfileStatus {
+    fn new(
+        config: &Config,
+        cache_dir: &Path,
+        nix_version: Option<&Version>,
+        offline: bool,
+    ) -> anyhow::Result<Self> {
+        // NB: Profiles whose flake can't be found anymore are reported as never built
+        let cache_profile = nix_version.and_then(|nix_version| {
+            new_cache_profile(config, cache_dir, nix_version, offline).ok()
+        });
+        let built_at = match &cache_profile {
+            Some(cache_profile) => NixProfileCacheMetadata::read(cache_profile.profile_metadata())?
+                .map(|metadata| metadata.built_at),
+            None => None,
+        };
+        let stale = match &cache_profile {
+            Some(cache_profile) => cache_profile.needs_update()?,
+            None => true,
+        };
+        Ok(Self {
+            flake_reference: config.flake_reference.clone(),
+            built_at: built_at
+                .map(|built_at| humantime::format_rfc3339_seconds(built_at).to_string()),
+            stale,
+        })
+    }
+}
+
+/// Variables relative to an environment, e.g. the one from before anything was loaded.
+#[derive(Debug, Default, Serialize)]
+struct EnvVarsDiff {
+    added: BTreeMap<String, String>,
+    changed: BTreeMap<String, EnvVarChange>,
+    /// Variables that were set before, but aren't anymore.
+    removed: BTreeMap<String, String>,
+    /// The entries of changed path-like variables.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    delimited: BTreeMap<String, DelimitedEnvVarDiff>,
+}
+
+impl EnvVarsDiff {
+    fn new(
+        old_env_vars: &EnvVars,
+        new_env_vars: &EnvVars,
+        delimited_env_vars: &DelimitedEnvVars,
+    ) -> Self {
+        let env_vars_set = new_env_vars
+            .iter()
+            .filter(|(key, value)| old_env_vars.get(*key) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<EnvVars>();
+        let old_env_vars_updated =
+            get_old_env_vars_to_be_updated(old_env_vars.clone(), &env_vars_set);
+
+        let mut env_vars_diff = Self::default();
+        for (key, new_value) in env_vars_set.iter() {
+            let key_string = key.to_str_lossy().into_owned();
+            let Some(old_value) = old_env_vars_updated.get(key) else {
+                env_vars_diff
+                    .added
+                    .insert(key_string, new_value.to_str_lossy().into_owned());
+                continue;
+            };
+            if let Some(delimited_env_var) = delimited_env_vars.get(key_string.as_str()) {
+                env_vars_diff.delimited.insert(
+                    key_string.clone(),
+                    DelimitedEnvVarDiff::new(&delimited_env_var.separator, old_value, new_value),
+                );
+            }
+            env_vars_diff.changed.insert(
+                key_string,
+                EnvVarChange {
+                    old: old_value.to_str_lossy().into_owned(),
+                    new: new_value.to_str_lossy().into_owned(),
+                },
+            );
+        }
+        for (key, old_value) in old_env_vars.iter() {
+            if !new_env_vars.contains_key(key) {
+                env_vars_diff.removed.insert(
+                    key.to_str_lossy().into_owned(),
+                    old_value.to_str_lossy().into_owned(),
+                );
+            }
+        }
+        env_vars_diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    fn write_human(&self, writer: &mut impl Write, colored: bool) -> io::Result<()> {
+        let paint = |text: String, color: &str| {
+            if colored {
+                format!("\x1b[{color}m{text}\x1b[0m")
+            } else {
+                text
+            }
+        };
+        for (key, value) in &self.added {
+            writeln!(writer, "{}", paint(format!("+ {key}={value}"), GREEN))?;
+        }
+        for (key, EnvVarChange { old, new }) in &self.changed {
+            let Some(delimited) = self.delimited.get(key) else {
+                writeln!(
+                    writer,
+                    "{}",
+                    paint(format!("~ {key}: {old} -> {new}"), YELLOW)
+                )?;
+                continue;
+            };
+            writeln!(writer, "{}", paint(format!("~ {key}"), YELLOW))?;
+            for entry in &delimited.added {
+                writeln!(writer, "    {}", paint(format!("+ {entry}"), GREEN))?;
+            }
+            for entry in &delimited.removed {
+                writeln!(writer, "    {}", paint(format!("- {entry}"), RED))?;
+            }
+            if delimited.reordered {
+                writeln!(
+                    writer,
+                    "    {}",
+                    paint(String::from("entries reordered"), YELLOW)
+                )?;
+            }
+        }
+        for key in self.removed.keys() {
+            writeln!(writer, "{}", paint(format!("- {key}"), RED))?;
+        }
+        Ok(())
+    }
+}
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+
+/// How the entries of a path-like variable, e.g. `PATH`, changed.
+#[derive(Debug, Default, Serialize)]
+struct DelimitedEnvVarDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    /// Whether the entries that were kept are in another order.
+    reordered: bool,
+}
+
+impl DelimitedEnvVarDiff {
+    fn new(separator: &str, old_value: &[u8], new_value: &[u8]) -> Self {
+        let split = |value: &[u8]| {
+            value
+                .split_str(separator)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| entry.to_str_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        let (old_entries, new_entries) = (split(old_value), split(new_value));
+        let kept_in_old_order = old_entries
+            .iter()
+            .filter(|entry| new_entries.contains(entry))
+            .collect::<Vec<_>>();
+        let kept_in_new_order = new_entries
+            .iter()
+            .filter(|entry| old_entries.contains(entry))
+            .collect::<Vec<_>>();
+        Self {
+            added: new_entries
+                .iter()
+                .filter(|entry| !old_entries.contains(entry))
+                .cloned()
+                .collect(),
+            removed: old_entries
+                .iter()
+                .filter(|entry| !new_entries.contains(entry))
+                .cloned()
+                .collect(),
+            reordered: kept_in_old_order != kept_in_new_order,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EnvVarChange {
+    old: String,
+    new: String,
+}
+
+impl fmt::Display for EnvoluntaryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.paused {
+            writeln!(
+                f,
+                "Paused, run `{CLI_NAME} resume` to load environments again"
+            )?;
+        }
+        if self.flake_references.is_empty() {
+            writeln!(f, "No environment is loaded")?;
+        } else {
+            writeln!(f, "Loaded:")?;
+            for profile in &self.profiles {
+                let built = match &profile.built_at {
+                    Some(built_at) => format!("built {built_at}"),
+                    None => String::from("not built"),
+                };
+                let manually = if self.loaded_manually.contains(&profile.flake_reference) {
+                    ", loaded by hand"
+                } else {
+                    ""
+                };
+                let stale = if profile.stale { ", stale" } else { "" };
+                writeln!(
+                    f,
+                    "  {} ({built}{stale}{manually})",
+                    profile.flake_reference
+                )?;
+            }
+        }
+        if !self.skipped_offline.is_empty() {
+            writeln!(f, "Skipped while offline:")?;
+            for flake_reference in &self.skipped_offline {
+                writeln!(f, "  {flake_reference}")?;
+            }
+        }
+        if !self.matched_entries.is_empty() {
+            writeln!(f, "Matching entries:")?;
+            for entry in &self.matched_entries {
+                match &entry.name {
+                    Some(name) => writeln!(f, "  {} ({name})", entry.config.flake_reference)?,
+                    None => writeln!(f, "  {}", entry.config.flake_reference)?,
+                }
+            }
+        }
+        let EnvVarsDiff {
+            added,
+            changed,
+            removed,
+            ..
+        } = &self.env_vars;
+        if !self.env_vars.is_empty() {
+            writeln!(f, "Variables:")?;
+            for key in added.keys() {
+                writeln!(f, "  + {key}")?;
+            }
+            for key in changed.keys() {
+                writeln!(f, "  ~ {key}")?;
+            }
+            for key in removed.keys() {
+                writeln!(f, "  - {key}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn print_cache_path(args: EnvoluntaryShellPrintCachePathArgs) -> anyhow::Result<()> {
     let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let nix_version = check_nix_version()?;
+    let config = Config {
+        flake_reference: args.flake_reference,
+        impure: Some(args.impure),
+        gcroots: None,
+        nix_args: Some(args.nix_args),
+        hook_output: None,
+        on_enter: None,
+        on_leave: None,
+    };
     println!(
         "{}",
-        get_cache_sub_dir(&cache_dir, &args.flake_reference).display()
+        get_cache_sub_dir(
+            &cache_dir,
+            &get_cache_key(&config, &cache_dir, &nix_version)?
+        )?
+        .display()
     );
     Ok(())
 }
 
 fn get_cache_profile(
+    config: &Config,
+    export_options: &ExportOptions,
+) -> anyhow::Result<NixProfileCache> {
+    let mut cache_profile = new_cache_profile(
+        config,
+        export_options.cache_dir,
+        export_options.nix_version,
+        export_options.offline,
+    )?
+    .with_deadline(export_options.deadline)
+    .with_cancellation(Arc::clone(export_options.cancelled));
+    if let Some(progress_line) = &export_options.progress_line {
+        let progress_line = Arc::clone(progress_line);
+        cache_profile =
+            cache_profile.with_progress_reporter(NixProgressReporter::new(move |progress| {
+                progress_line.draw(progress)
+            }));
+    }
+
+    if export_options.force_update || cache_profile.needs_update()? {
+        let update = cache_profile.update();
+        if let Some(progress_line) = &export_options.progress_line {
+            progress_line.clear();
+        }
+        update?;
+        // NB: Profiles built by other versions are only removed once the one replacing them is
+        // built, and never while offline, as they may be all there is until the next build
+        if !export_options.offline {
+            remove_stale_cache_dirs(
+                export_options.cache_dir,
+                &get_cache_key(config, export_options.cache_dir, export_options.nix_version)?,
+            )?;
+        }
+    } else {
+        cache_profile.record_usage()?;
+    }
+
+    Ok(cache_profile)
+}
+
+/// The cached profile for the config, without building it.
+fn new_cache_profile(
+    config: &Config,
     cache_dir: &Path,
-    flake_reference: &str,
-    force_update: bool,
-    impure: Option<bool>,
+    nix_version: &Version,
+    offline: bool,
 ) -> anyhow::Result<NixProfileCache> {
-    let cach_sub_dir = get_cache_sub_dir(cache_dir, flake_reference);
-    let cache_profile = NixProfileCache::new(
-        cach_sub_dir,
-        flake_reference,
-        if impure == Some(true) {
+    let cache_key = get_cache_key(config, cache_dir, nix_version)?;
+    let cache_sub_dir = get_cache_sub_dir(cache_dir, &cache_key)?;
+    Ok(NixProfileCache::new(
+        cache_sub_dir,
+        &config.flake_reference,
+        cache_key.evaluation_mode,
+    )?
+    .with_gcroots_mode(config.gcroots.unwrap_or_default())
+    .with_offline(offline)
+    .with_extra_args(cache_key.extra_args.clone())
+    .with_cache_key(cache_key))
+}
+
+fn get_cache_key(
+    config: &Config,
+    cache_dir: &Path,
+    nix_version: &Version,
+) -> anyhow::Result<NixCacheKey> {
+    Ok(NixCacheKey::new(
+        &config.flake_reference,
+        if config.impure == Some(true) {
             EvaluationMode::Impure
         } else {
             EvaluationMode::Pure
         },
-    )?;
+        get_nix_system(cache_dir, nix_version)?,
+        nix_version,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .with_extra_args(config.nix_args.clone().unwrap_or_default()))
+}
 
-    if force_update || cache_profile.needs_update()? {
-        cache_profile.update()?;
+/// The system Nix builds for. Looking it up runs Nix, so it's kept in the cache dir for each
+/// version of Nix, and only read once however many profiles get loaded.
+fn get_nix_system(cache_dir: &Path, nix_version: &Version) -> anyhow::Result<&'static str> {
+    static NIX_SYSTEM: OnceLock<String> = OnceLock::new();
+    if let Some(nix_system) = NIX_SYSTEM.get() {
+        return Ok(nix_system);
     }
+    let nix_system_path = cache_dir.join(format!("{NIX_SYSTEM_FILE_PREFIX}{nix_version}"));
+    let nix_system = match fs::read_to_string(&nix_system_path) {
+        Ok(nix_system) if !nix_system.trim().is_empty() => String::from(nix_system.trim()),
+        _ => {
+            let nix_system = nix_dev_env::get_nix_system(nix_version)?;
+            // NB: Failing to keep it only means it's looked up again next time
+            let _ = write_nix_system(&nix_system_path, &nix_system);
+            nix_system
+        }
+    };
+    Ok(NIX_SYSTEM.get_or_init(|| nix_system))
+}
 
-    Ok(cache_profile)
+fn write_nix_system(nix_system_path: &Path, nix_system: &str) -> io::Result<()> {
+    let Some(cache_dir) = nix_system_path.parent() else {
+        return Ok(());
+    };
+    fs::create_dir_all(cache_dir)?;
+    // NB: Other shells may read it at the same time, so it's only ever seen whole
+    let mut file = tempfile::NamedTempFile::new_in(cache_dir)?;
+    file.write_all(nix_system.as_bytes())?;
+    file.persist(nix_system_path).map_err(|e| e.error)?;
+    Ok(())
 }
 
-fn get_cache_sub_dir(cache_dir: &Path, flake_reference: &str) -> PathBuf {
-    cache_dir.join(format!("{:x}", Sha1::digest(flake_reference)))
+fn get_cache_sub_dir(cache_dir: &Path, cache_key: &NixCacheKey) -> anyhow::Result<PathBuf> {
+    Ok(cache_dir.join(cache_key.digest()?))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct EnvoluntaryEnvState {
     flake_references: Vec<String>,
     env_vars_reset: EnvVarsState,
+    /// The values the environment set, so that only variables still holding them get reset.
+    #[serde(default)]
+    env_vars_set: EnvVars,
+    /// Removes the functions and aliases the environment defined.
+    #[serde(default, skip_serializing_if = "ShellDefinitionsState::is_empty")]
+    shell_definitions_reset: ShellDefinitionsState,
+    /// Commands to run once the environment is unloaded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    on_leave: Vec<String>,
+    /// Profiles loaded with `envoluntary load`, which stay loaded whatever the current directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    loaded_manually: Vec<String>,
+    /// Profiles that couldn't be built while offline, which are retried once back online.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped_offline: Vec<String>,
 }
 
 impl EnvoluntaryEnvState {
-    fn decode(base64_value: impl AsRef<[u8]>) -> anyhow::Result<Self> {
-        let zstd_value = BASE64_STANDARD.decode(base64_value)?;
-        let mut zstd_value_slice = zstd_value.as_slice();
-        let mut zstd_decoder = ruzstd::decoding::StreamingDecoder::new(&mut zstd_value_slice)?;
-        let mut value = vec![];
-        zstd_decoder.read_to_end(&mut value)?;
-        Ok(serde_json::from_slice(&value)?)
-    }
-
-    fn encode(&self) -> anyhow::Result<String> {
-        let value = serde_json::to_vec(self)?;
-        let value_slice = value.as_slice();
-        let zstd_value = ruzstd::encoding::compress_to_vec(
-            value_slice,
-            ruzstd::encoding::CompressionLevel::Fastest,
+    /// Whether the state holds the environment of the configs, so it doesn't need reloading.
+    ///
+    /// Profiles skipped while offline count as loaded until the network can be used again.
+    fn is_loaded(&self, configs: &[Config], offline: bool) -> bool {
+        if !offline && !self.skipped_offline.is_empty() {
+            return false;
+        }
+        let flake_references = configs
+            .iter()
+            .map(|config| &config.flake_reference)
+            .collect::<Vec<_>>();
+        flake_references
+            .iter()
+            .filter(|&&flake_reference| !self.skipped_offline.contains(flake_reference))
+            .copied()
+            .eq(self.flake_references.iter())
+            && self
+                .skipped_offline
+                .iter()
+                .all(|flake_reference| flake_references.contains(&flake_reference))
+    }
+
+    fn restore(
+        self,
+        current_env_vars: &EnvVars,
+        delimited_env_vars: &DelimitedEnvVars,
+        state_store: &dyn StateStore,
+    ) -> ShellExport {
+        let mut env_vars_state = get_env_vars_restore(
+            self.env_vars_reset,
+            &self.env_vars_set,
+            current_env_vars,
+            delimited_env_vars,
+        );
+        env_vars_state.extend(state_store.clear(current_env_vars));
+        ShellExport {
+            env_vars_state,
+            shell_definitions_state: self.shell_definitions_reset,
+            commands: self.on_leave,
+        }
+    }
+
+    fn decode(value: &str) -> Result<Self, env_hooks::Error> {
+        if VersionedStateCodec::has_header(value) {
+            VersionedStateCodec::new(ENVOLUNTARY_ENV_STATE_VERSION).decode_json(value)
+        } else {
+            // NB: State written before it had a header is still read, so that upgrading doesn't
+            // break shells that already have an environment loaded
+            PlainStateCodec::new(Compression::Zstd).decode_json(value)
+        }
+    }
+
+    fn encode(&self) -> Result<String, env_hooks::Error> {
+        VersionedStateCodec::new(ENVOLUNTARY_ENV_STATE_VERSION).encode_json(self)
+    }
+}
+
+/// What's needed to unload an environment, kept per shell session in a format that doesn't change
+/// with [`ENVOLUNTARY_ENV_STATE_VERSION`], so that an environment can still be unloaded when its
+/// state can't be read.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct EnvoluntaryEnvSnapshot {
+    env_vars_reset: EnvVarsState,
+    env_vars_set: EnvVars,
+    #[serde(default)]
+    shell_definitions_reset: ShellDefinitionsState,
+}
+
+/// Reads and saves the state of the shell's environment, replacing state that can't be read, e.g.
+/// because it was written by another version or got truncated, with a best-effort state to unload.
+struct EnvoluntaryStateStore {
+    store: Box<dyn StateStore>,
+    /// Where the snapshot of the shell session's state is kept, if the session has an id.
+    snapshot_path: Option<PathBuf>,
+    /// The state once it's been read, so that it's only recovered from once.
+    loaded: OnceCell<Option<OsString>>,
+    /// Whether saving only returns the variables to set, without writing any files.
+    dry_run: bool,
+}
+
+impl EnvoluntaryStateStore {
+    fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Keeps a snapshot of the state, on a best-effort basis.
+    fn save_snapshot(&self, env_state: &EnvoluntaryEnvState) {
+        let Some(snapshot_path) = self.snapshot_path.as_ref().filter(|_| !self.dry_run) else {
+            return;
+        };
+        let snapshot = EnvoluntaryEnvSnapshot {
+            env_vars_reset: env_state.env_vars_reset.clone(),
+            env_vars_set: env_state.env_vars_set.clone(),
+            shell_definitions_reset: env_state.shell_definitions_reset.clone(),
+        };
+        // NB: Failing to keep a snapshot only matters once the state can't be read, so it doesn't
+        // fail loading the environment
+        let _ = snapshot_path
+            .parent()
+            .map_or(Ok(()), |snapshots_dir| {
+                DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(snapshots_dir)
+            })
+            .and_then(|()| Ok(serde_json::to_vec(&snapshot)?))
+            .and_then(|snapshot| fs::write(snapshot_path, snapshot));
+    }
+
+    fn recover(&self, err: env_hooks::Error) -> Result<OsString, env_hooks::Error> {
+        let snapshot = self
+            .snapshot_path
+            .as_ref()
+            .and_then(|snapshot_path| fs::read(snapshot_path).ok())
+            .and_then(|snapshot| serde_json::from_slice::<EnvoluntaryEnvSnapshot>(&snapshot).ok());
+        eprintln!(
+            "{CLI_NAME}: warning: the state of this shell couldn't be read, so it was dropped: {:#}",
+            anyhow::Error::from(err)
         );
-        Ok(BASE64_STANDARD.encode(zstd_value))
+        let snapshot = if let Some(snapshot) = snapshot {
+            eprintln!("{CLI_NAME}: unloading the environment from the last snapshot of the state");
+            snapshot
+        } else {
+            eprintln!(
+                "{CLI_NAME}: variables the environment set are kept, open a new shell to start over"
+            );
+            EnvoluntaryEnvSnapshot::default()
+        };
+        let env_state = EnvoluntaryEnvState {
+            flake_references: vec![],
+            env_vars_reset: snapshot.env_vars_reset,
+            env_vars_set: snapshot.env_vars_set,
+            shell_definitions_reset: snapshot.shell_definitions_reset,
+            on_leave: vec![],
+            loaded_manually: vec![],
+            skipped_offline: vec![],
+        };
+        Ok(OsString::from(env_state.encode()?))
+    }
+}
+
+impl StateStore for EnvoluntaryStateStore {
+    fn load(&self) -> Result<Option<OsString>, env_hooks::Error> {
+        if let Some(loaded) = self.loaded.get() {
+            return Ok(loaded.clone());
+        }
+        let loaded = self.load_or_recover()?;
+        Ok(self.loaded.get_or_init(|| loaded).clone())
+    }
+
+    fn save(
+        &self,
+        state: &str,
+        current_env_vars: &EnvVars,
+    ) -> Result<EnvVarsState, env_hooks::Error> {
+        if self.dry_run {
+            return EnvStateStore::new(ENVOLUNTARY_ENV_STATE_VAR_KEY).save(state, current_env_vars);
+        }
+        self.store.save(state, current_env_vars)
+    }
+
+    fn clear(&self, current_env_vars: &EnvVars) -> EnvVarsState {
+        self.store.clear(current_env_vars)
+    }
+}
+
+impl EnvoluntaryStateStore {
+    fn load_or_recover(&self) -> Result<Option<OsString>, env_hooks::Error> {
+        let env_state_var_value = match self.store.load() {
+            Ok(Some(env_state_var_value)) => env_state_var_value,
+            Ok(None) => return Ok(None),
+            Err(
+                err @ (env_hooks::Error::StateFileNotFound { .. }
+                | env_hooks::Error::StateDecode(_)),
+            ) => return self.recover(err).map(Some),
+            Err(err) => return Err(err),
+        };
+        match EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy()) {
+            Ok(_) => Ok(Some(env_state_var_value)),
+            Err(err) => self.recover(err).map(Some),
+        }
     }
 }
 
+/// Settings shared by every entry loaded into an environment.
+struct ExportOptions<'a> {
+    cache_dir: &'a Path,
+    /// The directory the environment gets loaded in, which shell hooks are sourced in.
+    current_dir: &'a Path,
+    nix_version: &'a Version,
+    delimited_env_vars: &'a DelimitedEnvVars,
+    env_vars_filter: &'a EnvVarsFilter,
+    force_update: bool,
+    offline: bool,
+    /// The time by which loading the environment has to have finished.
+    deadline: Option<Instant>,
+    /// Set by a signal handler once loading the environment should be cancelled.
+    cancelled: &'a Arc<AtomicBool>,
+    /// Shows the progress of Nix builds, unless it's hidden.
+    progress_line: Option<Arc<ProgressLine>>,
+    /// Where the state of the environment gets read from, and saved to.
+    state_store: &'a EnvoluntaryStateStore,
+}
+
 fn get_export_env_vars_state(
-    flake_reference: String,
-    cache_profile: &NixProfileCache,
-) -> anyhow::Result<EnvVarsState> {
-    let EnvVarUpdates {
-        mut new_env_vars,
-        old_env_vars_to_be_updated,
-    } = get_new_env_vars(cache_profile)?;
+    configs: Vec<Config>,
+    mut current_env_vars: EnvVars,
+    loaded_manually: &[String],
+    export_options: &ExportOptions,
+) -> anyhow::Result<ShellExport> {
+    let mut hooks_run = current_env_vars
+        .get(B(ENVOLUNTARY_HOOKS_RUN_VAR_KEY))
+        .map(|hooks_run| {
+            hooks_run
+                .lines()
+                .map(|line| line.to_str_lossy().into_owned())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let hooks_run_count = hooks_run.len();
+    let mut flake_references = vec![];
+    let mut skipped_offline = vec![];
+    let mut new_env_vars = EnvVars::new();
+    let mut new_shell_definitions = ShellDefinitions::new();
+    let mut old_env_vars_to_be_updated = EnvVars::new();
+    let mut on_enter = vec![];
+    let mut on_leave = vec![];
+    for config in configs {
+        let env_var_updates =
+            get_cache_profile(&config, export_options).and_then(|cache_profile| {
+                get_new_env_vars(&cache_profile, &current_env_vars, export_options)
+            });
+        // NB: Entries that can't be built offline are recorded as skipped in the state, so
+        // building them isn't retried on every prompt until the network can be used again
+        let env_var_updates = match env_var_updates {
+            Ok(env_var_updates) => env_var_updates,
+            Err(e) if export_options.offline => {
+                eprintln!(
+                    "{CLI_NAME}: couldn't build `{}` while offline, skipping it: {e:#}",
+                    config.flake_reference
+                );
+                skipped_offline.push(config.flake_reference);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        flake_references.push(config.flake_reference);
+        let show_shell_hook_output = match config.hook_output.unwrap_or_default() {
+            HookOutput::Show => true,
+            HookOutput::Quiet => false,
+            HookOutput::OncePerSession => {
+                let flake_reference = flake_references.last().unwrap();
+                let hook_run = hooks_run.contains(flake_reference);
+                if !hook_run {
+                    hooks_run.push(flake_reference.clone());
+                }
+                !hook_run
+            }
+        };
+        if show_shell_hook_output {
+            eprint!("{}", env_var_updates.shell_hook_output);
+        }
+        on_enter.extend(config.on_enter.unwrap_or_default());
+        on_leave.extend(config.on_leave.unwrap_or_default());
+        // NB: Later entries are merged on top of earlier ones, but the values to reset to are
+        // always the ones from before any entry was loaded
+        for (key, value) in env_var_updates.old_env_vars_to_be_updated {
+            if !new_env_vars.contains_key(&key) {
+                old_env_vars_to_be_updated.insert(key, value);
+            }
+        }
+        current_env_vars.extend(env_var_updates.new_env_vars.clone());
+        new_env_vars.extend(env_var_updates.new_env_vars);
+        new_shell_definitions.extend(env_var_updates.shell_definitions);
+    }
+
     let env_vars_reset = get_env_vars_reset(
         old_env_vars_to_be_updated,
         new_env_vars.keys().cloned().collect(),
-        String::from(ENVOLUNTARY_ENV_STATE_VAR_KEY),
+        ENVOLUNTARY_ENV_STATE_VAR_KEY,
     );
     let env_state = EnvoluntaryEnvState {
-        flake_references: vec![flake_reference],
+        flake_references,
         env_vars_reset,
+        env_vars_set: new_env_vars.clone(),
+        shell_definitions_reset: new_shell_definitions.reset(),
+        on_leave,
+        loaded_manually: loaded_manually.to_vec(),
+        skipped_offline,
     };
-    new_env_vars.insert(
-        String::from(ENVOLUNTARY_ENV_STATE_VAR_KEY),
-        env_state.encode()?,
+    export_options.state_store.save_snapshot(&env_state);
+    let mut env_vars_state = EnvVarsState::from(new_env_vars);
+    env_vars_state.extend(
+        export_options
+            .state_store
+            .save(&env_state.encode()?, &current_env_vars)?,
     );
-    Ok(EnvVarsState::from(new_env_vars))
+    // NB: This isn't part of the environment, so it doesn't get reset when it's unloaded
+    if hooks_run.len() != hooks_run_count {
+        env_vars_state.insert(
+            BString::from(ENVOLUNTARY_HOOKS_RUN_VAR_KEY),
+            Some(BString::from(hooks_run.join("\n"))),
+        );
+    }
+    Ok(ShellExport {
+        env_vars_state,
+        shell_definitions_state: ShellDefinitionsState::from(new_shell_definitions),
+        commands: on_enter,
+    })
+}
+
+/// Removes the variables only envoluntary reads, which aren't part of the environment.
+fn remove_internal_env_vars(env_vars: EnvVars) -> EnvVars {
+    env_vars
+        .into_iter()
+        .filter(|(key, _)| {
+            !key.starts_with(ENVOLUNTARY_ENV_STATE_VAR_KEY.as_bytes())
+                && key != ENVOLUNTARY_HOOKS_RUN_VAR_KEY
+        })
+        .collect()
+}
+
+/// The variables the shell ends up with once the exports are evaluated.
+fn get_env_vars_exported(current_env_vars: &EnvVars, shell_exports: &[ShellExport]) -> EnvVars {
+    let mut env_vars = current_env_vars.clone();
+    for shell_export in shell_exports {
+        env_vars.apply(&shell_export.env_vars_state);
+    }
+    env_vars
+}
+
+/// Everything printed for the shell to evaluate.
+struct ShellExport {
+    env_vars_state: EnvVarsState,
+    shell_definitions_state: ShellDefinitionsState,
+    /// Commands run after the variables and definitions are set.
+    commands: Vec<String>,
 }
 
 struct EnvVarUpdates {
     new_env_vars: EnvVars,
     old_env_vars_to_be_updated: EnvVars,
+    shell_definitions: ShellDefinitions,
+    shell_hook_output: String,
 }
 
-fn get_new_env_vars(cache_profile: &NixProfileCache) -> anyhow::Result<EnvVarUpdates> {
-    let mut bash_env_vars = EnvVars::new();
-
-    let old_path = env::var_os(ENV_VAR_KEY_PATH).map(|p| String::from(p.to_string_lossy()));
-    if let Some(path_value) = old_path.clone() {
-        bash_env_vars.insert(String::from(ENV_VAR_KEY_PATH), path_value);
+fn get_new_env_vars(
+    cache_profile: &NixProfileCache,
+    current_env_vars: &EnvVars,
+    export_options: &ExportOptions,
+) -> anyhow::Result<EnvVarUpdates> {
+    let ProfileEnv {
+        env_vars: mut new_env_vars,
+        shell_definitions,
+        shell_hook_output,
+    } = get_profile_env(cache_profile, export_options)?;
+    // NB: The persisted environment only leaves out the variables that are always ignored, so
+    // changes to the configured filter apply without rebuilding the profile
+    remove_filtered_env_vars(&mut new_env_vars, export_options.env_vars_filter);
+    if new_env_vars
+        .get(B(ENV_VAR_KEY_PATH))
+        .is_some_and(|path_value| path_value.is_empty())
+    {
+        new_env_vars.shift_remove(B(ENV_VAR_KEY_PATH));
     }
 
-    // Prints devshell "message of the day" the same way it would in `direnv`
-    // https://github.com/numtide/devshell/blob/7c9e793ebe66bcba8292989a68c0419b737a22a0/modules/devshell.nix#L400
-    bash_env_vars.insert(String::from("DIRENV_IN_ENVRC"), String::from("1"));
+    let old_env_vars_to_be_updated =
+        get_old_env_vars_to_be_updated(current_env_vars.clone(), &new_env_vars);
 
-    let mut new_env_vars = get_env_vars_from_bash(
-        BashSource::File(PathBuf::from(cache_profile.profile_rc())),
-        Some(bash_env_vars),
-    )?;
-    remove_ignored_env_vars(&mut new_env_vars);
-    if new_env_vars.get(ENV_VAR_KEY_PATH) == old_path.as_ref() {
-        new_env_vars.shift_remove(ENV_VAR_KEY_PATH);
+    merge_delimited_env_vars(
+        export_options.delimited_env_vars,
+        &old_env_vars_to_be_updated,
+        &mut new_env_vars,
+    );
+
+    Ok(EnvVarUpdates {
+        new_env_vars,
+        old_env_vars_to_be_updated,
+        shell_definitions,
+        shell_hook_output,
+    })
+}
+
+/// Profiles loaded with `envoluntary load` in the current shell, once the change is made.
+fn get_loaded_manually(
+    state_store: &EnvoluntaryStateStore,
+    manual_change: Option<&ManualChange>,
+) -> anyhow::Result<Vec<String>> {
+    let mut loaded_manually = match state_store.load()? {
+        Some(env_state_var_value) => {
+            EnvoluntaryEnvState::decode(&env_state_var_value.to_string_lossy())?.loaded_manually
+        }
+        None => vec![],
+    };
+    match manual_change {
+        Some(ManualChange::Load(profile)) => {
+            if !loaded_manually.contains(profile) {
+                loaded_manually.push(profile.clone());
+            }
+        }
+        Some(ManualChange::Unload(Some(profile))) => {
+            let count = loaded_manually.len();
+            loaded_manually.retain(|loaded_profile| loaded_profile != profile);
+            if loaded_manually.len() == count {
+                anyhow::bail!("`{profile}` wasn't loaded with `{CLI_NAME} load`");
+            }
+        }
+        Some(ManualChange::Unload(None) | ManualChange::Pause) => loaded_manually.clear(),
+        Some(ManualChange::Reload | ManualChange::Resume) | None => {}
     }
+    Ok(loaded_manually)
+}
 
-    let old_env_vars_to_be_updated = {
-        let mut old_env_vars = get_env_vars_from_current_process();
-        remove_ignored_env_vars(&mut old_env_vars);
-        get_old_env_vars_to_be_updated(old_env_vars, &new_env_vars)
+fn get_state_store(
+    envoluntary_config: &EnvoluntaryConfig,
+) -> anyhow::Result<EnvoluntaryStateStore> {
+    let runtime_dir = store::runtime_dir().map(|runtime_dir| runtime_dir.join(CLI_NAME));
+    let session_id = env::var_os(ENVOLUNTARY_SESSION_ID_VAR_KEY)
+        .map(|session_id| session_id.to_string_lossy().into_owned());
+    let snapshot_path = runtime_dir
+        .as_ref()
+        .zip(session_id.as_ref())
+        .filter(|(_, session_id)| {
+            session_id
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        })
+        .map(|(runtime_dir, session_id)| {
+            runtime_dir
+                .join(STATE_SNAPSHOTS_DIR)
+                .join(format!("{session_id}.json"))
+        });
+    let store: Box<dyn StateStore> = if envoluntary_config.state_store.unwrap_or_default()
+        == StateStoreKind::File
+        && let Some(runtime_dir) = runtime_dir
+        && let Some(session_id) = session_id
+    {
+        Box::new(FileStateStore::new(
+            ENVOLUNTARY_ENV_STATE_VAR_KEY,
+            runtime_dir,
+            session_id,
+        )?)
+    } else {
+        // NB: Without somewhere to put files, or a session to name them after, the state is kept
+        // in the environment
+        Box::new(EnvStateStore::new(ENVOLUNTARY_ENV_STATE_VAR_KEY))
     };
+    Ok(EnvoluntaryStateStore {
+        store,
+        snapshot_path,
+        loaded: OnceCell::new(),
+        dry_run: false,
+    })
+}
 
-    if new_env_vars.contains_key(ENV_VAR_KEY_PATH) {
-        merge_delimited_env_var(
-            ENV_VAR_KEY_PATH,
-            ':',
-            ':',
-            &old_env_vars_to_be_updated,
-            &mut new_env_vars,
-        );
+fn get_current_env_vars() -> EnvVars {
+    let mut current_env_vars = get_env_vars_from_current_process();
+    remove_ignored_env_vars(&mut current_env_vars);
+    current_env_vars
+}
+
+/// Environment captured from sourcing a profile's rc file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProfileEnv {
+    env_vars: EnvVars,
+    shell_definitions: ShellDefinitions,
+    /// What the rc file printed, e.g. a devshell motd.
+    shell_hook_output: String,
+}
+
+/// Reads the environment persisted for the profile, capturing it first if the profile was just
+/// built (or was cached before environments, or their functions, aliases and output, were
+/// persisted).
+fn get_profile_env(
+    cache_profile: &NixProfileCache,
+    export_options: &ExportOptions,
+) -> anyhow::Result<ProfileEnv> {
+    let profile_env_file = cache_profile.profile_env(export_options.current_dir);
+    match fs::read(&profile_env_file) {
+        Ok(contents) => {
+            if let Ok(profile_env) = serde_json::from_slice(&contents) {
+                return Ok(profile_env);
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
     }
-    if new_env_vars.contains_key(ENV_VAR_KEY_XDG_DATA_DIRS) {
-        merge_delimited_env_var(
-            ENV_VAR_KEY_XDG_DATA_DIRS,
-            ':',
-            ':',
-            &old_env_vars_to_be_updated,
-            &mut new_env_vars,
-        );
+    let profile_env = capture_profile_env(cache_profile, export_options)?;
+    fs::write(&profile_env_file, serde_json::to_vec(&profile_env)?)?;
+    cache_profile.remove_old_profile_envs()?;
+    Ok(profile_env)
+}
+
+/// Sources the profile's rc file in bash and captures the resulting environment, along with the
+/// functions and aliases it defines and what it prints.
+///
+/// The host's path-like variables, e.g. `PATH`, are available while sourcing, so that shell hooks
+/// can use them, but their entries are removed from the captured values. The current values get
+/// merged back in on activation.
+///
+/// It's sourced in the directory the environment gets loaded in, when that directory exists.
+fn capture_profile_env(
+    cache_profile: &NixProfileCache,
+    export_options: &ExportOptions,
+) -> anyhow::Result<ProfileEnv> {
+    let mut bash_env_vars = EnvVars::new();
+
+    let host_delimited_env_vars = export_options
+        .delimited_env_vars
+        .keys()
+        .filter_map(|key| {
+            let value = env::var_os(key)?;
+            Some((
+                BString::from(key.as_str()),
+                BString::from(value.into_encoded_bytes()),
+            ))
+        })
+        .collect::<EnvVars>();
+    bash_env_vars.extend(host_delimited_env_vars.clone());
+
+    // Prints devshell "message of the day" the same way it would in `direnv`
+    // https://github.com/numtide/devshell/blob/7c9e793ebe66bcba8292989a68c0419b737a22a0/modules/devshell.nix#L400
+    bash_env_vars.insert(BString::from("DIRENV_IN_ENVRC"), BString::from("1"));
+
+    let source = BashSource::File(PathBuf::from(cache_profile.profile_rc()));
+    let limits = BashLimits {
+        deadline: export_options.deadline,
+        cancelled: Some(export_options.cancelled),
+    };
+    let BashEnv {
+        mut env_vars,
+        shell_definitions,
+        output,
+    } = if export_options.current_dir.is_dir() {
+        get_bash_env_in_dir(
+            source,
+            Some(bash_env_vars),
+            export_options.current_dir,
+            limits,
+        )?
+    } else {
+        get_bash_env_within_limits(source, Some(bash_env_vars), limits)?
+    };
+    remove_ignored_env_vars(&mut env_vars);
+
+    for (key, host_value) in host_delimited_env_vars.iter() {
+        let Some(delimited_env_var) = export_options
+            .delimited_env_vars
+            .get(key.to_str_lossy().as_ref())
+        else {
+            continue;
+        };
+        let Some(value) = env_vars.get_mut(key) else {
+            continue;
+        };
+        let separator = delimited_env_var.separator.as_str();
+        let host_entries = host_value.split_str(separator).collect::<HashSet<_>>();
+        *value = bstr::join(
+            separator,
+            value
+                .split_str(separator)
+                .filter(|entry| !host_entries.contains(entry)),
+        )
+        .into();
+        // NB: Variables holding only the host's entries weren't set by the environment
+        if value.is_empty() && !host_value.is_empty() {
+            env_vars.shift_remove(key);
+        }
     }
 
-    Ok(EnvVarUpdates {
-        new_env_vars,
-        old_env_vars_to_be_updated,
+    Ok(ProfileEnv {
+        env_vars,
+        shell_definitions,
+        shell_hook_output: output.to_str_lossy().into_owned(),
     })
 }
 
-fn print_shell_export(shell: EnvoluntaryShell, env_vars_state: EnvVarsState) {
+fn write_shell_export(
+    writer: &mut impl Write,
+    shell: EnvoluntaryShell,
+    shell_export: ShellExport,
+    delimited_env_vars: &DelimitedEnvVars,
+) -> io::Result<()> {
+    let ShellExport {
+        env_vars_state,
+        shell_definitions_state,
+        commands,
+    } = shell_export;
     let export = match shell {
-        EnvoluntaryShell::Bash => {
-            shells::bash::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
-        }
-        EnvoluntaryShell::Fish => {
-            shells::fish::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
-        }
-        EnvoluntaryShell::Json => {
-            shells::json::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
-        }
+        EnvoluntaryShell::Bash => shells::bash::export(env_vars_state, Some(delimited_env_vars)),
+        EnvoluntaryShell::Fish => shells::fish::export(env_vars_state, Some(delimited_env_vars)),
+        EnvoluntaryShell::Json => shells::json::export(env_vars_state, Some(delimited_env_vars)),
         EnvoluntaryShell::Nushell => shells::nushell::export(env_vars_state),
-        EnvoluntaryShell::Zsh => {
-            shells::zsh::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
-        }
+        EnvoluntaryShell::Zsh => shells::zsh::export(env_vars_state, Some(delimited_env_vars)),
     };
-    println!("{}", export);
+    writeln!(writer, "{}", export)?;
+
+    // NB: Nushell can't define or run commands from the JSON it loads, and JSON only holds
+    // variables
+    if matches!(shell, EnvoluntaryShell::Json | EnvoluntaryShell::Nushell) {
+        return Ok(());
+    }
+
+    let shell_definitions_export = match shell {
+        _ if shell_definitions_state.is_empty() => None,
+        EnvoluntaryShell::Bash => Some(shells::bash::export_shell_definitions(
+            &shell_definitions_state,
+        )),
+        EnvoluntaryShell::Fish => Some(shells::fish::export_shell_definitions(
+            &shell_definitions_state,
+        )),
+        EnvoluntaryShell::Zsh => Some(shells::zsh::export_shell_definitions(
+            &shell_definitions_state,
+        )),
+        EnvoluntaryShell::Json | EnvoluntaryShell::Nushell => None,
+    };
+    if let Some(shell_definitions_export) = shell_definitions_export {
+        writeln!(writer, "{}", shell_definitions_export)?;
+    }
+
+    // NB: Commands are written for the user's shell, so they're run as is
+    for command in commands {
+        writeln!(writer, "{}", command)?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,166 @@
+use std::fs;
+
+use assert_cmd::{Command, cargo};
+use predicates::prelude::*;
+
+pub mod common;
+use common::{Fixtures, build_fixtures};
+
+fn populate_cache(fixtures: &Fixtures, current_dir: &str) {
+    fixtures
+        .command(&["shell", "export", "bash", "--current-dir", current_dir])
+        .assert()
+        .success();
+}
+
+fn cache_cmd(fixtures: &Fixtures, args: &[&str]) -> Command {
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.arg("cache")
+        .args(args)
+        .args(["--cache-dir", &fixtures.cache_dir.path().to_string_lossy()])
+        .env("PATH", &fixtures.path);
+    cmd
+}
+
+#[test]
+fn cache_list_and_inspect_show_cached_profiles() {
+    let fixtures = build_fixtures();
+
+    cache_cmd(&fixtures, &["list"])
+        .assert()
+        .success()
+        .stdout(predicate::eq(""));
+
+    populate_cache(&fixtures, "/some/dir");
+    populate_cache(&fixtures, "/home/some/other/dir");
+
+    let output = cache_cmd(&fixtures, &["list"]).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("FLAKE REFERENCE"));
+    assert!(lines[1].starts_with("github:other_github_owner/repo"));
+    assert!(lines[1].contains("just now"));
+    // NB: The mock `nix build` links to the temporary profile, which is removed after building
+    assert!(lines[1].contains("profile+inputs: 1 (missing)"));
+    assert!(lines[2].starts_with("github:owner/repo"));
+
+    let output = cache_cmd(&fixtures, &["inspect", "github:owner/repo"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let metadata = metadata.as_array().unwrap();
+    assert_eq!(metadata.len(), 1);
+    assert_eq!(metadata[0]["flake_reference"], "github:owner/repo");
+    assert_eq!(metadata[0]["evaluation_mode"], "pure");
+    assert_eq!(metadata[0]["gcroots"].as_array().unwrap().len(), 1);
+    assert_eq!(metadata[0]["cache_key"]["version"], 1);
+    assert_eq!(metadata[0]["cache_key"]["nix_version"], "2.30.0");
+
+    cache_cmd(&fixtures, &["inspect", "github:owner/not-cached"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "No cached profile found for `github:owner/not-cached`.",
+        ));
+}
+
+#[test]
+fn cache_prune_and_clear_remove_cached_profiles() {
+    let fixtures = build_fixtures();
+
+    populate_cache(&fixtures, "/some/dir");
+    populate_cache(&fixtures, "/home/some/other/dir");
+
+    cache_cmd(&fixtures, &["prune", "--older-than", "1day"])
+        .assert()
+        .success()
+        .stdout(predicate::eq(""));
+    assert_eq!(fixtures.cache_sub_dirs().len(), 2);
+
+    cache_cmd(&fixtures, &["prune", "--older-than", "0s", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Would remove github:owner/repo"));
+    assert_eq!(fixtures.cache_sub_dirs().len(), 2);
+
+    cache_cmd(&fixtures, &["prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Removed github:owner/repo"));
+    assert_eq!(fixtures.cache_sub_dirs().len(), 0);
+
+    populate_cache(&fixtures, "/some/dir");
+    // NB: Anything else kept in the cache dir is left alone
+    let unrelated_dir = fixtures.cache_dir.path().join("unrelated");
+    fs::create_dir(&unrelated_dir).unwrap();
+    fs::write(unrelated_dir.join("data"), "data").unwrap();
+
+    cache_cmd(&fixtures, &["clear"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Removed github:owner/repo"))
+        .stdout(predicate::str::contains(format!(
+            "Skipped {}, as it isn't a cached profile",
+            unrelated_dir.display()
+        )));
+    assert_eq!(fixtures.cache_sub_dirs(), vec![unrelated_dir]);
+}
+
+#[test]
+fn cache_commands_skip_profiles_with_unreadable_metadata() {
+    let fixtures = build_fixtures();
+
+    populate_cache(&fixtures, "/some/dir");
+    populate_cache(&fixtures, "/home/some/other/dir");
+
+    let profile_dir = fixtures
+        .cache_sub_dirs()
+        .into_iter()
+        .find(|dir| {
+            fs::read_dir(dir).unwrap().any(|dir_entry| {
+                let path = dir_entry.unwrap().path();
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+                    && fs::read_to_string(&path)
+                        .unwrap()
+                        .contains(r#""flake_reference": "github:owner/repo""#)
+            })
+        })
+        .unwrap();
+    for dir_entry in fs::read_dir(&profile_dir).unwrap() {
+        let path = dir_entry.unwrap().path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            fs::write(path, "not json").unwrap();
+        }
+    }
+
+    let output = cache_cmd(&fixtures, &["list"]).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 2);
+    assert!(stdout.contains("github:other_github_owner/repo"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+        "skipping {}, as its metadata can't be read",
+        profile_dir.display()
+    )));
+
+    cache_cmd(&fixtures, &["prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Removed github:other_github_owner/repo",
+        ));
+    assert_eq!(fixtures.cache_sub_dirs().len(), 1);
+
+    cache_cmd(&fixtures, &["clear"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(profile_dir.to_string_lossy()));
+    assert_eq!(fixtures.cache_sub_dirs().len(), 0);
+}
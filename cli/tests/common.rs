@@ -1,5 +1,6 @@
 use std::{env, fs, os::unix::fs::PermissionsExt, path::PathBuf};
 
+use assert_cmd::{Command, cargo};
 use tempfile::TempDir;
 
 pub struct Fixtures {
@@ -10,12 +11,43 @@ pub struct Fixtures {
     pub path: String,
 }
 
+impl Fixtures {
+    /// Runs envoluntary with the given args, followed by the config and cache dir of the
+    /// fixtures, with the mock `nix` on the `PATH` and no environment loaded yet.
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(args)
+            .args(["--config-path", &self.config_file.to_string_lossy()])
+            .args(["--cache-dir", &self.cache_dir.path().to_string_lossy()])
+            .env("PATH", &self.path)
+            .env("HOME", "/home")
+            .env_remove("ENVOLUNTARY_ENV_STATE");
+        cmd
+    }
+
+    /// The dirs within the cache dir, leaving out the files kept next to them.
+    pub fn cache_sub_dirs(&self) -> Vec<PathBuf> {
+        let mut cache_sub_dirs = fs::read_dir(self.cache_dir.path())
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+        cache_sub_dirs.sort();
+        cache_sub_dirs
+    }
+}
+
 pub fn build_fixtures() -> Fixtures {
+    build_fixtures_with_profile_rc(DEFAULT_PROFILE_RC_CONTENT)
+}
+
+/// Same as [`build_fixtures`], with the mock `nix print-dev-env` printing the given rc instead.
+pub fn build_fixtures_with_profile_rc(profile_rc_content: &str) -> Fixtures {
     let work_dir = tempfile::tempdir().unwrap();
     let cache_dir = tempfile::tempdir_in(work_dir.path()).unwrap();
 
     let config_file = setup_mock_config(work_dir.path());
-    let bin_dir = setup_mock_nix_bin(work_dir.path());
+    let bin_dir = setup_mock_nix_bin_with_profile_rc(work_dir.path(), profile_rc_content);
 
     let original_path = env::var("PATH").unwrap_or_default();
     let new_path = format!("{}:{}", bin_dir.display(), original_path);
@@ -57,21 +89,31 @@ pub fn setup_mock_config(work_dir: &std::path::Path) -> std::path::PathBuf {
     config_file
 }
 
+const DEFAULT_PROFILE_RC_CONTENT: &str = "export FAKE_VAR=true;";
+
 pub fn setup_mock_nix_bin(work_dir: &std::path::Path) -> std::path::PathBuf {
+    setup_mock_nix_bin_with_profile_rc(work_dir, DEFAULT_PROFILE_RC_CONTENT)
+}
+
+pub fn setup_mock_nix_bin_with_profile_rc(
+    work_dir: &std::path::Path,
+    profile_rc_content: &str,
+) -> std::path::PathBuf {
     let bin_dir = work_dir.join("bin");
     fs::create_dir(&bin_dir).unwrap();
     let nix_file = bin_dir.join("nix");
 
     let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
-    let profile_rc_content = "export FAKE_VAR=true;";
 
     let nix_file_content = format!(
         r#"#! {bash_path}
 
 if [[ "$@" == "--extra-experimental-features nix-command flakes --version" ]]; then
     echo "nix (Nix) 2.30.0"
+elif [[ "$@" == "--extra-experimental-features nix-command flakes config show system" ]]; then
+    echo "x86_64-linux"
 elif [[ "$@" == "--extra-experimental-features nix-command flakes print-dev-env --no-write-lock-file --profile "* ]]; then
-rc="{profile_rc_content}"
+rc='{profile_rc_content}'
 for ((i=0; i<$#; i++)); do
     if [[ "${{@:$i:1}}" == "--profile" ]]; then
         profile_path="${{@:$((i+1)):1}}"
@@ -103,6 +103,8 @@ fn config_add_entry_with_custom_config_path() {
             ".*/package.json",
             "--impure",
             "true",
+            "--gcroots",
+            "profile",
             "--config-path",
             &config_path.to_string_lossy(),
         ]);
@@ -120,6 +122,7 @@ fn config_add_entry_with_custom_config_path() {
                 flake_reference = "github:owner/repo"
                 pattern_adjacent = ".*/package.json"
                 impure = true
+                gcroots = "profile"
             }
             .into()
         )
@@ -149,6 +152,7 @@ fn config_add_entry_with_custom_config_path() {
                 flake_reference = "github:owner/repo"
                 pattern_adjacent = ".*/package.json"
                 impure = true
+                gcroots = "profile"
 
                 [[entries]]
                 pattern = ".*"
@@ -179,8 +183,54 @@ fn config_add_entry_with_custom_config_path() {
                 "pattern": ".*",
                 "flake_reference": "github:owner/repo",
                 "pattern_adjacent": null,
-                "impure": null
+                "impure": null,
+                "gcroots": null,
+                "nix_args": null,
+                "hook_output": null,
+                "on_enter": null,
+                "on_leave": null
             }])
         )
     }
 }
+
+#[test]
+fn config_print_matching_entries_skips_denied_paths() {
+    let config_dir = tempfile::tempdir().unwrap();
+    let config_path = config_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        r#"
+[[entries]]
+pattern = "^/some/"
+flake_reference = "github:owner/repo"
+
+[[deny]]
+pattern = "^/some/vendor(/|$)"
+"#,
+    )
+    .unwrap();
+
+    let print_matching_entries = |path: &str| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "config",
+            "print-matching-entries",
+            path,
+            "--config-path",
+            &config_path.to_string_lossy(),
+        ]);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        print_matching_entries("/some/dir")[0]["flake_reference"],
+        "github:owner/repo"
+    );
+    assert_eq!(
+        print_matching_entries("/some/vendor/lib"),
+        serde_json::json!([])
+    );
+}
@@ -2,15 +2,22 @@ use std::{
     env, fs,
     os::unix::fs::PermissionsExt,
     process::{self},
+    thread,
+    time::{Duration, Instant},
 };
 
 use assert_cmd::{Command, cargo};
-use env_hooks::{BashSource, EnvVars, get_env_vars_from_bash};
+use bstr::{B, BString, ByteSlice};
+use env_hooks::{
+    BashSource, EnvVars, get_env_vars_and_shell_definitions_from_bash, get_env_vars_from_bash,
+    remove_ignored_env_vars,
+};
+use nix_dev_env::{EvaluationMode, NixCacheKey};
 use predicates::prelude::*;
-use sha1::{Digest, Sha1};
+use semver::Version;
 
 pub mod common;
-use common::{setup_mock_config, setup_mock_nix_bin};
+use common::{build_fixtures, build_fixtures_with_profile_rc};
 
 fn test_evaluable_syntax(shell_name: &str, shell_cmd: &str) {
     let mut cmd = Command::new(cargo::cargo_bin!());
@@ -54,26 +61,47 @@ fn shell_hook_zsh_produces_evaluable_shell_syntax() {
 
 #[test]
 fn shell_print_cache_path_outputs_valid_path() {
-    let cache_dir = tempfile::tempdir().unwrap();
+    let fixtures = build_fixtures();
+    let cache_dir = fixtures.cache_dir.path();
 
     let flake_reference = "github:owner/repo";
 
-    let mut cmd = Command::new(cargo::cargo_bin!());
-    cmd.args([
-        "shell",
-        "print-cache-path",
-        "--flake-reference",
-        flake_reference,
-        "--cache-dir",
-        &cache_dir.path().to_string_lossy(),
-    ]);
+    let print_cache_path = |extra_args: &[&str]| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "print-cache-path",
+            "--flake-reference",
+            flake_reference,
+            "--cache-dir",
+            &cache_dir.to_string_lossy(),
+        ])
+        .args(extra_args)
+        .env("PATH", &fixtures.path);
+        cmd
+    };
+    let expected_cache_path = |evaluation_mode| {
+        let cache_key = NixCacheKey::new(
+            flake_reference,
+            evaluation_mode,
+            "x86_64-linux",
+            &Version::new(2, 30, 0),
+            env!("CARGO_PKG_VERSION"),
+        );
+        format!(
+            "{}\n",
+            cache_dir.join(cache_key.digest().unwrap()).display()
+        )
+    };
 
-    cmd.assert().success().stdout(predicate::eq(
-        cache_dir
-            .path()
-            .join(format!("{:x}\n", Sha1::digest(flake_reference)))
-            .to_string_lossy(),
-    ));
+    print_cache_path(&[])
+        .assert()
+        .success()
+        .stdout(predicate::eq(expected_cache_path(EvaluationMode::Pure)));
+    print_cache_path(&["--impure"])
+        .assert()
+        .success()
+        .stdout(predicate::eq(expected_cache_path(EvaluationMode::Impure)));
 }
 
 #[test]
@@ -108,35 +136,22 @@ exit 0
 
 #[test]
 fn test_bash_export_state_init_update_and_reset() {
-    let work_dir = tempfile::tempdir().unwrap();
-    let cache_dir = tempfile::tempdir_in(work_dir.path()).unwrap();
-
     // 1. Setup mock environment
-    let config_file = setup_mock_config(work_dir.path());
-    let bin_dir = setup_mock_nix_bin(work_dir.path());
-
-    let original_path = env::var("PATH").unwrap_or_default();
-    let new_path = format!("{}:{}", bin_dir.display(), original_path);
+    let fixtures = build_fixtures();
+    let work_dir = &fixtures.work_dir;
+    let bin_dir = &fixtures.bin_dir;
+    let new_path = &fixtures.path;
 
     // 2. Helper closure to run the shell export command
     let run_export = |current_dir: &str, home_dir: &str, env_vars: Option<&EnvVars>| -> String {
-        let mut cmd = Command::new(cargo::cargo_bin!());
-        cmd.args([
-            "shell",
-            "export",
-            "bash",
-            "--config-path",
-            &config_file.to_string_lossy(),
-            "--cache-dir",
-            &cache_dir.path().to_string_lossy(),
-            "--current-dir",
-            current_dir,
-        ])
-        .env("PATH", &new_path)
-        .env("HOME", home_dir);
+        let mut cmd = fixtures.command(&["shell", "export", "bash", "--current-dir", current_dir]);
+        cmd.env("HOME", home_dir);
 
         if let Some(envs) = env_vars {
-            cmd.envs(envs.iter());
+            cmd.envs(
+                envs.iter()
+                    .map(|(key, value)| (key.to_os_str_lossy(), value.to_os_str_lossy())),
+            );
         }
 
         let output = cmd.output().unwrap();
@@ -160,7 +175,7 @@ fn test_bash_export_state_init_update_and_reset() {
         &initial_export,
         &[
             "export FAKE_VAR=true;",
-            "export ENVOLUNTARY_ENV_STATE=KLUv/QQ4dQMArAYAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9yZXBvIl0sImVudl92YXJzX3Jlc2V0Ijp7IkZBS0VfVkFSIjpudWxsLCJFTlZPTFVOVEFSWV9FTlZfU1RBVEUiOm51bGx9fQCbvTM7;",
+            "export ENVOLUNTARY_ENV_STATE=$'ehs1:1:zstd:cdc6013a:KLUv/QQ4RQQA7AYAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9yZXBvIl0sImVudl92YXJzX3Jlc2V0Ijp7IkZBS0VfVkFSIjpudWxsLCJFTlZPTFVOVEFSWV9FTlZfU1RBVEUifXNlInRydWUifX0DqBCAEv8/HxAufhCCcRJ+wXHQCLjwAWT+XHQ=';",
         ],
     );
 
@@ -178,7 +193,7 @@ fn test_bash_export_state_init_update_and_reset() {
             "unset FAKE_VAR;",
             "unset ENVOLUNTARY_ENV_STATE;",
             "export FAKE_VAR=true;",
-            "export ENVOLUNTARY_ENV_STATE=$'KLUv/QQ4HQQAHAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1YjpvdGhlcl9fb3duZXIvcmVwbyJdLCJlbnZfdmFyc19yZXNldCI6eyJGQUtFX1ZBUiI6bnVsbCwiRU5WT0xVTlRBUllfRU5WX1NUQVRFIjpudWxsfX0BqBDj//0Qww8Qow+DMUZWbCwq';",
+            "export ENVOLUNTARY_ENV_STATE=$'ehs1:1:zstd:6c288fa3:KLUv/QQ4pQQAXAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1YjpvdGhlcl9fb3duZXIvcmVwbyJdLCJlbnZfdmFyc19yZXNldCI6eyJGQUtFX1ZBUiI6bnVsbCwiRU5WT0xVTlRBUllfRU5WX1NUQVRFIn1zZSJ0cnVlIn19BKgQeBL/j0QfEAbKBxB6EiPhB8FhIDlAgAaC54HWJ6Y=';",
         ],
     );
 
@@ -209,7 +224,7 @@ fn test_bash_export_state_init_update_and_reset() {
         &pattern_adjacent_match,
         &[
             "export FAKE_VAR=true;",
-            "export ENVOLUNTARY_ENV_STATE=$'KLUv/QQ4zQMAXAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9zdXBlcl9jb29sX3Rvb2wiXSwiZW52X3ZhcnNfcmVzZXQiOnsiRkFLRV9WQVIiOm51bGwsIkVOVk9MVU5UQVJZX0VOVl9TVEFURSI6bnVsbH19ACCjTjk=';",
+            "export ENVOLUNTARY_ENV_STATE=$'ehs1:1:zstd:e58e5567:KLUv/QQ4rQQArAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9zdXBlcl9jb29sX3Rvb2wiXSwiZW52X3ZhcnNfcmVzZXQiOnsiRkFLRV9WQVIiOm51bGwsIkVOVk9MVU5UQVJZX0VOVl9TVEFURSJ9LHNlInRydWUifX0DqBCK8f/zARAufhCCMRLzAcFhkAu4GAGB1Jqh';",
         ],
     );
 
@@ -223,7 +238,7 @@ fn test_bash_export_state_init_update_and_reset() {
         &pattern_adjacent_home_export,
         &[
             "export FAKE_VAR=true;",
-            "export ENVOLUNTARY_ENV_STATE=$'KLUv/QQ4tQMALAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9hd2Vzb21lX3Rvb2wiXSwiZW52X3ZhcnNfcmVzZXQiOnsiRkFLRV9WQVIiOm51bGwsIkVOVk9MVU5UQVJZX0VOVl9TVEFURSI6bnVsbH19AB6hxkc=';",
+            "export ENVOLUNTARY_ENV_STATE=$'ehs1:1:zstd:25583c7e:KLUv/QQ4hQQAbAcAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9hd2Vzb21lX3Rvb2wiXSwiZW52X3ZhcnNfcmVzZXQiOnsiRkFLRV9WQVIiOm51bGwsIkVOVk9MVU5UQVJZX0VOVl9TVEFURSJ9c2UidHJ1ZSJ9fQOoEIAS/z8fEC5+EIJxEn7BcdAKuPABzesKOg==';",
         ],
     );
 }
@@ -234,3 +249,1181 @@ fn assert_output_lines(output: &str, expected: &[&str]) {
     let lines: Vec<_> = output.split('\n').filter(|s| !s.is_empty()).collect();
     assert_eq!(lines, expected);
 }
+
+#[test]
+fn shell_export_offline_skips_entries_that_cannot_be_built() {
+    let fixtures = build_fixtures();
+
+    let export = |offline: bool, env_state: Option<&str>| {
+        let mut cmd = fixtures.command(&["shell", "export", "json", "--current-dir", "/some/dir"]);
+        cmd.env_remove("FAKE_VAR");
+        if offline {
+            cmd.arg("--offline");
+        }
+        if let Some(env_state) = env_state {
+            cmd.env("ENVOLUNTARY_ENV_STATE", env_state);
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    // NB: The mock `nix` doesn't know `--offline`, so building the profile fails
+    let (offline_export, stderr) = export(true, None);
+    let offline_export = serde_json::from_str::<serde_json::Value>(&offline_export).unwrap();
+    assert!(
+        stderr
+            .contains("envoluntary: couldn't build `github:owner/repo` while offline, skipping it")
+    );
+    assert!(offline_export.get("FAKE_VAR").is_none());
+    let env_state = offline_export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    // NB: Building isn't retried on every prompt while still offline
+    assert_eq!(
+        export(true, Some(env_state)),
+        (String::new(), String::new())
+    );
+
+    // NB: The environment is unloaded and loaded again, so there's an object for each
+    let (online_export, _) = export(false, Some(env_state));
+    let online_export = serde_json::Deserializer::from_str(&online_export)
+        .into_iter::<serde_json::Map<String, serde_json::Value>>()
+        .map(Result::unwrap)
+        .reduce(|mut acc, object| {
+            acc.extend(object);
+            acc
+        })
+        .unwrap();
+    assert_eq!(online_export["FAKE_VAR"], "true");
+}
+
+#[test]
+fn shell_export_offline_keeps_profiles_built_by_other_versions() {
+    let fixtures = build_fixtures();
+
+    let export = |offline: bool| {
+        let mut cmd = fixtures.command(&["shell", "export", "json", "--current-dir", "/some/dir"]);
+        cmd.env_remove("FAKE_VAR");
+        if offline {
+            cmd.arg("--offline");
+        }
+        cmd.assert().success();
+    };
+
+    export(false);
+    let old_cache_dirs = fixtures.cache_sub_dirs();
+    assert_eq!(old_cache_dirs.len(), 1);
+
+    // NB: Upgrading Nix changes the cache key, so the profile has to be built again
+    let nix_file = fixtures.bin_dir.join("nix");
+    fs::write(
+        &nix_file,
+        fs::read_to_string(&nix_file)
+            .unwrap()
+            .replace("nix (Nix) 2.30.0", "nix (Nix) 2.31.0"),
+    )
+    .unwrap();
+
+    // NB: Building fails while offline, which leaves the profile built by the old version
+    export(true);
+    assert!(old_cache_dirs[0].exists());
+
+    // NB: Once the new profile is built, the old one is of no use anymore
+    export(false);
+    assert!(!old_cache_dirs[0].exists());
+    assert_eq!(fixtures.cache_sub_dirs().len(), 1);
+}
+
+#[test]
+fn shell_export_keeps_nix_system_in_cache_dir() {
+    let fixtures = build_fixtures();
+
+    let export = || {
+        fixtures
+            .command(&["shell", "export", "json", "--current-dir", "/some/dir"])
+            .env_remove("FAKE_VAR")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(r#""FAKE_VAR": "true""#))
+    };
+
+    export();
+    assert_eq!(
+        fs::read_to_string(fixtures.cache_dir.path().join("nix-system-2.30.0")).unwrap(),
+        "x86_64-linux"
+    );
+
+    // NB: Nix isn't asked again, so the profile that was built is still used
+    let nix_file = fixtures.bin_dir.join("nix");
+    fs::write(
+        &nix_file,
+        fs::read_to_string(&nix_file)
+            .unwrap()
+            .replace(r#"echo "x86_64-linux""#, "exit 1"),
+    )
+    .unwrap();
+    export();
+    assert_eq!(fixtures.cache_sub_dirs().len(), 1);
+}
+
+#[test]
+fn shell_export_captures_env_in_each_directory() {
+    let fixtures = build_fixtures_with_profile_rc(
+        r#"export PRJ_ROOT="$PWD"; export XDG_DATA_DIRS="/nix/share:$XDG_DATA_DIRS";"#,
+    );
+    let home_dir = fixtures.work_dir.path();
+
+    let export = |current_dir: &std::path::Path| {
+        fs::create_dir_all(current_dir).unwrap();
+        let output = fixtures
+            .command(&["shell", "export", "json"])
+            .args(["--current-dir", &current_dir.to_string_lossy()])
+            .env("HOME", home_dir)
+            .env("XDG_DATA_DIRS", "/host/share")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    // NB: Both directories share the profile, but the shell hook is sourced in each of them
+    let dir = home_dir.join("some/other/dir/a");
+    let export_a = export(&dir);
+    assert_eq!(export_a["PRJ_ROOT"], dir.to_string_lossy().as_ref());
+    assert_eq!(export_a["XDG_DATA_DIRS"], "/nix/share:/host/share");
+
+    let dir = home_dir.join("some/other/dir/b");
+    let export_b = export(&dir);
+    assert_eq!(export_b["PRJ_ROOT"], dir.to_string_lossy().as_ref());
+    assert_eq!(export_b["XDG_DATA_DIRS"], "/nix/share:/host/share");
+}
+
+#[test]
+fn shell_export_keeps_envs_of_the_last_few_directories() {
+    let fixtures = build_fixtures();
+    let home_dir = fixtures.work_dir.path();
+
+    for index in 0..18 {
+        let current_dir = home_dir.join(format!("some/other/dir/{index}"));
+        fixtures
+            .command(&["shell", "export", "json"])
+            .args(["--current-dir", &current_dir.to_string_lossy()])
+            .env("HOME", home_dir)
+            .assert()
+            .success();
+    }
+
+    let profile_envs = fs::read_dir(fixtures.cache_sub_dirs().remove(0))
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "env"))
+        .count();
+    assert_eq!(profile_envs, 16);
+}
+
+#[test]
+fn shell_export_reuses_env_captured_when_profile_was_built() {
+    let fixtures = build_fixtures();
+
+    let export = || {
+        let output = fixtures
+            .command(&["shell", "export", "json", "--current-dir", "/some/dir"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    assert_eq!(export()["FAKE_VAR"], "true");
+
+    let cache_sub_dir = fixtures.cache_sub_dirs().remove(0);
+    let profile_files = fs::read_dir(&cache_sub_dir)
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().path())
+        .collect::<Vec<_>>();
+    let profile_env = profile_files
+        .iter()
+        .find(|path| path.extension().is_some_and(|ext| ext == "env"))
+        .unwrap();
+    let profile_env =
+        serde_json::from_slice::<serde_json::Value>(&fs::read(profile_env).unwrap()).unwrap();
+    let env_vars: EnvVars = serde_json::from_value(profile_env["env_vars"].clone()).unwrap();
+    assert_eq!(env_vars.get(B("FAKE_VAR")), Some(&BString::from("true")));
+
+    // NB: The rc isn't sourced again, so changes to it don't show up until the profile is rebuilt
+    let profile_rc = profile_files
+        .iter()
+        .find(|path| path.extension().is_some_and(|ext| ext == "rc"))
+        .unwrap();
+    fs::write(profile_rc, "export FAKE_VAR=changed;").unwrap();
+    assert_eq!(export()["FAKE_VAR"], "true");
+}
+
+#[test]
+fn shell_export_merges_configured_path_vars() {
+    let fixtures = build_fixtures_with_profile_rc(
+        "export MANPATH=/nix/store/fake/share/man; export PATH=/nix/store/fake/bin:$PATH;",
+    );
+    let mut config = fs::read_to_string(&fixtures.config_file).unwrap();
+    config.push_str("\n[path_vars.MANPATH]\nstrategy = \"append\"\n");
+    fs::write(&fixtures.config_file, config).unwrap();
+
+    let output = fixtures
+        .command(&["shell", "export", "json", "--current-dir", "/some/dir"])
+        .env("MANPATH", "/usr/share/man")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let exports = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+
+    assert_eq!(
+        exports["MANPATH"],
+        "/usr/share/man:/nix/store/fake/share/man"
+    );
+    // NB: Duplicate entries of the host `PATH` get removed, so only check where the entries go
+    let path = exports["PATH"].as_str().unwrap();
+    assert!(path.starts_with(&format!(
+        "/nix/store/fake/bin:{}:",
+        fixtures.bin_dir.display()
+    )));
+}
+
+#[test]
+fn shell_export_filters_configured_env_vars() {
+    let fixtures = build_fixtures_with_profile_rc(
+        "export FAKE_VAR=true; export TERM=dumb; export SSH_AUTH_SOCK=/tmp/agent; export TMPDIR=/tmp/devshell;",
+    );
+    let config = fs::read_to_string(&fixtures.config_file).unwrap();
+    let run_export = |filter: &str| {
+        // NB: Top-level keys have to come before the `[[entries]]` tables
+        fs::write(&fixtures.config_file, format!("{filter}\n{config}")).unwrap();
+
+        let output = fixtures
+            .command(&["shell", "export", "json", "--current-dir", "/some/dir"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    let exports = run_export(r#"ignored_env_vars = ["TERM", "SSH_.*"]"#);
+    assert_eq!(exports["FAKE_VAR"], "true");
+    assert_eq!(exports["TMPDIR"], "/tmp/devshell");
+    assert!(exports.get("TERM").is_none());
+    assert!(exports.get("SSH_AUTH_SOCK").is_none());
+
+    let exports = run_export(r#"allowed_env_vars = ["FAKE_.*"]"#);
+    assert_eq!(exports["FAKE_VAR"], "true");
+    assert!(exports.get("TERM").is_none());
+    assert!(exports.get("TMPDIR").is_none());
+    assert!(exports.get("ENVOLUNTARY_ENV_STATE").is_some());
+}
+
+#[test]
+fn shell_export_keeps_variables_changed_by_hand_when_unloading() {
+    let fixtures = build_fixtures_with_profile_rc(
+        "export FAKE_VAR=true; export OTHER_VAR=true; export PATH=/nix/store/fake/bin:$PATH;",
+    );
+
+    let run_export = |current_dir: &str, env_vars: &EnvVars| {
+        let output = fixtures
+            .command(&["shell", "export", "bash", "--current-dir", current_dir])
+            .env_clear()
+            .envs(
+                env_vars
+                    .iter()
+                    .map(|(key, value)| (key.to_os_str_lossy(), value.to_os_str_lossy())),
+            )
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let source = |script: Vec<u8>, env_vars: EnvVars| {
+        let mut env_vars =
+            get_env_vars_from_bash(BashSource::Script(script.into()), Some(env_vars)).unwrap();
+        remove_ignored_env_vars(&mut env_vars);
+        env_vars
+    };
+
+    let host_env_vars = EnvVars::from_iter([
+        ("PATH", fixtures.path.as_str()),
+        ("HOME", "/home"),
+        ("OTHER_VAR", "host"),
+    ]);
+    let loaded_env_vars = source(
+        run_export("/some/dir", &host_env_vars),
+        host_env_vars.clone(),
+    );
+    assert_eq!(
+        loaded_env_vars.get(B("FAKE_VAR")),
+        Some(&BString::from("true"))
+    );
+
+    let edited_env_vars = source(
+        Vec::from(r#"export FAKE_VAR=by-hand; export PATH="/home/user/bin:$PATH";"#),
+        loaded_env_vars,
+    );
+    let unloaded_env_vars = source(run_export("/", &edited_env_vars), edited_env_vars);
+
+    assert_eq!(
+        unloaded_env_vars.get(B("FAKE_VAR")),
+        Some(&BString::from("by-hand"))
+    );
+    assert_eq!(
+        unloaded_env_vars.get(B("OTHER_VAR")),
+        Some(&BString::from("host"))
+    );
+    assert_eq!(
+        unloaded_env_vars.get(B("PATH")),
+        Some(&BString::from(format!("/home/user/bin:{}", fixtures.path)))
+    );
+    assert!(!unloaded_env_vars.contains_key(B("ENVOLUNTARY_ENV_STATE")));
+}
+
+#[test]
+fn shell_export_defines_and_removes_shell_functions_and_aliases() {
+    let fixtures = build_fixtures_with_profile_rc(
+        r#"export FAKE_VAR=true; greet() { echo "hello $1"; }; alias ll="ls -l";"#,
+    );
+
+    let run_export = |current_dir: &str, env_vars: &EnvVars| {
+        let output = fixtures
+            .command(&["shell", "export", "bash", "--current-dir", current_dir])
+            .env_clear()
+            .envs(
+                env_vars
+                    .iter()
+                    .map(|(key, value)| (key.to_os_str_lossy(), value.to_os_str_lossy())),
+            )
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+
+    let host_env_vars = EnvVars::from_iter([("PATH", fixtures.path.as_str()), ("HOME", "/home")]);
+    let (loaded_env_vars, loaded_shell_definitions) = get_env_vars_and_shell_definitions_from_bash(
+        BashSource::Script(run_export("/some/dir", &host_env_vars).into()),
+        Some(host_env_vars),
+    )
+    .unwrap();
+    assert!(loaded_shell_definitions.functions.contains_key("greet"));
+    assert_eq!(
+        loaded_shell_definitions
+            .aliases
+            .get("ll")
+            .map(String::as_str),
+        Some("ls -l")
+    );
+
+    let unload_export = run_export("/", &loaded_env_vars);
+    assert!(unload_export.contains_str("unset -f -- greet;"));
+    assert!(unload_export.contains_str("unalias -- ll 2>/dev/null;"));
+}
+
+#[test]
+fn shell_export_shows_hook_output_and_runs_on_enter_and_on_leave_commands() {
+    let fixtures =
+        build_fixtures_with_profile_rc("echo welcome-to-the-devshell; export FAKE_VAR=true;");
+    fs::write(
+        &fixtures.config_file,
+        toml::to_string_pretty(&toml::toml! {
+            hook_output = "once-per-session"
+
+            [[entries]]
+            pattern = "^/some/dir(/.*)?"
+            flake_reference = "github:owner/repo"
+            on_enter = ["echo entered"]
+            on_leave = ["echo left"]
+
+            [[entries]]
+            pattern = "^/quiet/dir(/.*)?"
+            flake_reference = "github:owner/quiet"
+            hook_output = "quiet"
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let run_export = |current_dir: &str, env_vars: &EnvVars| {
+        let output = fixtures
+            .command(&["shell", "export", "bash", "--current-dir", current_dir])
+            .env_clear()
+            .envs(
+                env_vars
+                    .iter()
+                    .map(|(key, value)| (key.to_os_str_lossy(), value.to_os_str_lossy())),
+            )
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output
+    };
+    let source = |script: Vec<u8>, env_vars: EnvVars| {
+        // NB: The commands run while sourcing, so their output is left out of the script
+        let script = script
+            .lines()
+            .filter(|line| !line.starts_with(b"echo "))
+            .collect::<Vec<_>>();
+        get_env_vars_from_bash(
+            BashSource::Script(bstr::join("\n", script).into()),
+            Some(env_vars),
+        )
+        .unwrap()
+    };
+
+    let host_env_vars = EnvVars::from_iter([("PATH", fixtures.path.as_str()), ("HOME", "/home")]);
+    let load_output = run_export("/some/dir", &host_env_vars);
+    assert!(load_output.stderr.contains_str("welcome-to-the-devshell"));
+    assert!(
+        load_output
+            .stdout
+            .lines()
+            .any(|line| line == b"echo entered")
+    );
+    let loaded_env_vars = source(load_output.stdout, host_env_vars);
+    assert_eq!(
+        loaded_env_vars.get(B("ENVOLUNTARY_HOOKS_RUN")),
+        Some(&BString::from("github:owner/repo"))
+    );
+
+    let unload_output = run_export("/", &loaded_env_vars);
+    assert!(
+        unload_output
+            .stdout
+            .lines()
+            .any(|line| line == b"echo left")
+    );
+    let unloaded_env_vars = source(unload_output.stdout, loaded_env_vars);
+    assert!(!unloaded_env_vars.contains_key(B("FAKE_VAR")));
+
+    // NB: The session already saw the output, but the commands still run on every entry
+    let reload_output = run_export("/some/dir", &unloaded_env_vars);
+    assert!(!reload_output.stderr.contains_str("welcome-to-the-devshell"));
+    assert!(
+        reload_output
+            .stdout
+            .lines()
+            .any(|line| line == b"echo entered")
+    );
+
+    let quiet_output = run_export("/quiet/dir", &unloaded_env_vars);
+    assert!(!quiet_output.stderr.contains_str("welcome-to-the-devshell"));
+}
+
+#[test]
+fn shell_export_times_out_without_changing_the_environment() {
+    let fixtures = build_fixtures_with_profile_rc("sleep 30; export FAKE_VAR=true;");
+
+    let started_at = Instant::now();
+    fixtures
+        .command(&["shell", "export", "bash", "--timeout", "500ms"])
+        .args(["--current-dir", "/some/dir"])
+        .assert()
+        .code(124)
+        .stdout(predicate::eq(""))
+        .stderr(predicate::str::contains(
+            "activation timed out after 500ms, the environment was left as it was",
+        ));
+    assert!(started_at.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn shell_export_cancelled_by_sigint_without_changing_the_environment() {
+    let fixtures = build_fixtures_with_profile_rc("sleep 30; export FAKE_VAR=true;");
+
+    let child = process::Command::new(cargo::cargo_bin!())
+        .args([
+            "shell",
+            "export",
+            "bash",
+            "--config-path",
+            &fixtures.config_file.to_string_lossy(),
+            "--cache-dir",
+            &fixtures.cache_dir.path().to_string_lossy(),
+            "--current-dir",
+            "/some/dir",
+        ])
+        .env("PATH", &fixtures.path)
+        .env("HOME", "/home")
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // NB: Give the export time to start sourcing the rc, which hangs
+    thread::sleep(Duration::from_millis(500));
+    let started_at = Instant::now();
+    process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(output.stdout, b"");
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("envoluntary: activation cancelled, the environment was left as it was")
+    );
+    assert!(started_at.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn shell_export_exits_with_distinct_codes_for_each_failure() {
+    let fixtures = build_fixtures_with_profile_rc("exit 1;");
+    let export = || fixtures.command(&["shell", "export", "bash", "--current-dir", "/some/dir"]);
+
+    let empty_bin_dir = tempfile::tempdir().unwrap();
+    export()
+        .env("PATH", empty_bin_dir.path())
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("`nix` could not be found"));
+
+    export().assert().code(8).stderr(predicate::str::contains(
+        "Bash command to retrieve env vars failed",
+    ));
+
+    // NB: Exporting recovers from state it can't decode, so only reading it strictly fails
+    fixtures
+        .command(&["status", "--current-dir", "/"])
+        .env("ENVOLUNTARY_ENV_STATE", "ehs1:999:zstd:00000000:")
+        .assert()
+        .code(9)
+        .stderr(predicate::str::contains("has version 999"));
+
+    let config = fs::read_to_string(&fixtures.config_file).unwrap();
+    fs::write(
+        &fixtures.config_file,
+        format!("state_store = \"file\"\n{config}"),
+    )
+    .unwrap();
+    let runtime_dir = tempfile::tempdir().unwrap();
+    export()
+        .env("XDG_RUNTIME_DIR", runtime_dir.path())
+        .env("ENVOLUNTARY_SESSION_ID", "../../etc/passwd")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(
+            "Session id `../../etc/passwd` is invalid",
+        ));
+}
+
+#[test]
+fn shell_export_unloads_state_written_before_it_had_a_header() {
+    let fixtures = build_fixtures();
+
+    let output = fixtures
+        .command(&["shell", "export", "bash", "--current-dir", "/"])
+        .env("FAKE_VAR", "true")
+        .env(
+            "ENVOLUNTARY_ENV_STATE",
+            "KLUv/QQ4RQQA7AYAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9yZXBvIl0sImVudl92YXJzX3Jlc2V0Ijp7IkZBS0VfVkFSIjpudWxsLCJFTlZPTFVOVEFSWV9FTlZfU1RBVEUifXNlInRydWUifX0DqBCAEv8/HxAufhCCcRJ+wXHQCLjwAWT+XHQ=",
+        )
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_output_lines(
+        &String::from_utf8_lossy(&output.stdout),
+        &["unset FAKE_VAR;", "unset ENVOLUNTARY_ENV_STATE;"],
+    );
+}
+
+#[test]
+fn shell_export_keeps_state_in_a_session_file_when_configured() {
+    let fixtures = build_fixtures();
+    let config = fs::read_to_string(&fixtures.config_file).unwrap();
+    fs::write(
+        &fixtures.config_file,
+        format!("state_store = \"file\"\n{config}"),
+    )
+    .unwrap();
+    let runtime_dir = tempfile::tempdir().unwrap();
+    let export_cmd = |current_dir: &str| {
+        let mut cmd = fixtures.command(&["shell", "export", "bash", "--current-dir", current_dir]);
+        cmd.env("XDG_RUNTIME_DIR", runtime_dir.path())
+            .env("ENVOLUNTARY_SESSION_ID", "1234-5678");
+        cmd
+    };
+
+    let output = export_cmd("/some/dir").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let token = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("export ENVOLUNTARY_ENV_STATE="))
+        .unwrap()
+        .trim_end_matches(';')
+        .trim_start_matches('$')
+        .trim_matches('\'');
+    let file_name = token.strip_prefix("file:").unwrap();
+    assert!(file_name.starts_with("1234-5678."));
+    let state_file = runtime_dir.path().join("envoluntary").join(file_name);
+    assert!(
+        fs::read_to_string(&state_file)
+            .unwrap()
+            .starts_with("ehs1:")
+    );
+
+    let output = export_cmd("/")
+        .env("FAKE_VAR", "true")
+        .env("ENVOLUNTARY_ENV_STATE", token)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_output_lines(
+        &String::from_utf8_lossy(&output.stdout),
+        &["unset FAKE_VAR;", "unset ENVOLUNTARY_ENV_STATE;"],
+    );
+}
+
+#[test]
+fn shell_export_drops_state_that_cannot_be_read() {
+    let fixtures = build_fixtures();
+
+    fixtures
+        .command(&["shell", "export", "bash", "--current-dir", "/"])
+        .env("ENVOLUNTARY_ENV_STATE", "not-a-valid-state")
+        .env_remove("ENVOLUNTARY_SESSION_ID")
+        .assert()
+        .success()
+        .stdout("unset ENVOLUNTARY_ENV_STATE;\n")
+        .stderr(predicate::str::contains(
+            "the state of this shell couldn't be read, so it was dropped",
+        ));
+}
+
+#[test]
+fn shell_export_drops_state_with_missing_chunks() {
+    let fixtures = build_fixtures();
+
+    fixtures
+        .command(&["shell", "export", "bash", "--current-dir", "/"])
+        .env("ENVOLUNTARY_ENV_STATE", "chunked:2")
+        .env("ENVOLUNTARY_ENV_STATE_0", "ehs1:1:")
+        .env_remove("ENVOLUNTARY_SESSION_ID")
+        .assert()
+        .success()
+        .stdout("unset ENVOLUNTARY_ENV_STATE;\nunset ENVOLUNTARY_ENV_STATE_0;\n")
+        .stderr(predicate::str::contains(
+            "chunk `ENVOLUNTARY_ENV_STATE_1` is missing",
+        ));
+}
+
+#[test]
+fn shell_export_unloads_state_that_cannot_be_read_from_snapshot() {
+    let fixtures = build_fixtures();
+    let runtime_dir = tempfile::tempdir().unwrap();
+    let export_cmd = |shell: &str, current_dir: &str| {
+        let mut cmd = fixtures.command(&["shell", "export", shell, "--current-dir", current_dir]);
+        cmd.env("XDG_RUNTIME_DIR", runtime_dir.path())
+            .env("ENVOLUNTARY_SESSION_ID", "1234-5678");
+        cmd
+    };
+
+    let output = export_cmd("json", "/some/dir").output().unwrap();
+    assert!(output.status.success());
+    assert!(
+        runtime_dir
+            .path()
+            .join("envoluntary/snapshots/1234-5678.json")
+            .exists()
+    );
+
+    let output = export_cmd("bash", "/")
+        .env("FAKE_VAR", "true")
+        .env("ENVOLUNTARY_ENV_STATE", "ehs1:1:zstd:00000000:truncated")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_output_lines(
+        &String::from_utf8_lossy(&output.stdout),
+        &["unset FAKE_VAR;", "unset ENVOLUNTARY_ENV_STATE;"],
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("unloading the environment from the last snapshot of the state")
+    );
+}
+
+#[test]
+fn shell_reset_unloads_every_environment() {
+    let fixtures = build_fixtures();
+
+    let output = fixtures
+        .command(&["shell", "export", "json", "--current-dir", "/some/dir"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let export = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    let output = Command::new(cargo::cargo_bin!())
+        .args(["shell", "reset", "bash"])
+        .args(["--config-path", &fixtures.config_file.to_string_lossy()])
+        .env("PATH", &fixtures.path)
+        .env("HOME", "/home")
+        .env("FAKE_VAR", "true")
+        .env("ENVOLUNTARY_ENV_STATE", env_state)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_output_lines(
+        &String::from_utf8_lossy(&output.stdout),
+        &[
+            "unset FAKE_VAR;",
+            "unset ENVOLUNTARY_ENV_STATE;",
+            "unset ENVOLUNTARY_HOOKS_RUN;",
+            "unset ENVOLUNTARY_PAUSED;",
+        ],
+    );
+}
+
+fn run_manual_change(
+    fixtures: &common::Fixtures,
+    args: &[&str],
+    env_state: Option<&str>,
+) -> String {
+    let mut cmd = fixtures.command(args);
+    cmd.args(["--shell", "json", "--current-dir", "/"]);
+    if let Some(env_state) = env_state {
+        cmd.env("ENVOLUNTARY_ENV_STATE", env_state)
+            .env("FAKE_VAR", "true");
+    }
+    let output = cmd.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn load_keeps_environment_loaded_until_unloaded() {
+    let fixtures = build_fixtures();
+
+    let export = run_manual_change(&fixtures, &["load", "github:owner/repo"], None);
+    let export = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+    assert_eq!(export["FAKE_VAR"], "true");
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    let output = fixtures
+        .command(&["shell", "export", "json", "--current-dir", "/"])
+        .env("FAKE_VAR", "true")
+        .env("ENVOLUNTARY_ENV_STATE", env_state)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+    let export = run_manual_change(&fixtures, &["unload"], Some(env_state));
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&export).unwrap(),
+        serde_json::json!({"FAKE_VAR": null, "ENVOLUNTARY_ENV_STATE": null})
+    );
+}
+
+#[test]
+fn load_finds_entries_by_name() {
+    let fixtures = build_fixtures();
+    let config = fs::read_to_string(&fixtures.config_file).unwrap();
+    fs::write(
+        &fixtures.config_file,
+        format!(
+            "{config}\n[[entries]]\nname = \"tool\"\npattern = \"^$\"\nflake_reference = \"github:owner/tool\"\n"
+        ),
+    )
+    .unwrap();
+
+    let export = run_manual_change(&fixtures, &["load", "tool"], None);
+    let export = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+    assert_eq!(export["FAKE_VAR"], "true");
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    let export = run_manual_change(&fixtures, &["unload", "tool"], Some(env_state));
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&export).unwrap(),
+        serde_json::json!({"FAKE_VAR": null, "ENVOLUNTARY_ENV_STATE": null})
+    );
+}
+
+#[test]
+fn reload_loads_the_same_environments_again() {
+    let fixtures = build_fixtures();
+
+    let export = run_manual_change(&fixtures, &["load", "github:owner/repo"], None);
+    let export = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    // NB: The environment is unloaded and loaded again, so there's an object for each
+    let export = run_manual_change(&fixtures, &["reload"], Some(env_state));
+    let export = serde_json::Deserializer::from_str(&export)
+        .into_iter::<serde_json::Map<String, serde_json::Value>>()
+        .map(Result::unwrap)
+        .reduce(|mut acc, object| {
+            acc.extend(object);
+            acc
+        })
+        .unwrap();
+    assert_eq!(export["FAKE_VAR"], "true");
+    assert_eq!(export["ENVOLUNTARY_ENV_STATE"], env_state);
+}
+
+#[test]
+fn unload_fails_for_profiles_that_were_not_loaded() {
+    let fixtures = build_fixtures();
+
+    fixtures
+        .command(&["unload", "github:owner/repo", "--shell", "bash"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "`github:owner/repo` wasn't loaded with `envoluntary load`",
+        ));
+}
+
+#[test]
+fn pause_unloads_environments_until_resumed() {
+    let fixtures = build_fixtures();
+
+    let export = run_manual_change(&fixtures, &["load", "github:owner/repo"], None);
+    let export = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    let export = fixtures
+        .command(&["pause", "--shell", "json", "--current-dir", "/"])
+        .env("ENVOLUNTARY_ENV_STATE", env_state)
+        .env("ENVOLUNTARY_SESSION_ID", "1234-5678")
+        .env("FAKE_VAR", "true")
+        .output()
+        .unwrap();
+    assert!(
+        export.status.success(),
+        "{}",
+        String::from_utf8_lossy(&export.stderr)
+    );
+    let export = serde_json::Deserializer::from_slice(&export.stdout)
+        .into_iter::<serde_json::Map<String, serde_json::Value>>()
+        .map(Result::unwrap)
+        .reduce(|mut acc, object| {
+            acc.extend(object);
+            acc
+        })
+        .unwrap();
+    assert_eq!(
+        serde_json::Value::from(export),
+        serde_json::json!({
+            "ENVOLUNTARY_PAUSED": "1234-5678",
+            "FAKE_VAR": null,
+            "ENVOLUNTARY_ENV_STATE": null,
+        })
+    );
+
+    // NB: Nothing gets loaded while paused, even in directories matching an entry
+    let export_in_session = |session_id: &str, args: &[&str]| {
+        fixtures
+            .command(args)
+            .env("ENVOLUNTARY_PAUSED", "1234-5678")
+            .env("ENVOLUNTARY_SESSION_ID", session_id)
+            .assert()
+    };
+    let export_paused = |args: &[&str]| export_in_session("1234-5678", args);
+    export_paused(&["shell", "export", "json", "--current-dir", "/some/dir"])
+        .success()
+        .stdout("");
+    export_paused(&["load", "github:owner/repo", "--shell", "json"])
+        .failure()
+        .stderr(predicate::str::contains("run `envoluntary resume` first"));
+
+    // NB: Shells started from the paused one inherit the var, but have a session of their own
+    let export = export_in_session(
+        "8765-4321",
+        &["shell", "export", "json", "--current-dir", "/some/dir"],
+    )
+    .success()
+    .get_output()
+    .stdout
+    .clone();
+    let export = serde_json::Deserializer::from_slice(&export)
+        .into_iter::<serde_json::Map<String, serde_json::Value>>()
+        .map(Result::unwrap)
+        .reduce(|mut acc, object| {
+            acc.extend(object);
+            acc
+        })
+        .unwrap();
+    assert_eq!(export["FAKE_VAR"], "true");
+
+    let export = export_paused(&["resume", "--shell", "json", "--current-dir", "/some/dir"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let export = serde_json::Deserializer::from_slice(&export)
+        .into_iter::<serde_json::Map<String, serde_json::Value>>()
+        .map(Result::unwrap)
+        .reduce(|mut acc, object| {
+            acc.extend(object);
+            acc
+        })
+        .unwrap();
+    assert_eq!(export["ENVOLUNTARY_PAUSED"], serde_json::Value::Null);
+    assert_eq!(export["FAKE_VAR"], "true");
+}
+
+#[test]
+fn status_describes_loaded_environments() {
+    let fixtures = build_fixtures();
+
+    let export = run_manual_change(&fixtures, &["load", "github:owner/repo"], None);
+    let export = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+    let env_state = export["ENVOLUNTARY_ENV_STATE"].as_str().unwrap();
+
+    let status = |format: &str| {
+        fixtures
+            .command(&["status", "--format", format, "--current-dir", "/some/dir"])
+            .env("FAKE_VAR", "true")
+            .env("ENVOLUNTARY_ENV_STATE", env_state)
+            .env_remove("ENVOLUNTARY_PAUSED")
+            .output()
+            .unwrap()
+    };
+
+    let output = status("json");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let status_json = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(status_json["paused"], false);
+    assert_eq!(
+        status_json["flake_references"],
+        serde_json::json!(["github:owner/repo"])
+    );
+    assert_eq!(
+        status_json["loaded_manually"],
+        serde_json::json!(["github:owner/repo"])
+    );
+    assert_eq!(
+        status_json["matched_entries"][0]["flake_reference"],
+        "github:owner/repo"
+    );
+    assert_eq!(
+        status_json["profiles"][0]["flake_reference"],
+        "github:owner/repo"
+    );
+    assert!(status_json["profiles"][0]["built_at"].is_string());
+    assert_eq!(status_json["profiles"][0]["stale"], false);
+    assert_eq!(
+        status_json["env_vars"],
+        serde_json::json!({
+            "added": {"FAKE_VAR": "true"},
+            "changed": {},
+            "removed": {},
+        })
+    );
+
+    let output = status("human");
+    assert!(output.status.success());
+    let status_human = String::from_utf8_lossy(&output.stdout);
+    assert!(status_human.contains("  github:owner/repo (built "));
+    assert!(status_human.contains("  + FAKE_VAR\n"));
+}
+
+#[test]
+fn status_fails_for_state_that_cannot_be_read() {
+    let fixtures = build_fixtures();
+
+    fixtures
+        .command(&["status", "--current-dir", "/"])
+        .env("ENVOLUNTARY_ENV_STATE", "ehs1:1:zstd:00000000:corrupt")
+        .assert()
+        .code(9);
+}
+
+#[test]
+fn shell_diff_previews_changes_without_saving_state() {
+    let fixtures = build_fixtures_with_profile_rc(
+        "export FAKE_VAR=true; export PATH=/nix/store/fake/bin:$PATH; export EDITOR=nvim;",
+    );
+    let config = fs::read_to_string(&fixtures.config_file).unwrap();
+    fs::write(
+        &fixtures.config_file,
+        format!("state_store = \"file\"\n{config}"),
+    )
+    .unwrap();
+    let runtime_dir = tempfile::tempdir().unwrap();
+
+    let diff = |format: &str| {
+        fixtures
+            .command(&["shell", "diff", "--current-dir", "/some/dir"])
+            .args(["--format", format])
+            .env("EDITOR", "vim")
+            .env("XDG_RUNTIME_DIR", runtime_dir.path())
+            .env("ENVOLUNTARY_SESSION_ID", "1234-5678")
+            .env_remove("FAKE_VAR")
+            .output()
+            .unwrap()
+    };
+
+    let output = diff("json");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let diff_json = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(diff_json["added"], serde_json::json!({"FAKE_VAR": "true"}));
+    assert_eq!(
+        diff_json["changed"]["EDITOR"],
+        serde_json::json!({"old": "vim", "new": "nvim"})
+    );
+    assert_eq!(diff_json["removed"], serde_json::json!({}));
+    assert_eq!(
+        diff_json["delimited"]["PATH"],
+        serde_json::json!({"added": ["/nix/store/fake/bin"], "removed": [], "reordered": false})
+    );
+
+    let output = diff("human");
+    assert!(output.status.success());
+    let diff_human = String::from_utf8_lossy(&output.stdout);
+    assert!(diff_human.contains("+ FAKE_VAR=true\n"));
+    assert!(diff_human.contains("~ EDITOR: vim -> nvim\n"));
+    assert!(diff_human.contains("~ PATH\n    + /nix/store/fake/bin\n"));
+    assert!(!diff_human.contains("ENVOLUNTARY_ENV_STATE"));
+
+    // NB: Neither a state file nor a snapshot gets written
+    assert!(!runtime_dir.path().join("envoluntary").exists());
+}
+
+#[test]
+fn exec_runs_command_with_environment_of_directory() {
+    let fixtures = build_fixtures();
+
+    let exec = |args: &[&str]| {
+        fixtures
+            .command(&[&["exec"], args].concat())
+            .args(["--", "sh", "-c", "echo \"${FAKE_VAR:-unset}\"; exit 3"])
+            .env_remove("FAKE_VAR")
+            .assert()
+    };
+
+    // NB: The command's exit code is kept
+    exec(&["--dir", "/some/dir"]).code(3).stdout("true\n");
+    exec(&["--dir", "/"]).code(3).stdout("unset\n");
+    exec(&["--dir", "/", "--flake-reference", "github:owner/repo"])
+        .code(3)
+        .stdout("true\n");
+}
+
+#[test]
+fn env_prints_environment_of_directory() {
+    let fixtures = build_fixtures();
+
+    let env = |args: &[&str]| {
+        let output = fixtures
+            .command(&[&["env"], args].concat())
+            .env("OTHER_VAR", "other")
+            .env_remove("FAKE_VAR")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // NB: The profile isn't in the cache until the first run builds it
+    let env_json =
+        serde_json::from_str::<serde_json::Value>(&env(&["--dir", "/some/dir"])).unwrap();
+    assert_eq!(env_json["dir"], "/some/dir");
+    assert_eq!(
+        env_json["matched_entries"][0]["flake_reference"],
+        "github:owner/repo"
+    );
+    assert_eq!(env_json["fresh"], false);
+    assert_eq!(env_json["env"], serde_json::json!({"FAKE_VAR": "true"}));
+
+    let env_json =
+        serde_json::from_str::<serde_json::Value>(&env(&["--dir", "/some/dir"])).unwrap();
+    assert_eq!(env_json["fresh"], true);
+    assert_eq!(env_json["profiles"][0]["stale"], false);
+
+    let env_json =
+        serde_json::from_str::<serde_json::Value>(&env(&["--dir", "/some/dir", "--full"])).unwrap();
+    assert_eq!(env_json["env"]["FAKE_VAR"], "true");
+    assert_eq!(env_json["env"]["OTHER_VAR"], "other");
+    assert!(env_json["env"].get("ENVOLUNTARY_ENV_STATE").is_none());
+
+    for format in ["dotenv", "systemd", "docker", "github-actions"] {
+        assert_eq!(
+            env(&["--dir", "/some/dir", "--format", format]),
+            "FAKE_VAR=true\n"
+        );
+    }
+
+    let env_json = serde_json::from_str::<serde_json::Value>(&env(&["--dir", "/"])).unwrap();
+    assert_eq!(env_json["matched_entries"], serde_json::json!([]));
+    assert_eq!(env_json["fresh"], true);
+    assert_eq!(env_json["env"], serde_json::json!({}));
+}
+
+#[test]
+fn env_warns_about_vars_the_format_cant_hold() {
+    let fixtures = build_fixtures_with_profile_rc(
+        r#"export FAKE_VAR=true; export MULTI_LINE="$(printf "a\nb")"; export NOT_UTF8="$(printf "\xff")";"#,
+    );
+
+    let env = |format: &str| {
+        fixtures
+            .command(&["env", "--dir", "/some/dir", "--format", format])
+            .assert()
+            .success()
+    };
+
+    // NB: Docker takes values as they are, so only the multi-line one gets skipped
+    env("docker")
+        .stdout(predicate::function(|stdout: &[u8]| {
+            let stdout = String::from_utf8_lossy(stdout);
+            stdout.contains("FAKE_VAR=true\n") && !stdout.contains("MULTI_LINE")
+        }))
+        .stderr(predicate::str::contains(
+            "envoluntary: warning: skipped variables the docker format can't hold: MULTI_LINE",
+        ));
+    env("systemd")
+        .stdout(predicate::str::contains("MULTI_LINE=\"a\\nb\"\n"))
+        .stdout(predicate::str::contains("NOT_UTF8").not())
+        .stderr(predicate::str::contains(
+            "envoluntary: warning: skipped variables the systemd format can't hold: NOT_UTF8",
+        ));
+}
+
+#[test]
+fn shell_enter_starts_shell_with_environment_loaded() {
+    let fixtures = build_fixtures();
+    let home_dir = fixtures.work_dir.path();
+    let dir = home_dir.join("some/other/dir");
+    fs::create_dir_all(&dir).unwrap();
+
+    fixtures
+        .command(&["shell", "enter", "--shell", "bash"])
+        .args(["--dir", &dir.to_string_lossy()])
+        .env("HOME", home_dir)
+        .env("ENVOLUNTARY_SESSION_ID", "1234-5678")
+        .env_remove("FAKE_VAR")
+        .write_stdin(
+            "echo \"$FAKE_VAR $ENVOLUNTARY_SHELL $PWD ${ENVOLUNTARY_SESSION_ID:-none}\"; exit 4\n",
+        )
+        .assert()
+        .code(4)
+        .stdout(format!("true {0} {0} none\n", dir.display()));
+}
@@ -0,0 +1,415 @@
+use std::{
+    env,
+    ffi::OsString,
+    io::{Read, Write},
+};
+
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use bstr::{B, BString, ByteSlice};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{EnvVars, EnvVarsState, Error};
+
+/// Turns the state kept in an env state var into a value that can be put in the environment, and
+/// back.
+pub trait StateCodec {
+    fn encode(&self, state: &[u8]) -> Result<String, Error>;
+
+    fn decode(&self, value: &str) -> Result<Vec<u8>, Error>;
+
+    /// Encodes the state as JSON.
+    fn encode_json(&self, state: &impl Serialize) -> Result<String, Error>
+    where
+        Self: Sized,
+    {
+        let json = serde_json::to_vec(state).map_err(|e| Error::StateEncode(e.into()))?;
+        self.encode(&json)
+    }
+
+    /// Decodes state that was encoded with [`StateCodec::encode_json`].
+    fn decode_json<T: DeserializeOwned>(&self, value: &str) -> Result<T, Error>
+    where
+        Self: Sized,
+    {
+        let json = self.decode(value)?;
+        serde_json::from_slice(&json).map_err(|e| Error::StateDecode(e.into()))
+    }
+}
+
+/// How state gets compressed before it's encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// What direnv uses for `DIRENV_DIFF`.
+    Zlib,
+    #[default]
+    Zstd,
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zlib => "zlib",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "zlib" => Some(Self::Zlib),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::None => Ok(bytes.to_vec()),
+            Self::Zlib => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(bytes)
+                    .map_err(|e| Error::StateEncode(e.into()))?;
+                encoder.finish().map_err(|e| Error::StateEncode(e.into()))
+            }
+            Self::Zstd => Ok(ruzstd::encoding::compress_to_vec(
+                bytes,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+        }
+    }
+
+    fn decompress(self, mut bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decompressed = vec![];
+        match self {
+            Self::None => decompressed.extend_from_slice(bytes),
+            Self::Zlib => {
+                flate2::read::ZlibDecoder::new(bytes)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| Error::StateDecode(e.into()))?;
+            }
+            Self::Zstd => {
+                ruzstd::decoding::StreamingDecoder::new(&mut bytes)
+                    .map_err(|e| Error::StateDecode(e.into()))?
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| Error::StateDecode(e.into()))?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Which base64 alphabet encoded state is written with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Base64Alphabet {
+    #[default]
+    Standard,
+    /// URL-safe and unpadded, which is what direnv uses for `DIRENV_DIFF`.
+    UrlSafeNoPad,
+}
+
+impl Base64Alphabet {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Self::Standard => BASE64_STANDARD.encode(bytes),
+            Self::UrlSafeNoPad => BASE64_URL_SAFE_NO_PAD.encode(bytes),
+        }
+    }
+
+    fn decode(self, value: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Standard => BASE64_STANDARD.decode(value),
+            Self::UrlSafeNoPad => BASE64_URL_SAFE_NO_PAD.decode(value),
+        }
+        .map_err(|e| Error::StateDecode(e.into()))
+    }
+}
+
+/// Compresses state and encodes it as base64, without any header.
+///
+/// Nothing checks that the value decodes to the state that was encoded, so this is mostly useful
+/// for reading and writing formats other tools define, e.g. direnv's `DIRENV_DIFF`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlainStateCodec {
+    compression: Compression,
+    alphabet: Base64Alphabet,
+}
+
+impl PlainStateCodec {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            alphabet: Base64Alphabet::default(),
+        }
+    }
+
+    pub fn with_alphabet(mut self, alphabet: Base64Alphabet) -> Self {
+        self.alphabet = alphabet;
+        self
+    }
+}
+
+impl StateCodec for PlainStateCodec {
+    fn encode(&self, state: &[u8]) -> Result<String, Error> {
+        Ok(self.alphabet.encode(&self.compression.compress(state)?))
+    }
+
+    fn decode(&self, value: &str) -> Result<Vec<u8>, Error> {
+        self.compression.decompress(&self.alphabet.decode(value)?)
+    }
+}
+
+/// Starts every value encoded by [`VersionedStateCodec`], followed by the version of the header.
+const VERSIONED_STATE_MAGIC: &str = "ehs";
+
+/// Bumped whenever the header written by [`VersionedStateCodec`] changes.
+const VERSIONED_STATE_HEADER_VERSION: u32 = 1;
+
+/// Separates the fields of the header written by [`VersionedStateCodec`].
+const VERSIONED_STATE_SEPARATOR: char = ':';
+
+/// Writes state with a header recording the version of the state, how it was compressed and a
+/// checksum, so that values written in another format, or that were truncated or changed, are
+/// rejected instead of being decoded into the wrong state.
+///
+/// Encoded values look like `ehs1:<version>:<compression>:<crc32>:<base64>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VersionedStateCodec {
+    version: u32,
+    compression: Compression,
+}
+
+impl VersionedStateCodec {
+    /// The version should be bumped whenever the state's format changes, so that state written by
+    /// an older version fails to decode with [`Error::StateVersionMismatch`].
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            compression: Compression::default(),
+        }
+    }
+
+    /// Sets how state gets compressed when it's encoded. Values are decoded with whichever
+    /// compression their header records.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Whether the value starts with the header this codec writes, regardless of its version.
+    pub fn has_header(value: &str) -> bool {
+        value
+            .strip_prefix(VERSIONED_STATE_MAGIC)
+            .and_then(|value| value.split_once(VERSIONED_STATE_SEPARATOR))
+            .is_some_and(|(header_version, _)| header_version.parse::<u32>().is_ok())
+    }
+}
+
+impl StateCodec for VersionedStateCodec {
+    fn encode(&self, state: &[u8]) -> Result<String, Error> {
+        let compressed = self.compression.compress(state)?;
+        let separator = VERSIONED_STATE_SEPARATOR;
+        Ok(format!(
+            "{VERSIONED_STATE_MAGIC}{VERSIONED_STATE_HEADER_VERSION}{separator}{}{separator}{}{separator}{:08x}{separator}{}",
+            self.version,
+            self.compression.name(),
+            checksum(state),
+            Base64Alphabet::Standard.encode(&compressed),
+        ))
+    }
+
+    fn decode(&self, value: &str) -> Result<Vec<u8>, Error> {
+        let mut fields = value
+            .strip_prefix(VERSIONED_STATE_MAGIC)
+            .ok_or_else(|| Error::StateDecode("missing header".into()))?
+            .splitn(5, VERSIONED_STATE_SEPARATOR);
+        let mut next_field = |name: &str| {
+            fields
+                .next()
+                .ok_or_else(|| Error::StateDecode(format!("missing {name} in header").into()))
+        };
+        let header_version = next_field("header version")?;
+        if header_version.parse() != Ok(VERSIONED_STATE_HEADER_VERSION) {
+            return Err(Error::StateDecode(
+                format!("unsupported header version `{header_version}`").into(),
+            ));
+        }
+        let version = next_field("version")?;
+        let version = version
+            .parse::<u32>()
+            .map_err(|_| Error::StateDecode(format!("invalid version `{version}`").into()))?;
+        if version != self.version {
+            return Err(Error::StateVersionMismatch {
+                found: version,
+                expected: self.version,
+            });
+        }
+        let compression = next_field("compression")?;
+        let compression = Compression::from_name(compression).ok_or_else(|| {
+            Error::StateDecode(format!("unknown compression `{compression}`").into())
+        })?;
+        let expected_checksum = next_field("checksum")?;
+        let payload = next_field("payload")?;
+
+        let state = compression.decompress(&Base64Alphabet::Standard.decode(payload)?)?;
+        if format!("{:08x}", checksum(&state)) != expected_checksum {
+            return Err(Error::StateDecode("checksum mismatch".into()));
+        }
+        Ok(state)
+    }
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// Starts the value of a chunked env var, followed by how many chunks there are.
+const CHUNKED_ENV_VAR_MARKER: &str = "chunked:";
+
+/// Linux limits each variable to 128 KiB, so this leaves plenty of room for the name.
+const DEFAULT_MAX_CHUNK_LEN: usize = 64 * 1024;
+
+/// Far more chunks than any state needs, so that a bogus count can't have a value read from
+/// millions of variables.
+const MAX_CHUNK_COUNT: usize = 1024;
+
+/// An env var whose value gets split across several variables once it's too long for one.
+///
+/// Values that fit are stored as is. Longer values are stored in `<KEY>_0`, `<KEY>_1` and so on,
+/// with `<KEY>` set to `chunked:<count>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkedEnvVar {
+    key: BString,
+    max_chunk_len: usize,
+}
+
+impl ChunkedEnvVar {
+    pub fn new(key: impl Into<BString>) -> Self {
+        Self {
+            key: key.into(),
+            max_chunk_len: DEFAULT_MAX_CHUNK_LEN,
+        }
+    }
+
+    /// Sets how long the value of each variable can get before it's split.
+    pub fn with_max_chunk_len(mut self, max_chunk_len: usize) -> Self {
+        self.max_chunk_len = max_chunk_len.max(1);
+        self
+    }
+
+    /// Variables that store the value, and unset any chunks of a previous, longer value.
+    pub fn set(&self, value: impl AsRef<[u8]>, current_env_vars: &EnvVars) -> EnvVarsState {
+        let value = value.as_ref();
+        let mut env_vars_state = self.unset(current_env_vars);
+        if value.len() <= self.max_chunk_len {
+            env_vars_state.insert(self.key.clone(), Some(BString::from(value)));
+            return env_vars_state;
+        }
+        let chunks = value.chunks(self.max_chunk_len).collect::<Vec<_>>();
+        env_vars_state.insert(
+            self.key.clone(),
+            Some(BString::from(format!(
+                "{CHUNKED_ENV_VAR_MARKER}{}",
+                chunks.len()
+            ))),
+        );
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            env_vars_state.insert(self.chunk_key(index), Some(BString::from(chunk)));
+        }
+        env_vars_state
+    }
+
+    /// Variables that unset the value, along with all of its chunks.
+    ///
+    /// Only chunks that are actually set get unset, so that a bogus count doesn't get out of hand.
+    pub fn unset(&self, current_env_vars: &EnvVars) -> EnvVarsState {
+        let mut env_vars_state = EnvVarsState::new();
+        env_vars_state.insert(self.key.clone(), None);
+        let chunk_count = current_env_vars
+            .get(&self.key)
+            .and_then(|value| parse_chunk_count(value))
+            .unwrap_or(0);
+        for key in current_env_vars.keys() {
+            if self
+                .chunk_index(key)
+                .is_some_and(|index| index < chunk_count)
+            {
+                env_vars_state.insert(key.clone(), None);
+            }
+        }
+        env_vars_state
+    }
+
+    /// Reads the value, joining its chunks back together.
+    ///
+    /// Returns `None` when the variable isn't set, and fails with [`Error::StateDecode`] when
+    /// any of its chunks are missing, or there are more than are ever written.
+    pub fn get(
+        &self,
+        get_env_var: impl Fn(&[u8]) -> Option<BString>,
+    ) -> Result<Option<BString>, Error> {
+        let Some(value) = get_env_var(&self.key) else {
+            return Ok(None);
+        };
+        let Some(chunk_count) = parse_chunk_count(&value) else {
+            return Ok(Some(value));
+        };
+        if chunk_count > MAX_CHUNK_COUNT {
+            return Err(Error::StateDecode(
+                format!(
+                    "`{}` has {chunk_count} chunks, at most {MAX_CHUNK_COUNT} are supported",
+                    self.key
+                )
+                .into(),
+            ));
+        }
+        let chunks = (0..chunk_count)
+            .map(|index| {
+                let chunk_key = self.chunk_key(index);
+                get_env_var(&chunk_key).ok_or_else(|| {
+                    Error::StateDecode(format!("chunk `{chunk_key}` is missing").into())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(bstr::concat(chunks).into()))
+    }
+
+    /// Reads the value from the environment of the current process.
+    pub fn get_from_current_process(&self) -> Result<Option<OsString>, Error> {
+        let value = self.get(|key| {
+            env::var_os(key.to_os_str_lossy()).map(|value| value.into_encoded_bytes().into())
+        })?;
+        Ok(value.map(|value| value.to_os_str_lossy().into_owned()))
+    }
+
+    fn chunk_key(&self, index: usize) -> BString {
+        bstr::concat([self.key.as_bytes(), B("_"), index.to_string().as_bytes()]).into()
+    }
+
+    fn chunk_index(&self, key: &[u8]) -> Option<usize> {
+        key.strip_prefix(self.key.as_bytes())?
+            .strip_prefix(B("_"))?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|&index| self.chunk_key(index) == key)
+    }
+}
+
+fn parse_chunk_count(value: &[u8]) -> Option<usize> {
+    value
+        .strip_prefix(CHUNKED_ENV_VAR_MARKER.as_bytes())?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,53 @@
+use std::{io, path::PathBuf, process::ExitStatus};
+
+use bstr::BString;
+
+/// Everything that can go wrong while capturing an environment or reading the state var.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// `bash` isn't installed, or isn't on `PATH`.
+    #[error("`bash` could not be found")]
+    BashNotFound,
+
+    /// Sourcing the script in bash exited unsuccessfully.
+    #[error("Bash command to retrieve env vars failed:\nprocess exited unsuccessfully: {status}")]
+    BashFailed {
+        status: ExitStatus,
+        /// What the source printed to stdout and stderr, interleaved.
+        output: BString,
+    },
+
+    /// Sourcing the script in bash didn't finish before the deadline.
+    #[error("Bash command to retrieve env vars timed out")]
+    Timeout,
+
+    /// Sourcing the script in bash was stopped because it was cancelled.
+    #[error("Bash command to retrieve env vars was cancelled")]
+    Cancelled,
+
+    /// The env state var couldn't be decoded, e.g. because it was truncated or written in another
+    /// format.
+    #[error("Env state var could not be decoded")]
+    StateDecode(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The env state var was written for another version of the state.
+    #[error("Env state var has version {found}, expected version {expected}")]
+    StateVersionMismatch { found: u32, expected: u32 },
+
+    #[error("Env state var could not be encoded")]
+    StateEncode(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The env state var points at a state file that was removed, e.g. because the runtime dir
+    /// was cleaned up.
+    #[error("Env state file `{path}` could not be found")]
+    StateFileNotFound { path: PathBuf },
+
+    /// Session ids end up in file names, so they're limited to ASCII letters, digits, `-` and
+    /// `_`.
+    #[error("Session id `{session_id}` is invalid")]
+    InvalidSessionId { session_id: String },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
@@ -1,30 +1,61 @@
+pub mod codec;
+mod error;
 pub mod shells;
 pub mod state;
+pub mod store;
 
 use std::{
     collections::HashSet,
-    env, fs, num,
+    env, fs,
+    io::{self, Write},
+    num,
     ops::{Deref, DerefMut},
-    path::PathBuf,
-    process::ExitStatus,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
+use base64::{Engine, prelude::BASE64_STANDARD};
 use bstr::{B, BString, ByteSlice};
 use duct::cmd;
 use indexmap::{IndexMap, IndexSet, map::IntoIter};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use regex::bytes::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use shell_quote::Bash;
 
-type EnvVarsInner = IndexMap<String, String>;
+pub use crate::error::Error;
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+type EnvVarsInner = IndexMap<BString, BString>;
+
+/// Environment variables, stored as bytes so that values which aren't valid UTF-8 survive intact.
+///
+/// Serializes to a map of strings. Values that aren't valid UTF-8 serialize to arrays of bytes
+/// instead, and names that aren't serialize to their base64 encoding, prefixed with `=base64:`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct EnvVars(EnvVarsInner);
 
 impl EnvVars {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Sets, or unsets, each variable in the state.
+    pub fn apply(&mut self, env_vars_state: &EnvVarsState) {
+        for (key, value) in env_vars_state.iter() {
+            match value {
+                Some(value) => {
+                    self.insert(key.clone(), value.clone());
+                }
+                None => {
+                    self.shift_remove(key);
+                }
+            }
+        }
+    }
 }
 
 impl Deref for EnvVars {
@@ -41,17 +72,42 @@ impl DerefMut for EnvVars {
 }
 
 impl IntoIterator for EnvVars {
-    type Item = (String, String);
-    type IntoIter = EnvVarIntoIter<String, String>;
+    type Item = (BString, BString);
+    type IntoIter = EnvVarIntoIter<BString, BString>;
 
     fn into_iter(self) -> Self::IntoIter {
         EnvVarIntoIter(self.0.into_iter())
     }
 }
 
-impl FromIterator<(String, String)> for EnvVars {
-    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
-        EnvVars(EnvVarsInner::from_iter(iter))
+impl<K: Into<BString>, V: Into<BString>> FromIterator<(K, V)> for EnvVars {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        EnvVars(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl Serialize for EnvVars {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(key, value)| (EnvVarKeyRef(key), EnvVarValueRef(value))),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for EnvVars {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(
+            IndexMap::<EnvVarKey, EnvVarValue>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(key, value)| (key.0, BString::from(value)))
+                .collect(),
+        )
     }
 }
 
@@ -61,9 +117,12 @@ impl From<EnvVars> for EnvVarsState {
     }
 }
 
-type EnvVarsStateInner = IndexMap<String, Option<String>>;
+type EnvVarsStateInner = IndexMap<BString, Option<BString>>;
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// Environment variables to set, or to unset when the value is `None`.
+///
+/// Serializes the same way as [`EnvVars`], with unset variables serializing to `null`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct EnvVarsState(EnvVarsStateInner);
 
 impl EnvVarsState {
@@ -86,17 +145,103 @@ impl DerefMut for EnvVarsState {
 }
 
 impl IntoIterator for EnvVarsState {
-    type Item = (String, Option<String>);
-    type IntoIter = EnvVarIntoIter<String, Option<String>>;
+    type Item = (BString, Option<BString>);
+    type IntoIter = EnvVarIntoIter<BString, Option<BString>>;
 
     fn into_iter(self) -> Self::IntoIter {
         EnvVarIntoIter(self.0.into_iter())
     }
 }
 
-impl FromIterator<(String, Option<String>)> for EnvVarsState {
-    fn from_iter<I: IntoIterator<Item = (String, Option<String>)>>(iter: I) -> Self {
-        EnvVarsState(EnvVarsStateInner::from_iter(iter))
+impl<K: Into<BString>, V: Into<BString>> FromIterator<(K, Option<V>)> for EnvVarsState {
+    fn from_iter<I: IntoIterator<Item = (K, Option<V>)>>(iter: I) -> Self {
+        EnvVarsState(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.map(Into::into)))
+                .collect(),
+        )
+    }
+}
+
+impl Serialize for EnvVarsState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(key, value)| (EnvVarKeyRef(key), value.as_ref().map(EnvVarValueRef))),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for EnvVarsState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(
+            IndexMap::<EnvVarKey, Option<EnvVarValue>>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(key, value)| (key.0, value.map(BString::from)))
+                .collect(),
+        )
+    }
+}
+
+/// Prefixes the base64 encoding of names that aren't valid UTF-8. Names can't contain `=`, so it
+/// can't be confused with a valid one.
+const NON_UTF8_KEY_PREFIX: &str = "=base64:";
+
+struct EnvVarKeyRef<'a>(&'a BString);
+
+impl Serialize for EnvVarKeyRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Ok(key) => serializer.serialize_str(key),
+            Err(_) => serializer.serialize_str(&format!(
+                "{NON_UTF8_KEY_PREFIX}{}",
+                BASE64_STANDARD.encode(self.0)
+            )),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct EnvVarKey(BString);
+
+impl<'de> Deserialize<'de> for EnvVarKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        match key.strip_prefix(NON_UTF8_KEY_PREFIX) {
+            Some(encoded_key) => BASE64_STANDARD
+                .decode(encoded_key)
+                .map(|key| EnvVarKey(key.into()))
+                .map_err(de::Error::custom),
+            None => Ok(EnvVarKey(key.into())),
+        }
+    }
+}
+
+struct EnvVarValueRef<'a>(&'a BString);
+
+impl Serialize for EnvVarValueRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Ok(value) => serializer.serialize_str(value),
+            Err(_) => serializer.collect_seq(self.0.iter()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvVarValue {
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<EnvVarValue> for BString {
+    fn from(value: EnvVarValue) -> Self {
+        match value {
+            EnvVarValue::String(value) => value.into(),
+            EnvVarValue::Bytes(value) => value.into(),
+        }
     }
 }
 
@@ -137,8 +282,8 @@ pub fn get_old_env_vars_to_be_updated(old_env_vars: EnvVars, new_env_vars: &EnvV
 
 pub fn get_env_vars_reset(
     mut old_env_vars_that_were_updated: EnvVars,
-    new_env_vars: HashSet<String>,
-    env_state_var_key: String,
+    new_env_vars: HashSet<BString>,
+    env_state_var_key: impl Into<BString>,
 ) -> EnvVarsState {
     let mut env_vars_state = new_env_vars
         .into_iter()
@@ -147,12 +292,71 @@ pub fn get_env_vars_reset(
             acc.insert(key, value);
             acc
         });
-    env_vars_state.insert(env_state_var_key, None);
+    env_vars_state.insert(env_state_var_key.into(), None);
     env_vars_state
 }
 
+/// Works out how to undo the variables an environment set, without discarding changes made to them
+/// since.
+///
+/// Variables still holding the value in `env_vars_set` get reset to their value in
+/// `env_vars_reset`. Variables that were changed since keep their current value, except for
+/// delimited variables, which only have the entries the environment added removed. Variables
+/// missing from `env_vars_set` are always reset.
+pub fn get_env_vars_restore(
+    env_vars_reset: EnvVarsState,
+    env_vars_set: &EnvVars,
+    current_env_vars: &EnvVars,
+    delimited_env_vars: &DelimitedEnvVars,
+) -> EnvVarsState {
+    let mut env_vars_restore = EnvVarsState::new();
+    for (key, old_value) in env_vars_reset {
+        let Some(set_value) = env_vars_set.get(&key) else {
+            env_vars_restore.insert(key, old_value);
+            continue;
+        };
+        let current_value = current_env_vars.get(&key);
+        if current_value == Some(set_value) {
+            env_vars_restore.insert(key, old_value);
+            continue;
+        }
+        // NB: Variables that were unset, or that aren't delimited, were changed by hand
+        let (Some(current_value), Some(delimited_env_var)) = (
+            current_value,
+            key.to_str()
+                .ok()
+                .and_then(|key| delimited_env_vars.get(key)),
+        ) else {
+            continue;
+        };
+        let old_entries = old_value
+            .as_ref()
+            .map(|old_value| delimited_env_var.split(old_value).collect::<HashSet<_>>())
+            .unwrap_or_default();
+        let added_entries = delimited_env_var
+            .split(set_value)
+            .filter(|entry| !old_entries.contains(entry))
+            .collect::<HashSet<_>>();
+        let restored_entries = delimited_env_var
+            .split(current_value)
+            .filter(|entry| !added_entries.contains(entry))
+            .collect::<Vec<_>>();
+        let restored_value = if restored_entries.is_empty() && old_value.is_none() {
+            None
+        } else {
+            Some(bstr::join(&delimited_env_var.separator, restored_entries).into())
+        };
+        if restored_value.as_ref() != Some(current_value) {
+            env_vars_restore.insert(key, restored_value);
+        }
+    }
+    env_vars_restore
+}
+
 pub fn get_env_vars_from_current_process() -> EnvVars {
-    EnvVars(env::vars().collect::<EnvVarsInner>())
+    env::vars_os()
+        .map(|(key, value)| (key.into_encoded_bytes(), value.into_encoded_bytes()))
+        .collect()
 }
 
 pub enum BashSource {
@@ -176,87 +380,438 @@ impl BashSource {
 }
 
 pub(crate) trait SimplifiedExitOk {
-    fn simplified_exit_ok(&self) -> anyhow::Result<()>;
+    fn simplified_exit_ok(&self) -> Result<(), ExitStatus>;
 }
 
 impl SimplifiedExitOk for ExitStatus {
     /// Simplified implementation of <https://github.com/rust-lang/rust/issues/84908>
     // TODO: Remove this and use `exit_ok` when it's stabilized.
-    fn simplified_exit_ok(&self) -> anyhow::Result<()> {
+    fn simplified_exit_ok(&self) -> Result<(), ExitStatus> {
         match num::NonZero::try_from(self.code().unwrap_or(-1)) {
-            Ok(_) => Err(anyhow::format_err!(
-                "process exited unsuccessfully: {}",
-                &self
-            )),
+            Ok(_) => Err(*self),
             Err(_) => Ok(()),
         }
     }
 }
 
+/// Functions and aliases defined by sourcing a script, keyed by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShellDefinitions {
+    /// Function definitions, as printed by `declare -f`.
+    pub functions: IndexMap<String, String>,
+    /// Alias values, without the `alias name=` part.
+    pub aliases: IndexMap<String, String>,
+}
+
+impl ShellDefinitions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty() && self.aliases.is_empty()
+    }
+
+    /// Adds the other definitions, replacing any with the same name.
+    pub fn extend(&mut self, other: ShellDefinitions) {
+        self.functions.extend(other.functions);
+        self.aliases.extend(other.aliases);
+    }
+
+    /// State that removes every one of the definitions again.
+    pub fn reset(&self) -> ShellDefinitionsState {
+        ShellDefinitionsState {
+            functions: self
+                .functions
+                .keys()
+                .map(|name| (name.clone(), None))
+                .collect(),
+            aliases: self
+                .aliases
+                .keys()
+                .map(|name| (name.clone(), None))
+                .collect(),
+        }
+    }
+}
+
+/// Functions and aliases to define, or to remove when the value is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShellDefinitionsState {
+    pub functions: IndexMap<String, Option<String>>,
+    pub aliases: IndexMap<String, Option<String>>,
+}
+
+impl ShellDefinitionsState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty() && self.aliases.is_empty()
+    }
+}
+
+impl From<ShellDefinitions> for ShellDefinitionsState {
+    fn from(shell_definitions: ShellDefinitions) -> Self {
+        Self {
+            functions: shell_definitions
+                .functions
+                .into_iter()
+                .map(|(name, definition)| (name, Some(definition)))
+                .collect(),
+            aliases: shell_definitions
+                .aliases
+                .into_iter()
+                .map(|(name, value)| (name, Some(value)))
+                .collect(),
+        }
+    }
+}
+
+// NB: Each name and value is followed by a NUL byte, since neither can contain one
+const BASH_PRINT_SHELL_DEFINITIONS: &str = r#"
+    while IFS= read -r __env_hooks_name; do
+        printf '%s\0' "$__env_hooks_name";
+        declare -f -- "$__env_hooks_name";
+        printf '\0';
+    done < <(compgen -A function) > {{.FunctionsFile}};
+    for __env_hooks_name in "${!BASH_ALIASES[@]}"; do
+        printf '%s\0%s\0' "$__env_hooks_name" "${BASH_ALIASES[$__env_hooks_name]}";
+    done > {{.AliasesFile}};
+"#;
+
 pub fn get_env_vars_from_bash(
     source: impl AsRef<BashSource>,
     env_vars: Option<EnvVars>,
-) -> anyhow::Result<EnvVars> {
-    let bash_env_vars_file = tempfile::NamedTempFile::new()?;
+) -> Result<EnvVars, Error> {
+    let (env_vars, _) = get_env_vars_and_shell_definitions_from_bash(source, env_vars)?;
+    Ok(env_vars)
+}
+
+/// Like [`get_env_vars_from_bash`], but also captures the functions and aliases the source defines.
+pub fn get_env_vars_and_shell_definitions_from_bash(
+    source: impl AsRef<BashSource>,
+    env_vars: Option<EnvVars>,
+) -> Result<(EnvVars, ShellDefinitions), Error> {
+    let bash_env = get_bash_env(source, env_vars)?;
+    io::stderr().write_all(&bash_env.output)?;
+    Ok((bash_env.env_vars, bash_env.shell_definitions))
+}
+
+/// Everything captured from sourcing a script in bash.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BashEnv {
+    pub env_vars: EnvVars,
+    pub shell_definitions: ShellDefinitions,
+    /// What the source printed to stdout and stderr, interleaved.
+    pub output: BString,
+}
 
+/// Like [`get_env_vars_and_shell_definitions_from_bash`], but captures what the source prints
+/// instead of forwarding it to stderr.
+///
+/// When sourcing fails, the output is still forwarded to stderr, since it's usually the only hint
+/// as to why.
+pub fn get_bash_env(
+    source: impl AsRef<BashSource>,
+    env_vars: Option<EnvVars>,
+) -> Result<BashEnv, Error> {
+    get_bash_env_within_limits(source, env_vars, BashLimits::default())
+}
+
+/// How long to wait between checks on whether bash should be stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long bash gets to exit after `SIGTERM`, before it's sent `SIGKILL`.
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// When sourcing a script in bash gets stopped before it finishes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BashLimits<'a> {
+    /// The time by which sourcing has to have finished.
+    pub deadline: Option<Instant>,
+    /// Set, e.g. by a signal handler, once sourcing should be cancelled.
+    pub cancelled: Option<&'a AtomicBool>,
+}
+
+/// Like [`get_bash_env`], but stops bash, along with every process it spawned, once the limits
+/// are reached.
+pub fn get_bash_env_within_limits(
+    source: impl AsRef<BashSource>,
+    env_vars: Option<EnvVars>,
+    limits: BashLimits,
+) -> Result<BashEnv, Error> {
+    get_bash_env_in(source, env_vars, None, limits)
+}
+
+/// Like [`get_bash_env_within_limits`], but sources the script with `current_dir` as the working
+/// directory, e.g. for scripts that read `$PWD`.
+pub fn get_bash_env_in_dir(
+    source: impl AsRef<BashSource>,
+    env_vars: Option<EnvVars>,
+    current_dir: &Path,
+    limits: BashLimits,
+) -> Result<BashEnv, Error> {
+    get_bash_env_in(source, env_vars, Some(current_dir), limits)
+}
+
+fn get_bash_env_in(
+    source: impl AsRef<BashSource>,
+    env_vars: Option<EnvVars>,
+    current_dir: Option<&Path>,
+    limits: BashLimits,
+) -> Result<BashEnv, Error> {
+    let bash_env_vars_file = tempfile::NamedTempFile::new()?;
+    let bash_functions_file = tempfile::NamedTempFile::new()?;
+    let bash_aliases_file = tempfile::NamedTempFile::new()?;
+
+    let print_shell_definitions = BString::from(BASH_PRINT_SHELL_DEFINITIONS)
+        .replace(
+            "{{.FunctionsFile}}",
+            Bash::quote_vec(bash_functions_file.path()),
+        )
+        .replace(
+            "{{.AliasesFile}}",
+            Bash::quote_vec(bash_aliases_file.path()),
+        );
     let command_string = bstr::join(
         " ",
         [
             &source.as_ref().to_command_string(),
             B("&& env -0 >"),
             &Bash::quote_vec(bash_env_vars_file.path()),
+            B("&&"),
+            &print_shell_definitions,
         ],
     );
-    let handle = cmd!("bash", "-c", command_string.to_os_str()?)
-        .full_env(env_vars.unwrap_or_default())
-        .stdout_to_stderr()
-        .start()?;
-    let output = handle.wait()?;
-    output
-        .status
-        .simplified_exit_ok()
-        .map_err(|e| anyhow::format_err!("Bash command to retrieve env vars failed:\n{e}"))?;
-
-    let bash_env_vars_string = fs::read_to_string(bash_env_vars_file.path())?;
-
-    let bash_env_vars = EnvVars(
-        bash_env_vars_string
-            .split('\0')
-            .filter_map(|env_var| env_var.split_once('='))
-            .map(|(key, value)| (String::from(key), String::from(value)))
-            .collect::<EnvVarsInner>(),
-    );
+    let mut expression = cmd!("bash", "-c", command_string.to_os_str_lossy());
+    if let Some(current_dir) = current_dir {
+        expression = expression.dir(current_dir);
+    }
+    let handle = expression
+        .full_env(
+            env_vars
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, value)| {
+                    (
+                        key.to_os_str_lossy().into_owned(),
+                        value.to_os_str_lossy().into_owned(),
+                    )
+                }),
+        )
+        // NB: Bash runs in its own process group, which would be stopped if it read the terminal
+        .stdin_null()
+        .before_spawn(|command| {
+            command.process_group(0);
+            Ok(())
+        })
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .start()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::BashNotFound,
+            _ => Error::Io(e),
+        })?;
+    let output = wait_within_limits(&handle, limits)?;
+    if let Err(status) = output.status.simplified_exit_ok() {
+        io::stderr().write_all(&output.stdout)?;
+        return Err(Error::BashFailed {
+            status,
+            output: output.stdout.into(),
+        });
+    }
+
+    let bash_env_vars_bytes = fs::read(bash_env_vars_file.path())?;
+
+    let bash_env_vars = bash_env_vars_bytes
+        .split_str("\0")
+        .filter_map(|env_var| env_var.split_once_str("="))
+        .map(|(key, value)| (key.as_bstr(), value.as_bstr()))
+        .collect::<EnvVars>();
+
+    let shell_definitions = ShellDefinitions {
+        functions: read_nul_separated_pairs(bash_functions_file.path())?,
+        aliases: read_nul_separated_pairs(bash_aliases_file.path())?,
+    };
+
+    Ok(BashEnv {
+        env_vars: bash_env_vars,
+        shell_definitions,
+        output: output.stdout.into(),
+    })
+}
+
+fn wait_within_limits(handle: &duct::Handle, limits: BashLimits) -> Result<Output, Error> {
+    loop {
+        if let Some(output) = handle.try_wait()? {
+            return Ok(output.clone());
+        }
+        let stop_error = if limits
+            .cancelled
+            .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+        {
+            Error::Cancelled
+        } else if limits
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Error::Timeout
+        } else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        // NB: Bash was spawned as the leader of its own process group, so its ID is the group's
+        if let Some(process_group_id) = handle.pids().first() {
+            stop_process_group(handle, *process_group_id as libc::pid_t)?;
+        }
+        return Err(stop_error);
+    }
+}
+
+fn stop_process_group(handle: &duct::Handle, process_group_id: libc::pid_t) -> io::Result<()> {
+    // SAFETY: `killpg` doesn't access any memory, and only signals bash's process group
+    unsafe { libc::killpg(process_group_id, libc::SIGTERM) };
+    let grace_period_end = Instant::now() + TERMINATION_GRACE_PERIOD;
+    while handle.try_wait()?.is_none() && Instant::now() < grace_period_end {
+        thread::sleep(POLL_INTERVAL);
+    }
+    // NB: Processes bash spawned may still be running even once bash itself has exited
+    // SAFETY: See above
+    unsafe { libc::killpg(process_group_id, libc::SIGKILL) };
+    handle.wait()?;
+    Ok(())
+}
 
-    Ok(bash_env_vars)
+fn read_nul_separated_pairs(path: &Path) -> io::Result<IndexMap<String, String>> {
+    let bytes = fs::read(path)?;
+    let mut parts = bytes.split_str("\0");
+    let mut pairs = IndexMap::new();
+    while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+        pairs.insert(
+            name.to_str_lossy().into_owned(),
+            value.to_str_lossy().into_owned(),
+        );
+    }
+    Ok(pairs)
 }
 
 pub fn merge_delimited_env_var(
-    env_var: &str,
+    env_var: impl AsRef<[u8]>,
     split_delimiter: char,
     join_delimiter: char,
     old_env_vars: &EnvVars,
     new_env_vars: &mut EnvVars,
 ) {
+    let env_var = env_var.as_ref();
     if let (Some(old_value), Some(new_value)) =
         (old_env_vars.get(env_var), new_env_vars.get_mut(env_var))
     {
-        *new_value = merge_delimited_values(split_delimiter, join_delimiter, old_value, new_value);
+        *new_value = merge_delimited_values(split_delimiter, join_delimiter, old_value, &new_value);
     }
 }
 
 pub fn merge_delimited_values(
     split_delimiter: char,
     join_delimiter: char,
-    old_value: &str,
-    new_value: &str,
-) -> String {
-    new_value
-        .split(split_delimiter)
-        .chain(old_value.split(split_delimiter))
-        .collect::<IndexSet<_>>()
-        .into_iter()
-        .collect::<Vec<_>>()
-        .join(&join_delimiter.to_string())
+    old_value: impl AsRef<[u8]>,
+    new_value: impl AsRef<[u8]>,
+) -> BString {
+    let split_delimiter = split_delimiter.to_string();
+    let join_delimiter = join_delimiter.to_string();
+    bstr::join(
+        join_delimiter,
+        new_value
+            .as_ref()
+            .split_str(&split_delimiter)
+            .chain(old_value.as_ref().split_str(&split_delimiter))
+            .collect::<IndexSet<_>>(),
+    )
+    .into()
+}
+
+/// How the value a new environment sets for a delimited variable is combined with the value that
+/// was already set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// New entries go before the old entries.
+    Prepend,
+    /// New entries go after the old entries.
+    Append,
+    /// The new value is used as is.
+    Replace,
+    /// New entries go before the old entries, keeping only the first occurrence of each entry.
+    #[default]
+    Dedupe,
+}
+
+/// A variable whose value is a list of entries joined by a separator, e.g. `PATH`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelimitedEnvVar {
+    pub separator: String,
+    pub strategy: MergeStrategy,
+}
+
+impl Default for DelimitedEnvVar {
+    fn default() -> Self {
+        Self {
+            separator: String::from(":"),
+            strategy: MergeStrategy::default(),
+        }
+    }
+}
+
+impl DelimitedEnvVar {
+    pub fn merge(&self, old_value: impl AsRef<[u8]>, new_value: impl AsRef<[u8]>) -> BString {
+        let (old_value, new_value) = (old_value.as_ref(), new_value.as_ref());
+        match self.strategy {
+            MergeStrategy::Prepend | MergeStrategy::Append if old_value.is_empty() => {
+                new_value.into()
+            }
+            MergeStrategy::Prepend | MergeStrategy::Append if new_value.is_empty() => {
+                old_value.into()
+            }
+            MergeStrategy::Prepend => bstr::join(&self.separator, [new_value, old_value]).into(),
+            MergeStrategy::Append => bstr::join(&self.separator, [old_value, new_value]).into(),
+            MergeStrategy::Replace => new_value.into(),
+            MergeStrategy::Dedupe => bstr::join(
+                &self.separator,
+                new_value
+                    .split_str(&self.separator)
+                    .chain(old_value.split_str(&self.separator))
+                    .collect::<IndexSet<_>>(),
+            )
+            .into(),
+        }
+    }
+
+    /// Splits the value into its entries.
+    pub fn split<'a>(&'a self, value: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        value.split_str(&self.separator)
+    }
+}
+
+/// Delimited variables keyed by name.
+pub type DelimitedEnvVars = IndexMap<String, DelimitedEnvVar>;
+
+/// Merges every delimited variable set by both the old and the new environment.
+pub fn merge_delimited_env_vars(
+    delimited_env_vars: &DelimitedEnvVars,
+    old_env_vars: &EnvVars,
+    new_env_vars: &mut EnvVars,
+) {
+    for (env_var, delimited_env_var) in delimited_env_vars {
+        if let (Some(old_value), Some(new_value)) = (
+            old_env_vars.get(env_var.as_bytes()),
+            new_env_vars.get_mut(env_var.as_bytes()),
+        ) {
+            *new_value = delimited_env_var.merge(old_value, &new_value);
+        }
+    }
 }
 
 const IGNORED_ENV_VAR_PREFIXES: &[&str] = &["__fish", "BASH_FUNC_"];
@@ -281,20 +836,79 @@ static IGNORED_ENV_VAR_KEYS: Lazy<HashSet<&str>> = Lazy::new(|| {
     ])
 });
 
-pub fn ignored_env_var_key(env_var_key: &str) -> bool {
+pub fn ignored_env_var_key(env_var_key: impl AsRef<[u8]>) -> bool {
+    let env_var_key = env_var_key.as_ref();
     for ignored_env_var_prefix in IGNORED_ENV_VAR_PREFIXES {
-        if env_var_key.starts_with(ignored_env_var_prefix) {
+        if env_var_key.starts_with(ignored_env_var_prefix.as_bytes()) {
             return true;
         }
     }
-    IGNORED_ENV_VAR_KEYS.contains(env_var_key)
+    env_var_key
+        .to_str()
+        .is_ok_and(|env_var_key| IGNORED_ENV_VAR_KEYS.contains(env_var_key))
+}
+
+/// Variables to leave out of an environment on top of the ones that are always ignored.
+///
+/// Patterns are regexes matched against the whole variable name.
+#[derive(Debug, Clone, Default)]
+pub struct EnvVarsFilter {
+    ignored: Vec<Regex>,
+    allowed: Option<Vec<Regex>>,
+}
+
+impl EnvVarsFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignores variables matching any of the patterns.
+    pub fn with_ignored(
+        mut self,
+        patterns: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Self, regex::Error> {
+        self.ignored = compile_env_var_key_patterns(patterns)?;
+        Ok(self)
+    }
+
+    /// Ignores variables that don't match any of the patterns.
+    pub fn with_allowed(
+        mut self,
+        patterns: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Self, regex::Error> {
+        self.allowed = Some(compile_env_var_key_patterns(patterns)?);
+        Ok(self)
+    }
+
+    pub fn is_ignored(&self, env_var_key: impl AsRef<[u8]>) -> bool {
+        let env_var_key = env_var_key.as_ref();
+        ignored_env_var_key(env_var_key)
+            || self
+                .ignored
+                .iter()
+                .any(|pattern| pattern.is_match(env_var_key))
+            || self
+                .allowed
+                .as_ref()
+                .is_some_and(|allowed| !allowed.iter().any(|pattern| pattern.is_match(env_var_key)))
+    }
+}
+
+fn compile_env_var_key_patterns(
+    patterns: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<Vec<Regex>, regex::Error> {
+    patterns
+        .into_iter()
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern.as_ref())))
+        .collect()
 }
 
+/// Removes the variables that are always ignored, e.g. `HOME` or `SHLVL`.
 pub fn remove_ignored_env_vars(env_vars: &mut EnvVars) {
-    let env_var_keys = env_vars.keys().cloned().collect::<Vec<_>>();
-    env_var_keys.into_iter().for_each(|env_var_key| {
-        if ignored_env_var_key(&env_var_key) {
-            env_vars.shift_remove(&env_var_key);
-        }
-    });
+    remove_filtered_env_vars(env_vars, &EnvVarsFilter::default());
+}
+
+/// Like [`remove_ignored_env_vars`], but also removes the variables the filter ignores.
+pub fn remove_filtered_env_vars(env_vars: &mut EnvVars, env_vars_filter: &EnvVarsFilter) {
+    env_vars.retain(|env_var_key, _| !env_vars_filter.is_ignored(env_var_key));
 }
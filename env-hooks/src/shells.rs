@@ -1,5 +1,9 @@
 pub mod bash;
+pub mod docker;
+pub mod dotenv;
 pub mod fish;
+pub mod github_actions;
 pub mod json;
 pub mod nushell;
+pub mod systemd;
 pub mod zsh;
@@ -1,9 +1,7 @@
-use std::collections::HashSet;
-
-use bstr::{B, BString, ByteSlice};
+use bstr::{B, BStr, BString, ByteSlice};
 use shell_quote::Bash;
 
-use crate::EnvVarsState;
+use crate::{DelimitedEnvVars, EnvVarsState, ShellDefinitionsState};
 
 const BASH_HOOK: &str = r#"
     _{{.HookPrefix}}_hook() {
@@ -23,6 +21,31 @@ const BASH_HOOK: &str = r#"
     fi
 "#;
 
+/// Exports an id that's new for every shell the hook is evaluated in, even when a variable of the
+/// same name was inherited from a parent shell.
+const BASH_SESSION_ID: &str = r#"
+    export {{.SessionIdVarKey}}="$$-$RANDOM$RANDOM";
+"#;
+
+/// Defines a function that runs the program as is, except for the subcommands whose output gets
+/// evaluated, which are passed `--shell bash` so they know what syntax to print.
+const BASH_WRAPPER: &str = r#"
+    {{.FunctionName}}() {
+        case "${1:-}" in
+            {{.ExportSubcommands}})
+                local vars;
+                vars="$({{.Program}} "$@" --shell bash)" || return;
+                trap -- '' SIGINT;
+                eval "$vars";
+                trap - SIGINT;
+                ;;
+            *)
+                {{.Program}} "$@";
+                ;;
+        esac;
+    };
+"#;
+
 pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) -> BString {
     BString::from(BASH_HOOK)
         .replace("{{.HookPrefix}}", hook_prefix)
@@ -30,33 +53,95 @@ pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) ->
         .into()
 }
 
+pub fn session_id(session_id_var_key: impl AsRef<[u8]>) -> BString {
+    BString::from(BASH_SESSION_ID)
+        .replace("{{.SessionIdVarKey}}", session_id_var_key)
+        .into()
+}
+
+pub fn wrapper(
+    function_name: impl AsRef<[u8]>,
+    program: impl AsRef<[u8]>,
+    export_subcommands: &[&str],
+) -> BString {
+    BString::from(BASH_WRAPPER)
+        .replace("{{.FunctionName}}", function_name)
+        .replace("{{.Program}}", program)
+        .replace("{{.ExportSubcommands}}", export_subcommands.join("|"))
+        .into()
+}
+
 pub fn export(
     env_vars_state: EnvVarsState,
-    _semicolon_delimited_env_vars: Option<&HashSet<String>>,
+    _delimited_env_vars: Option<&DelimitedEnvVars>,
 ) -> BString {
     let exports = env_vars_state
         .iter()
         .map(|(key, state)| {
             if let Some(value) = state {
-                export_var(key, value)
+                export_var(key.as_bstr(), value.as_bstr())
             } else {
-                unset_var(key)
+                unset_var(key.as_bstr())
             }
         })
         .collect::<Vec<_>>();
     bstr::join("\n", exports).into()
 }
 
-fn export_var(key: &str, value: &str) -> BString {
+fn export_var(key: &BStr, value: &BStr) -> BString {
     let script = bstr::join(" ", [B("export"), &Bash::quote_vec(key)]);
     let value = Bash::quote_vec(value);
     bstr::concat([&bstr::join("=", [script, value]), B(";")]).into()
 }
 
-fn unset_var(key: &str) -> BString {
+fn unset_var(key: &BStr) -> BString {
     bstr::concat([
         &bstr::join(" ", [B("unset"), &Bash::quote_vec(key)]),
         B(";"),
     ])
     .into()
 }
+
+pub fn export_shell_definitions(shell_definitions_state: &ShellDefinitionsState) -> BString {
+    let functions = shell_definitions_state
+        .functions
+        .iter()
+        .map(|(name, definition)| match definition {
+            // NB: Definitions are printed by bash itself, so they can be used as is
+            Some(definition) => BString::from(definition.trim_end()),
+            None => unset_function(name),
+        });
+    let aliases = shell_definitions_state
+        .aliases
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => set_alias(name, value),
+            None => unset_alias(name),
+        });
+    bstr::join("\n", functions.chain(aliases)).into()
+}
+
+fn unset_function(name: &str) -> BString {
+    bstr::concat([
+        &bstr::join(" ", [B("unset -f --"), &Bash::quote_vec(name)]),
+        B(";"),
+    ])
+    .into()
+}
+
+fn set_alias(name: &str, value: &str) -> BString {
+    let alias = bstr::concat([name.as_bytes(), B("="), value.as_bytes()]);
+    bstr::concat([
+        &bstr::join(" ", [B("alias --"), &Bash::quote_vec(&alias)]),
+        B(";"),
+    ])
+    .into()
+}
+
+fn unset_alias(name: &str) -> BString {
+    bstr::concat([
+        &bstr::join(" ", [B("unalias --"), &Bash::quote_vec(name)]),
+        B(" 2>/dev/null;"),
+    ])
+    .into()
+}
@@ -0,0 +1,36 @@
+use bstr::BString;
+
+use crate::EnvVars;
+
+/// Exports every variable as a `KEY=value` line, as read by `docker run --env-file`.
+///
+/// The format can't unset variables, so it takes the full environment rather than the changes to
+/// it. Docker takes everything after the `=` literally, so values aren't quoted. Variables that
+/// can't be written are left out, see [`skipped_keys`].
+pub fn export(env_vars: &EnvVars) -> BString {
+    let mut output = BString::default();
+    for (key, value) in env_vars.iter() {
+        if !is_exportable(value) {
+            continue;
+        }
+        output.extend_from_slice(key);
+        output.push(b'=');
+        output.extend_from_slice(value);
+        output.push(b'\n');
+    }
+    output
+}
+
+/// Keys of the variables [`export`] leaves out, i.e. the ones whose values hold newlines.
+pub fn skipped_keys(env_vars: &EnvVars) -> Vec<&BString> {
+    env_vars
+        .iter()
+        .filter(|(_, value)| !is_exportable(value))
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Values can't span lines.
+fn is_exportable(value: &[u8]) -> bool {
+    !value.contains(&b'\n')
+}
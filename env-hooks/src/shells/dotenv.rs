@@ -0,0 +1,41 @@
+use bstr::{BString, ByteSlice};
+
+use crate::EnvVars;
+
+/// Exports every variable as a `KEY=value` line, as read by most `.env` file parsers.
+///
+/// The format can't unset variables, so it takes the full environment rather than the changes to
+/// it. Values that aren't plain words are single quoted, so that they're taken literally, unless
+/// they hold single quotes or newlines, in which case they're double quoted and escaped.
+pub fn export(env_vars: &EnvVars) -> BString {
+    env_vars
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key.to_str_lossy(), quote(&value.to_str_lossy())))
+        .collect::<String>()
+        .into()
+}
+
+fn quote(value: &str) -> String {
+    let is_plain = value
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"_./:@%+,-".contains(&byte));
+    if is_plain {
+        return String::from(value);
+    }
+    if !value.contains(['\'', '\n']) {
+        return format!("'{value}'");
+    }
+    let mut quoted = String::from("\"");
+    for char in value.chars() {
+        match char {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '$' => quoted.push_str("\\$"),
+            '`' => quoted.push_str("\\`"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
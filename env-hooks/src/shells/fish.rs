@@ -1,9 +1,7 @@
-use std::collections::HashSet;
+use bstr::{B, BStr, BString, ByteSlice};
+use shell_quote::{Bash, Fish};
 
-use bstr::{B, BString, ByteSlice};
-use shell_quote::Fish;
-
-use crate::EnvVarsState;
+use crate::{DelimitedEnvVars, EnvVarsState, ShellDefinitionsState};
 
 const FISH_HOOK: &str = r#"
     function __{{.HookPrefix}}_export_eval --on-event fish_prompt;
@@ -31,6 +29,23 @@ const FISH_HOOK: &str = r#"
     end;
 "#;
 
+/// Holds the bash definitions of every function, so that wrappers can call each other.
+const BASH_FUNCTIONS_VAR_KEY: &str = "__env_hooks_bash_functions";
+
+const FISH_SESSION_ID: &str = r#"
+    set -gx {{.SessionIdVarKey}} "$fish_pid-"(random)(random);
+"#;
+
+const FISH_WRAPPER: &str = r#"
+    function {{.FunctionName}};
+        if contains -- "$argv[1]" {{.ExportSubcommands}};
+            {{.Program}} $argv --shell fish | source;
+        else;
+            {{.Program}} $argv;
+        end;
+    end;
+"#;
+
 pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) -> BString {
     BString::from(FISH_HOOK)
         .replace("{{.HookPrefix}}", hook_prefix)
@@ -38,44 +53,167 @@ pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) ->
         .into()
 }
 
+pub fn session_id(session_id_var_key: impl AsRef<[u8]>) -> BString {
+    BString::from(FISH_SESSION_ID)
+        .replace("{{.SessionIdVarKey}}", session_id_var_key)
+        .into()
+}
+
+pub fn wrapper(
+    function_name: impl AsRef<[u8]>,
+    program: impl AsRef<[u8]>,
+    export_subcommands: &[&str],
+) -> BString {
+    BString::from(FISH_WRAPPER)
+        .replace("{{.FunctionName}}", function_name)
+        .replace("{{.Program}}", program)
+        .replace("{{.ExportSubcommands}}", export_subcommands.join(" "))
+        .into()
+}
+
 pub fn export(
     env_vars_state: EnvVarsState,
-    semicolon_delimited_env_vars: Option<&HashSet<String>>,
+    delimited_env_vars: Option<&DelimitedEnvVars>,
 ) -> BString {
     let exports = env_vars_state
         .iter()
         .map(|(key, state)| {
             if let Some(value) = state {
-                export_var(key, value, semicolon_delimited_env_vars)
+                export_var(key.as_bstr(), value.as_bstr(), delimited_env_vars)
             } else {
-                unset_var(key)
+                unset_var(key.as_bstr())
             }
         })
         .collect::<Vec<_>>();
     bstr::join("\n", exports).into()
 }
 
-fn export_var(
-    key: &str,
-    value: &str,
-    semicolon_delimited_env_vars: Option<&HashSet<String>>,
-) -> BString {
-    let script = bstr::join(" ", [B("set -x -g"), &Fish::quote_vec(key)]);
-    let value = if let Some(sdev) = semicolon_delimited_env_vars
-        && sdev.contains(key)
-    {
-        let value_parts = value.split(':').map(Fish::quote_vec).collect::<Vec<_>>();
-        bstr::join(" ", value_parts)
-    } else {
-        Fish::quote_vec(value)
+fn export_var(key: &BStr, value: &BStr, delimited_env_vars: Option<&DelimitedEnvVars>) -> BString {
+    let delimited_env_var = delimited_env_vars.and_then(|delimited_env_vars| {
+        key.to_str()
+            .ok()
+            .and_then(|key| delimited_env_vars.get(key))
+    });
+    // NB: Fish joins exported lists with spaces, unless they're marked as a path
+    let (flags, value) = match delimited_env_var {
+        Some(delimited_env_var) if matches!(delimited_env_var.separator.as_str(), ":" | " ") => {
+            let value_parts = delimited_env_var
+                .split(value)
+                .map(|value_part| Fish::quote_vec(value_part.as_bstr()))
+                .collect::<Vec<_>>();
+            let flags = if delimited_env_var.separator == ":" {
+                B("set -x -g --path")
+            } else {
+                B("set -x -g")
+            };
+            (flags, bstr::join(" ", value_parts))
+        }
+        _ => (B("set -x -g"), Fish::quote_vec(value)),
     };
+    let script = bstr::join(" ", [flags, &Fish::quote_vec(key)]);
     bstr::concat([&bstr::join(" ", [script, value]), B(";")]).into()
 }
 
-fn unset_var(key: &str) -> BString {
+fn unset_var(key: &BStr) -> BString {
     bstr::concat([
         &bstr::join(" ", [B("set -e -g"), &Fish::quote_vec(key)]),
         B(";"),
     ])
     .into()
 }
+
+/// Functions are written for bash, so each one becomes a wrapper that runs it in bash. They can't
+/// change the state of the fish session, e.g. its variables or working directory.
+pub fn export_shell_definitions(shell_definitions_state: &ShellDefinitionsState) -> BString {
+    let definitions = shell_definitions_state
+        .functions
+        .values()
+        .flatten()
+        .map(|definition| format!("{}\n", definition.trim_end()))
+        .collect::<String>();
+    let bash_functions = if !definitions.is_empty() {
+        Some(bstr::concat([
+            &bstr::join(
+                " ",
+                [
+                    B("set -g"),
+                    B(BASH_FUNCTIONS_VAR_KEY),
+                    &Fish::quote_vec(&definitions),
+                ],
+            ),
+            B(";"),
+        ]))
+    } else if !shell_definitions_state.functions.is_empty() {
+        Some(bstr::concat([
+            B("set -e -g "),
+            B(BASH_FUNCTIONS_VAR_KEY),
+            B(";"),
+        ]))
+    } else {
+        None
+    };
+    let functions = shell_definitions_state
+        .functions
+        .iter()
+        .map(|(name, definition)| match definition {
+            Some(_) => set_function_wrapper(name),
+            None => unset_function(name),
+        });
+    let aliases = shell_definitions_state
+        .aliases
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => set_alias(name, value),
+            None => unset_function(name),
+        });
+    let exports = bash_functions
+        .into_iter()
+        .map(BString::from)
+        .chain(functions)
+        .chain(aliases)
+        .collect::<Vec<_>>();
+    bstr::join("\n", exports).into()
+}
+
+fn set_function_wrapper(name: &str) -> BString {
+    let call = bstr::join(" ", [&Bash::quote_vec(name), B(r#""$@""#)]);
+    let body = bstr::concat([
+        B("bash -c \"$"),
+        B(BASH_FUNCTIONS_VAR_KEY),
+        B("\""),
+        &Fish::quote_vec(&call),
+        B(" "),
+        &Fish::quote_vec(name),
+        B(" $argv;"),
+    ]);
+    bstr::join(
+        " ",
+        [
+            B("function"),
+            &Fish::quote_vec(name),
+            B(";"),
+            &body,
+            B("end;"),
+        ],
+    )
+    .into()
+}
+
+fn set_alias(name: &str, value: &str) -> BString {
+    bstr::concat([
+        &bstr::join(
+            " ",
+            [B("alias"), &Fish::quote_vec(name), &Fish::quote_vec(value)],
+        ),
+        B(";"),
+    ])
+    .into()
+}
+
+fn unset_function(name: &str) -> BString {
+    bstr::concat([
+        &bstr::join(" ", [B("functions -e"), &Fish::quote_vec(name)]),
+        B(";"),
+    ])
+    .into()
+}
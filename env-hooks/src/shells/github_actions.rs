@@ -0,0 +1,34 @@
+use bstr::{BString, ByteSlice};
+
+use crate::EnvVars;
+
+const DELIMITER: &str = "ENVOLUNTARY_EOF";
+
+/// Exports every variable in the format of the file `$GITHUB_ENV` points to in GitHub Actions.
+///
+/// The format can't unset variables, so it takes the full environment rather than the changes to
+/// it. Values holding newlines are written between `KEY<<DELIMITER` and `DELIMITER` lines, with a
+/// delimiter that none of the value's lines are equal to.
+pub fn export(env_vars: &EnvVars) -> BString {
+    let mut output = BString::default();
+    for (key, value) in env_vars.iter() {
+        output.extend_from_slice(key);
+        if !value.contains(&b'\n') {
+            output.push(b'=');
+            output.extend_from_slice(value);
+            output.push(b'\n');
+            continue;
+        }
+        let delimiter = (0..)
+            .map(|i| match i {
+                0 => String::from(DELIMITER),
+                _ => format!("{DELIMITER}_{i}"),
+            })
+            .find(|delimiter| !value.lines().any(|line| line == delimiter.as_bytes()))
+            .unwrap_or_else(|| String::from(DELIMITER));
+        output.extend_from_slice(format!("<<{delimiter}\n").as_bytes());
+        output.extend_from_slice(value);
+        output.extend_from_slice(format!("\n{delimiter}\n").as_bytes());
+    }
+    output
+}
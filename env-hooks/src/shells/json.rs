@@ -1,12 +1,10 @@
-use std::collections::HashSet;
-
 use bstr::BString;
 
-use crate::EnvVarsState;
+use crate::{DelimitedEnvVars, EnvVarsState};
 
 pub fn export(
     env_vars_state: EnvVarsState,
-    _semicolon_delimited_env_vars: Option<&HashSet<String>>,
+    _delimited_env_vars: Option<&DelimitedEnvVars>,
 ) -> BString {
     serde_json::to_string_pretty(&env_vars_state)
         .unwrap_or_else(|_| String::from("{}"))
@@ -1,4 +1,5 @@
 use bstr::{BString, ByteSlice};
+use indexmap::IndexMap;
 
 use crate::EnvVarsState;
 
@@ -10,13 +11,55 @@ $env.config.hooks.env_change.PWD = (
 )
 "#;
 
+const NUSHELL_SESSION_ID: &str = r#"
+$env.{{.SessionIdVarKey}} = $"($nu.pid)-(random int 0..1000000000)"
+"#;
+
+const NUSHELL_WRAPPER: &str = r#"
+def --env --wrapped {{.FunctionName}} [...args] {
+    if ($args | get --optional 0) in [{{.ExportSubcommands}}] {
+        ^{{.Program}} ...$args --shell nushell | from json --objects | default {} | reduce --fold {} {|row, acc| $acc | merge $row} | load-env
+    } else {
+        ^{{.Program}} ...$args
+    }
+}
+"#;
+
 pub fn hook(export_command: impl AsRef<[u8]>) -> BString {
     BString::from(NUSHELL_HOOK)
         .replace("{{.ExportCommand}}", export_command)
         .into()
 }
 
+pub fn session_id(session_id_var_key: impl AsRef<[u8]>) -> BString {
+    BString::from(NUSHELL_SESSION_ID)
+        .replace("{{.SessionIdVarKey}}", session_id_var_key)
+        .into()
+}
+
+pub fn wrapper(
+    function_name: impl AsRef<[u8]>,
+    program: impl AsRef<[u8]>,
+    export_subcommands: &[&str],
+) -> BString {
+    BString::from(NUSHELL_WRAPPER)
+        .replace("{{.FunctionName}}", function_name)
+        .replace("{{.Program}}", program)
+        .replace("{{.ExportSubcommands}}", export_subcommands.join(" "))
+        .into()
+}
+
 pub fn export(env_vars_state: EnvVarsState) -> BString {
+    // NB: Nushell strings are always UTF-8, so values that aren't get converted lossily
+    let env_vars_state = env_vars_state
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                key.to_str_lossy().into_owned(),
+                value.map(|value| value.to_str_lossy().into_owned()),
+            )
+        })
+        .collect::<IndexMap<_, _>>();
     let json = serde_json::to_string(&env_vars_state).unwrap_or_else(|_| String::from("{}"));
     if json.trim().is_empty() {
         String::from("{}").into()
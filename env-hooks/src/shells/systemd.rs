@@ -0,0 +1,58 @@
+use bstr::{BString, ByteSlice};
+
+use crate::EnvVars;
+
+/// Exports every variable as a `KEY=value` line of a systemd `EnvironmentFile`.
+///
+/// The format can't unset variables, so it takes the full environment rather than the changes to
+/// it. Values that aren't plain words are double quoted, with backslashes, double quotes and
+/// newlines escaped. systemd doesn't expand variables in these files, so `$` is kept as is.
+/// Variables that can't be written are left out, see [`skipped_keys`].
+pub fn export(env_vars: &EnvVars) -> BString {
+    env_vars
+        .iter()
+        .filter_map(|(key, value)| Some((key.to_str().ok()?, value.to_str().ok()?)))
+        .filter(|(key, _)| is_valid_key(key))
+        .map(|(key, value)| format!("{key}={}\n", quote(value)))
+        .collect::<String>()
+        .into()
+}
+
+/// Keys of the variables [`export`] leaves out, i.e. the ones whose names systemd doesn't accept,
+/// such as the `BASH_FUNC_<name>%%` ones bash exports functions in, or whose values aren't UTF-8.
+pub fn skipped_keys(env_vars: &EnvVars) -> Vec<&BString> {
+    env_vars
+        .iter()
+        .filter(|(key, value)| !key.to_str().is_ok_and(is_valid_key) || value.to_str().is_err())
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// systemd only accepts names made of ASCII letters, digits and `_`, not starting with a digit.
+fn is_valid_key(key: &str) -> bool {
+    !key.starts_with(|char: char| char.is_ascii_digit())
+        && !key.is_empty()
+        && key
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+fn quote(value: &str) -> String {
+    let is_plain = value
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"_./:@%+,-".contains(&byte));
+    if is_plain {
+        return String::from(value);
+    }
+    let mut quoted = String::from("\"");
+    for char in value.chars() {
+        match char {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
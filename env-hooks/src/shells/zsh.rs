@@ -1,9 +1,7 @@
-use std::collections::HashSet;
-
-use bstr::{B, BString, ByteSlice};
+use bstr::{B, BStr, BString, ByteSlice};
 use shell_quote::Zsh;
 
-use crate::EnvVarsState;
+use crate::{DelimitedEnvVars, EnvVarsState, ShellDefinitionsState};
 
 const ZSH_HOOK: &str = r#"
     _{{.HookPrefix}}_hook() {
@@ -22,6 +20,27 @@ const ZSH_HOOK: &str = r#"
     fi
 "#;
 
+const ZSH_SESSION_ID: &str = r#"
+    export {{.SessionIdVarKey}}="$$-$RANDOM$RANDOM";
+"#;
+
+const ZSH_WRAPPER: &str = r#"
+    {{.FunctionName}}() {
+        case "${1:-}" in
+            {{.ExportSubcommands}})
+                local vars
+                vars="$({{.Program}} "$@" --shell zsh)" || return
+                trap -- '' SIGINT
+                eval "$vars"
+                trap - SIGINT
+                ;;
+            *)
+                {{.Program}} "$@"
+                ;;
+        esac
+    }
+"#;
+
 pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) -> BString {
     BString::from(ZSH_HOOK)
         .replace("{{.HookPrefix}}", hook_prefix)
@@ -29,29 +48,101 @@ pub fn hook(hook_prefix: impl AsRef<[u8]>, export_command: impl AsRef<[u8]>) ->
         .into()
 }
 
+pub fn session_id(session_id_var_key: impl AsRef<[u8]>) -> BString {
+    BString::from(ZSH_SESSION_ID)
+        .replace("{{.SessionIdVarKey}}", session_id_var_key)
+        .into()
+}
+
+pub fn wrapper(
+    function_name: impl AsRef<[u8]>,
+    program: impl AsRef<[u8]>,
+    export_subcommands: &[&str],
+) -> BString {
+    BString::from(ZSH_WRAPPER)
+        .replace("{{.FunctionName}}", function_name)
+        .replace("{{.Program}}", program)
+        .replace("{{.ExportSubcommands}}", export_subcommands.join("|"))
+        .into()
+}
+
 pub fn export(
     env_vars_state: EnvVarsState,
-    _semicolon_delimited_env_vars: Option<&HashSet<String>>,
+    _delimited_env_vars: Option<&DelimitedEnvVars>,
 ) -> BString {
     let exports = env_vars_state
         .iter()
         .map(|(key, state)| {
             if let Some(value) = state {
-                export_var(key, value)
+                export_var(key.as_bstr(), value.as_bstr())
             } else {
-                unset_var(key)
+                unset_var(key.as_bstr())
             }
         })
         .collect::<Vec<_>>();
     bstr::join("\n", exports).into()
 }
 
-fn export_var(key: &str, value: &str) -> BString {
+fn export_var(key: &BStr, value: &BStr) -> BString {
     let script = bstr::join(" ", [B("export"), &Zsh::quote_vec(key)]);
     let value = Zsh::quote_vec(value);
     bstr::concat([&bstr::join("=", [script, value]), B(";")]).into()
 }
 
-fn unset_var(key: &str) -> BString {
+fn unset_var(key: &BStr) -> BString {
     bstr::concat([&bstr::join(" ", [B("unset"), &Zsh::quote_vec(key)]), B(";")]).into()
 }
+
+pub fn export_shell_definitions(shell_definitions_state: &ShellDefinitionsState) -> BString {
+    let functions = shell_definitions_state
+        .functions
+        .iter()
+        .map(|(name, definition)| match definition {
+            Some(definition) => set_function(definition),
+            None => unset_function(name),
+        });
+    let aliases = shell_definitions_state
+        .aliases
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => set_alias(name, value),
+            None => unset_alias(name),
+        });
+    bstr::join("\n", functions.chain(aliases)).into()
+}
+
+fn set_function(definition: &str) -> BString {
+    // NB: Definitions are written for bash, so they're defined with ksh emulation, which is the
+    // closest to bash and sticks to the function when it runs. A definition zsh can't parse only
+    // fails on its own, since it's quoted.
+    bstr::concat([
+        &bstr::join(" ", [B("emulate ksh -c"), &Zsh::quote_vec(definition)]),
+        B(";"),
+    ])
+    .into()
+}
+
+fn unset_function(name: &str) -> BString {
+    bstr::concat([
+        &bstr::join(" ", [B("unset -f --"), &Zsh::quote_vec(name)]),
+        B(" 2>/dev/null;"),
+    ])
+    .into()
+}
+
+fn set_alias(name: &str, value: &str) -> BString {
+    let alias = bstr::concat([name.as_bytes(), B("="), value.as_bytes()]);
+    bstr::concat([
+        &bstr::join(" ", [B("alias --"), &Zsh::quote_vec(&alias)]),
+        B(";"),
+    ])
+    .into()
+}
+
+fn unset_alias(name: &str) -> BString {
+    bstr::concat([
+        &bstr::join(" ", [B("unalias --"), &Zsh::quote_vec(name)]),
+        B(" 2>/dev/null;"),
+    ])
+    .into()
+}
@@ -36,12 +36,12 @@
 
 use std::{
     env,
-    ffi::{OsStr, OsString},
+    ffi::OsString,
     marker::PhantomData,
     path::{Path, PathBuf},
 };
 
-use anyhow::Ok;
+use crate::{Error, store::StateStore};
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ShellPromptState;
@@ -49,7 +49,7 @@ pub struct ShellPromptState;
 impl ShellPromptState {
     pub fn get_current_dir(
         provided_current_dir: Option<PathBuf>,
-    ) -> anyhow::Result<CurrentDirState> {
+    ) -> Result<CurrentDirState, Error> {
         let current_dir = if let Some(current_dir) = provided_current_dir {
             current_dir
         } else {
@@ -65,10 +65,10 @@ pub struct CurrentDirState {
 }
 
 impl CurrentDirState {
-    pub fn match_rcs<RC>(
+    pub fn match_rcs<RC, E>(
         self,
-        match_rcs_cb: impl Fn(&Path) -> anyhow::Result<Vec<RC>>,
-    ) -> anyhow::Result<MatchRcs<RC>> {
+        match_rcs_cb: impl Fn(&Path) -> Result<Vec<RC>, E>,
+    ) -> Result<MatchRcs<RC>, E> {
         let rcs = match_rcs_cb(&self.current_dir)?;
         let match_rcs = if rcs.is_empty() {
             MatchRcs::NoRcs(NoRcsState {
@@ -95,11 +95,14 @@ pub struct NoRcsState {
 impl NoRcsState {
     pub fn get_env_state_var(
         self,
-        env_state_var_key: impl AsRef<OsStr>,
-    ) -> Option<ReadyForFullResetState> {
-        get_env_state_var(env_state_var_key).map(|env_state_var_value| ReadyForFullResetState {
-            env_state_var_value,
-        })
+        state_store: &(impl StateStore + ?Sized),
+    ) -> Result<Option<ReadyForFullResetState>, Error> {
+        let env_state_var_value = state_store.load()?;
+        Ok(
+            env_state_var_value.map(|env_state_var_value| ReadyForFullResetState {
+                env_state_var_value,
+            }),
+        )
     }
 }
 
@@ -109,10 +112,10 @@ pub struct ReadyForFullResetState {
 }
 
 impl ReadyForFullResetState {
-    pub fn reset_env_vars(
+    pub fn reset_env_vars<E>(
         self,
-        reset_env_vars_cb: impl Fn(OsString) -> anyhow::Result<()>,
-    ) -> anyhow::Result<()> {
+        reset_env_vars_cb: impl Fn(OsString) -> Result<(), E>,
+    ) -> Result<(), E> {
         reset_env_vars_cb(self.env_state_var_value)?;
         Ok(())
     }
@@ -124,16 +127,20 @@ pub struct RcsState<RC> {
 }
 
 impl<RC> RcsState<RC> {
-    pub fn get_env_state_var(self, env_state_var_key: impl AsRef<OsStr>) -> GetEnvStateVar<RC> {
+    pub fn get_env_state_var(
+        self,
+        state_store: &(impl StateStore + ?Sized),
+    ) -> Result<GetEnvStateVar<RC>, Error> {
         let rcs = self.rcs;
-        if let Some(env_state_var_value) = get_env_state_var(env_state_var_key) {
+        let get_env_state_var = if let Some(env_state_var_value) = state_store.load()? {
             GetEnvStateVar::EnvStateVar(EnvStateVarState {
                 rcs,
                 env_state_var_value,
             })
         } else {
             GetEnvStateVar::NoEnvStateVar(NoEnvStateVarState { rcs })
-        }
+        };
+        Ok(get_env_state_var)
     }
 }
 
@@ -149,10 +156,10 @@ pub struct NoEnvStateVarState<RC> {
 }
 
 impl<RC> NoEnvStateVarState<RC> {
-    pub fn set_new_env_state_var(
+    pub fn set_new_env_state_var<E>(
         self,
-        set_new_env_state_var_cb: impl Fn(Vec<RC>) -> anyhow::Result<()>,
-    ) -> anyhow::Result<()> {
+        set_new_env_state_var_cb: impl Fn(Vec<RC>) -> Result<(), E>,
+    ) -> Result<(), E> {
         set_new_env_state_var_cb(self.rcs)?;
         Ok(())
     }
@@ -165,17 +172,13 @@ pub struct EnvStateVarState<RC> {
 }
 
 impl<RC> EnvStateVarState<RC> {
-    pub fn reset_and_set_new_env_state_var<T>(
+    pub fn reset_and_set_new_env_state_var<T, E>(
         self,
-        reset_env_vars_cb: impl Fn(Vec<RC>, OsString) -> anyhow::Result<T>,
-        set_new_env_state_var_cb: impl Fn(T) -> anyhow::Result<()>,
-    ) -> anyhow::Result<()> {
+        reset_env_vars_cb: impl Fn(Vec<RC>, OsString) -> Result<T, E>,
+        set_new_env_state_var_cb: impl Fn(T) -> Result<(), E>,
+    ) -> Result<(), E> {
         let state = reset_env_vars_cb(self.rcs, self.env_state_var_value)?;
         set_new_env_state_var_cb(state)?;
         Ok(())
     }
 }
-
-fn get_env_state_var(env_state_var_key: impl AsRef<OsStr>) -> Option<OsString> {
-    env::var_os(env_state_var_key)
-}
@@ -0,0 +1,276 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, DirBuilder},
+    io::{self, Write},
+    os::unix::{ffi::OsStringExt, fs::DirBuilderExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use bstr::{BString, ByteSlice};
+
+use crate::{EnvVars, EnvVarsState, Error, codec::ChunkedEnvVar, codec::checksum};
+
+/// Where the state of an environment is kept between prompts.
+///
+/// Whatever a store keeps in the environment is returned as variables for the shell to set, so
+/// that nothing changes until the shell evaluates them.
+pub trait StateStore {
+    /// Reads the state, or `None` when no environment is loaded.
+    fn load(&self) -> Result<Option<OsString>, Error>;
+
+    /// Variables that make the shell hold on to the state, persisting it first when it's kept
+    /// outside of the environment.
+    fn save(&self, state: &str, current_env_vars: &EnvVars) -> Result<EnvVarsState, Error>;
+
+    /// Variables that remove the state from the shell.
+    fn clear(&self, current_env_vars: &EnvVars) -> EnvVarsState;
+}
+
+/// Keeps the state in an env var, which every child process of the shell inherits.
+///
+/// Values too long for one variable are split across several, see [`ChunkedEnvVar`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvStateStore {
+    env_var: ChunkedEnvVar,
+}
+
+impl EnvStateStore {
+    pub fn new(key: impl Into<BString>) -> Self {
+        Self {
+            env_var: ChunkedEnvVar::new(key),
+        }
+    }
+
+    /// Sets how long the value of each variable can get before it's split.
+    pub fn with_max_chunk_len(mut self, max_chunk_len: usize) -> Self {
+        self.env_var = self.env_var.with_max_chunk_len(max_chunk_len);
+        self
+    }
+}
+
+impl StateStore for EnvStateStore {
+    fn load(&self) -> Result<Option<OsString>, Error> {
+        self.env_var.get_from_current_process()
+    }
+
+    fn save(&self, state: &str, current_env_vars: &EnvVars) -> Result<EnvVarsState, Error> {
+        Ok(self.env_var.set(state, current_env_vars))
+    }
+
+    fn clear(&self, current_env_vars: &EnvVars) -> EnvVarsState {
+        self.env_var.unset(current_env_vars)
+    }
+}
+
+/// Starts the value of the env var when the state is kept in a file, followed by the file's name.
+const FILE_TOKEN_PREFIX: &str = "file:";
+
+/// How long files of sessions whose shell can't be told from their id are kept.
+const STALE_FILE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Keeps the state in a file per shell session, so that only a short token pointing at the file
+/// is kept in the env var.
+///
+/// Files are named `<session id>.<checksum>`, so each state saved gets a new file and the one the
+/// shell still points at is never overwritten. Older files of the same session are removed the
+/// next time the state is saved, along with files of sessions whose shell has exited. Shells
+/// started from another inherit its token, so they read their own copy of the file it points at,
+/// which outlives the parent moving on. Values of the env var that aren't tokens are read as the
+/// state itself, so that switching from [`EnvStateStore`] doesn't break shells that already have
+/// an environment loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStateStore {
+    env_var: ChunkedEnvVar,
+    key: BString,
+    dir: PathBuf,
+    session_id: String,
+}
+
+impl FileStateStore {
+    /// The session id has to be unique to the shell, e.g. generated by its hook, see
+    /// [`crate::shells::bash::session_id`].
+    pub fn new(
+        key: impl Into<BString>,
+        dir: impl Into<PathBuf>,
+        session_id: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let key = key.into();
+        let session_id = session_id.into();
+        if !valid_file_name_part(&session_id) {
+            return Err(Error::InvalidSessionId { session_id });
+        }
+        Ok(Self {
+            env_var: ChunkedEnvVar::new(key.clone()),
+            key,
+            dir: dir.into(),
+            session_id,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file the env var points at, if it holds a token.
+    fn file_name_from_value(value: &[u8]) -> Option<&str> {
+        value
+            .strip_prefix(FILE_TOKEN_PREFIX.as_bytes())?
+            .to_str()
+            .ok()
+            .filter(|file_name| {
+                file_name
+                    .split_once('.')
+                    .is_some_and(|(session_id, checksum)| {
+                        valid_file_name_part(session_id) && valid_file_name_part(checksum)
+                    })
+            })
+    }
+
+    fn create_dir(&self) -> io::Result<()> {
+        // NB: The state holds the values of variables, so only the user gets to read it
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+    }
+
+    /// The name of this session's copy of a file, which may belong to another session.
+    fn own_file_name(&self, file_name: &str) -> Option<String> {
+        let (_, checksum) = file_name.split_once('.')?;
+        Some(format!("{}.{checksum}", self.session_id))
+    }
+
+    /// Removes files of this session other than the ones the shell may still read, and files of
+    /// sessions that have ended.
+    fn remove_stale_files(&self, keep: &[&str]) -> io::Result<()> {
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            let Some((session_id, _)) = file_name.to_str().and_then(|file_name| {
+                file_name
+                    .split_once('.')
+                    .filter(|_| !keep.contains(&file_name))
+            }) else {
+                continue;
+            };
+            let stale = if session_id == self.session_id {
+                true
+            } else if let Some(pid) = session_pid(session_id) {
+                !process_is_running(pid)
+            } else {
+                dir_entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age > STALE_FILE_MAX_AGE)
+            };
+            if !stale {
+                continue;
+            }
+            match fs::remove_file(dir_entry.path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn load(&self) -> Result<Option<OsString>, Error> {
+        let Some(value) = self.env_var.get_from_current_process()? else {
+            return Ok(None);
+        };
+        let Some(file_name) = Self::file_name_from_value(value.as_encoded_bytes()) else {
+            return Ok(Some(value));
+        };
+        let mut path = self.dir.join(file_name);
+        // NB: The token was inherited from the shell this one was started from, which removes the
+        // file once it saves new state, so this session reads its own copy instead
+        if let Some(own_file_name) = self
+            .own_file_name(file_name)
+            .filter(|own_file_name| own_file_name != file_name)
+        {
+            let own_path = self.dir.join(own_file_name);
+            match fs::hard_link(&path, &own_path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound && own_path.exists() => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(Error::StateFileNotFound { path });
+                }
+                Err(e) => return Err(e.into()),
+            }
+            path = own_path;
+        }
+        match fs::read(&path) {
+            Ok(state) => Ok(Some(OsString::from_vec(state))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::StateFileNotFound { path }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, state: &str, current_env_vars: &EnvVars) -> Result<EnvVarsState, Error> {
+        let file_name = format!("{}.{:08x}", self.session_id, checksum(state.as_bytes()));
+
+        self.create_dir()?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(state.as_bytes())?;
+        file.persist(self.dir.join(&file_name))
+            .map_err(|e| e.error)?;
+
+        // NB: The file the shell points at now stays until the shell points at the new one
+        let current_file_name = current_env_vars
+            .get(&self.key)
+            .and_then(|value| Self::file_name_from_value(value));
+        let current_own_file_name =
+            current_file_name.and_then(|current_file_name| self.own_file_name(current_file_name));
+        self.remove_stale_files(&[
+            &file_name,
+            current_file_name.unwrap_or_default(),
+            current_own_file_name.as_deref().unwrap_or_default(),
+        ])?;
+
+        let mut env_vars_state = self.env_var.unset(current_env_vars);
+        env_vars_state.insert(
+            self.key.clone(),
+            Some(BString::from(format!("{FILE_TOKEN_PREFIX}{file_name}"))),
+        );
+        Ok(env_vars_state)
+    }
+
+    fn clear(&self, current_env_vars: &EnvVars) -> EnvVarsState {
+        self.env_var.unset(current_env_vars)
+    }
+}
+
+/// The dir for files that last as long as the user's login, i.e. `$XDG_RUNTIME_DIR`.
+pub fn runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|runtime_dir| runtime_dir.is_absolute())
+}
+
+/// Session ids generated by the shell hooks start with the pid of the shell.
+fn session_pid(session_id: &str) -> Option<libc::pid_t> {
+    let (pid, _) = session_id.split_once('-')?;
+    pid.parse().ok().filter(|&pid| pid > 0)
+}
+
+fn process_is_running(pid: libc::pid_t) -> bool {
+    // NB: Signal 0 only checks whether the process exists, and `EPERM` means it does but
+    // belongs to another user
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn valid_file_name_part(part: &str) -> bool {
+    !part.is_empty()
+        && part.len() <= 64
+        && part
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
@@ -0,0 +1,170 @@
+use assert_matches::assert_matches;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bstr::{B, BString};
+use env_hooks::{
+    EnvVars, EnvVarsState, Error,
+    codec::{
+        Base64Alphabet, ChunkedEnvVar, Compression, PlainStateCodec, StateCodec,
+        VersionedStateCodec,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct State {
+    flake_references: Vec<String>,
+}
+
+fn state() -> State {
+    State {
+        flake_references: vec![String::from("github:owner/repo")],
+    }
+}
+
+#[test]
+fn versioned_state_codec_round_trips_with_every_compression() {
+    for compression in [Compression::None, Compression::Zlib, Compression::Zstd] {
+        let codec = VersionedStateCodec::new(3).with_compression(compression);
+        let encoded = codec.encode_json(&state()).unwrap();
+        assert!(VersionedStateCodec::has_header(&encoded));
+        assert_eq!(codec.decode_json::<State>(&encoded).unwrap(), state());
+    }
+}
+
+#[test]
+fn versioned_state_codec_decodes_values_written_with_other_compression() {
+    let encoded = VersionedStateCodec::new(1)
+        .with_compression(Compression::Zlib)
+        .encode_json(&state())
+        .unwrap();
+    assert_eq!(
+        VersionedStateCodec::new(1)
+            .decode_json::<State>(&encoded)
+            .unwrap(),
+        state()
+    );
+}
+
+#[test]
+fn versioned_state_codec_rejects_other_versions() {
+    let encoded = VersionedStateCodec::new(1).encode_json(&state()).unwrap();
+    assert_matches!(
+        VersionedStateCodec::new(2).decode(&encoded),
+        Err(Error::StateVersionMismatch {
+            found: 1,
+            expected: 2
+        })
+    );
+}
+
+#[test]
+fn versioned_state_codec_rejects_corrupt_values() {
+    let codec = VersionedStateCodec::new(1).with_compression(Compression::None);
+    let encoded = codec.encode(b"{\"a\":1}").unwrap();
+    let (header, _) = encoded.rsplit_once(':').unwrap();
+
+    let tampered = format!("{header}:{}", BASE64_STANDARD.encode(b"{\"a\":2}"));
+    assert_matches!(codec.decode(&tampered), Err(Error::StateDecode(_)));
+
+    let truncated = &encoded[..encoded.len() - 4];
+    assert_matches!(codec.decode(truncated), Err(Error::StateDecode(_)));
+
+    assert!(!VersionedStateCodec::has_header("KLUv/QQ4"));
+    assert_matches!(codec.decode("KLUv/QQ4"), Err(Error::StateDecode(_)));
+}
+
+#[test]
+fn plain_state_codec_round_trips() {
+    let codec = PlainStateCodec::new(Compression::Zlib).with_alphabet(Base64Alphabet::UrlSafeNoPad);
+    let encoded = codec.encode_json(&state()).unwrap();
+    assert!(!encoded.contains(['+', '/', '=']));
+    assert_eq!(codec.decode_json::<State>(&encoded).unwrap(), state());
+}
+
+#[test]
+fn chunked_env_var_stores_short_values_as_is() {
+    let chunked_env_var = ChunkedEnvVar::new("STATE").with_max_chunk_len(8);
+    assert_eq!(
+        chunked_env_var.set("short", &EnvVars::new()),
+        EnvVarsState::from_iter([("STATE", Some("short"))])
+    );
+}
+
+#[test]
+fn chunked_env_var_splits_long_values() {
+    let chunked_env_var = ChunkedEnvVar::new("STATE").with_max_chunk_len(4);
+    let env_vars_state = chunked_env_var.set("0123456789", &EnvVars::new());
+    assert_eq!(
+        env_vars_state,
+        EnvVarsState::from_iter([
+            ("STATE", Some("chunked:3")),
+            ("STATE_0", Some("0123")),
+            ("STATE_1", Some("4567")),
+            ("STATE_2", Some("89")),
+        ])
+    );
+
+    let mut env_vars = EnvVars::new();
+    env_vars.apply(&env_vars_state);
+    assert_eq!(
+        chunked_env_var
+            .get(|key| env_vars.get(key).cloned())
+            .unwrap(),
+        Some(BString::from("0123456789"))
+    );
+
+    // NB: Chunks of the previous value that the new value doesn't need get unset
+    assert_eq!(
+        chunked_env_var.set("012345", &env_vars),
+        EnvVarsState::from_iter([
+            ("STATE", Some("chunked:2")),
+            ("STATE_0", Some("0123")),
+            ("STATE_1", Some("45")),
+            ("STATE_2", None),
+        ])
+    );
+    assert_eq!(
+        chunked_env_var.unset(&env_vars),
+        EnvVarsState::from_iter([
+            ("STATE", None::<&str>),
+            ("STATE_0", None),
+            ("STATE_1", None),
+            ("STATE_2", None),
+        ])
+    );
+}
+
+#[test]
+fn chunked_env_var_fails_to_read_when_a_chunk_is_missing() {
+    let env_vars = EnvVars::from_iter([("STATE", "chunked:2"), ("STATE_0", "0123")]);
+    assert_matches!(
+        ChunkedEnvVar::new("STATE").get(|key| env_vars.get(B(key)).cloned()),
+        Err(Error::StateDecode(_))
+    );
+}
+
+#[test]
+fn chunked_env_var_doesnt_trust_huge_chunk_counts() {
+    let chunked_env_var = ChunkedEnvVar::new("STATE");
+    let env_vars = EnvVars::from_iter([
+        ("STATE", "chunked:18446744073709551615"),
+        ("STATE_0", "0123"),
+        ("STATE_1", "4567"),
+        ("STATE_01", "unrelated"),
+        ("STATE_FOO", "unrelated"),
+    ]);
+    assert_matches!(
+        chunked_env_var.get(|key| env_vars.get(B(key)).cloned()),
+        Err(Error::StateDecode(_))
+    );
+
+    // NB: Only chunks that are set get unset
+    assert_eq!(
+        chunked_env_var.unset(&env_vars),
+        EnvVarsState::from_iter([
+            ("STATE", None::<&str>),
+            ("STATE_0", None),
+            ("STATE_1", None),
+        ])
+    );
+}
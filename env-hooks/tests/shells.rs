@@ -1,9 +1,8 @@
-use std::collections::HashSet;
-
-use bstr::ByteSlice;
+use bstr::{B, BString, ByteSlice};
 use env_hooks::{
-    EnvVarsState,
-    shells::{bash, fish, json, nushell, zsh},
+    DelimitedEnvVar, DelimitedEnvVars, EnvVars, EnvVarsState, ShellDefinitions,
+    ShellDefinitionsState,
+    shells::{bash, docker, dotenv, fish, github_actions, json, nushell, systemd, zsh},
 };
 use once_cell::sync::Lazy;
 
@@ -53,12 +52,88 @@ fn bash_export_set_unset_and_special_vars() {
     );
 }
 
+#[test]
+fn bash_export_non_utf8_values() {
+    let result = bash::export(
+        EnvVarsState::from_iter([(
+            "LD_LIBRARY_PATH",
+            Some(BString::from(B(b"/opt/caf\xe9/lib"))),
+        )]),
+        None,
+    );
+
+    assert_eq!(result, B(b"export LD_LIBRARY_PATH=$'/opt/caf\\xE9/lib';"));
+}
+
+static TEST_SHELL_DEFINITIONS: Lazy<ShellDefinitions> = Lazy::new(|| ShellDefinitions {
+    functions: [(
+        String::from("greet"),
+        String::from("greet () \n{ \n    echo \"hello $1\"\n}\n"),
+    )]
+    .into_iter()
+    .collect(),
+    aliases: [(String::from("ll"), String::from("ls -l"))]
+        .into_iter()
+        .collect(),
+});
+
+#[test]
+fn bash_export_shell_definitions() {
+    assert_eq!(
+        bash::export_shell_definitions(&ShellDefinitionsState::new()),
+        ""
+    );
+
+    let script = bstr::join(
+        "\n",
+        [
+            bash::export_shell_definitions(&TEST_SHELL_DEFINITIONS.clone().into()),
+            BString::from("greet world; alias ll"),
+            bash::export_shell_definitions(&TEST_SHELL_DEFINITIONS.reset()),
+            BString::from("type greet ll >/dev/null 2>&1 || echo removed"),
+        ],
+    );
+    let output = std::process::Command::new("bash")
+        .args(["--norc", "--noprofile", "-c"])
+        .arg(script.to_os_str().unwrap())
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        output.stdout.to_str().unwrap(),
+        "hello world\nalias ll='ls -l'\nremoved\n"
+    );
+}
+
 #[test]
 fn bash_hook_templated() {
     let result = bash::hook("myapp", "myapp export bash").to_string();
     assert!(!result.contains("{{."));
 }
 
+#[test]
+fn bash_session_id_templated() {
+    let result = bash::session_id("MYAPP_SESSION_ID").to_string();
+    assert!(!result.contains("{{."));
+    assert!(result.contains("export MYAPP_SESSION_ID="));
+}
+
+#[test]
+fn bash_wrapper_evaluates_output_of_export_subcommands() {
+    let result = bash::wrapper("myapp", "fake_myapp", &["load", "unload"]);
+    assert!(!result.contains_str("{{."));
+
+    let output = std::process::Command::new("bash")
+        .arg("-c")
+        .arg(format!(
+            "fake_myapp() {{ echo \"export WRAPPED=$2-$4\"; }}\n{result}\nmyapp load yes; echo \"$WRAPPED\""
+        ))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout.to_str().unwrap(), "yes-bash\n");
+}
+
 #[test]
 fn zsh_export_set_unset_and_special_vars() {
     assert_eq!(zsh::export(EnvVarsState::new(), None).to_str().unwrap(), "");
@@ -146,13 +221,62 @@ fn fish_export_delimited_variables() {
         vec!["set -x -g PATH /usr/bin':/usr/local/bin:/home/user/bin';"]
     );
 
-    let mut delim_vars = HashSet::new();
-    delim_vars.insert("PATH".to_string());
-    let result = fish::export(env_vars.clone(), Some(&delim_vars)).to_string();
+    let delim_vars = DelimitedEnvVars::from_iter([
+        ("PATH".to_string(), DelimitedEnvVar::default()),
+        (
+            "NIX_LDFLAGS".to_string(),
+            DelimitedEnvVar {
+                separator: " ".to_string(),
+                ..Default::default()
+            },
+        ),
+        (
+            "COMMA_LIST".to_string(),
+            DelimitedEnvVar {
+                separator: ",".to_string(),
+                ..Default::default()
+            },
+        ),
+    ]);
+    let mut env_vars = env_vars;
+    env_vars.insert("NIX_LDFLAGS".into(), Some("-L/a/lib -L/b/lib".into()));
+    env_vars.insert("COMMA_LIST".into(), Some("a,b".into()));
+    let result = fish::export(env_vars, Some(&delim_vars)).to_string();
     let lines = result.lines().filter(|l| !l.is_empty()).collect::<Vec<_>>();
     assert_eq!(
         lines,
-        vec!["set -x -g PATH /usr/bin /usr/local/bin /home/user/bin;"]
+        vec![
+            "set -x -g --path PATH /usr/bin /usr/local/bin /home/user/bin;",
+            "set -x -g NIX_LDFLAGS -L/a/lib -L/b/lib;",
+            "set -x -g COMMA_LIST a,b;",
+        ]
+    );
+}
+
+#[test]
+fn fish_export_shell_definitions_as_bash_wrappers() {
+    let result = fish::export_shell_definitions(&TEST_SHELL_DEFINITIONS.clone().into());
+    let lines = result.lines().collect::<Vec<_>>();
+
+    assert!(lines[0].starts_with(b"set -g __env_hooks_bash_functions greet"));
+    assert_eq!(
+        &lines[lines.len() - 2..],
+        [
+            B(
+                r#"function greet ; bash -c "$__env_hooks_bash_functions"greet' "$@"' greet $argv; end;"#
+            ),
+            B("alias ll ls' -l';"),
+        ]
+    );
+
+    let result = fish::export_shell_definitions(&TEST_SHELL_DEFINITIONS.reset());
+    assert_eq!(
+        result.lines().collect::<Vec<_>>(),
+        [
+            B("set -e -g __env_hooks_bash_functions;"),
+            B("functions -e greet;"),
+            B("functions -e ll;"),
+        ]
     );
 }
 
@@ -162,6 +286,138 @@ fn fish_hook_templated() {
     assert!(!result.contains("{{."));
 }
 
+fn full_test_env_vars() -> EnvVars {
+    EnvVars::from_iter(
+        TEST_ENV_VARS
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+            .chain([(BString::from("QUOTES"), BString::from(r#"it's "quoted""#))]),
+    )
+}
+
+#[test]
+fn dotenv_export_quotes_special_vars() {
+    assert_eq!(dotenv::export(&EnvVars::new()), "");
+
+    let result = dotenv::export(&full_test_env_vars()).to_string();
+
+    assert_eq!(
+        result.lines().collect::<Vec<_>>(),
+        vec![
+            "SIMPLE=value",
+            "WITH_SPACES='value with spaces'",
+            "DOLLAR='$VAR'",
+            "EMPTY=",
+            "PATH=/usr/bin:/usr/local/bin",
+            "VAR123=numeric",
+            "_PRIVATE=private",
+            r#"MULTI_LINE_VAR="\nHello,\nWorld!\n""#,
+            r#"QUOTES="it's \"quoted\"""#,
+        ]
+    );
+}
+
+#[test]
+fn systemd_export_quotes_special_vars() {
+    assert_eq!(systemd::export(&EnvVars::new()), "");
+
+    let result = systemd::export(&full_test_env_vars()).to_string();
+
+    assert_eq!(
+        result.lines().collect::<Vec<_>>(),
+        vec![
+            "SIMPLE=value",
+            r#"WITH_SPACES="value with spaces""#,
+            r#"DOLLAR="$VAR""#,
+            "EMPTY=",
+            "PATH=/usr/bin:/usr/local/bin",
+            "VAR123=numeric",
+            "_PRIVATE=private",
+            r#"MULTI_LINE_VAR="\nHello,\nWorld!\n""#,
+            r#"QUOTES="it's \"quoted\"""#,
+        ]
+    );
+    assert_eq!(
+        systemd::skipped_keys(&full_test_env_vars()),
+        Vec::<&BString>::new()
+    );
+}
+
+#[test]
+fn systemd_export_skips_vars_it_cant_hold() {
+    let env_vars = EnvVars::from_iter([
+        (BString::from("SIMPLE"), BString::from("value")),
+        (BString::from("BASH_FUNC_f%%"), BString::from("() {  :\n}")),
+        (BString::from("1ST"), BString::from("first")),
+        (BString::from("NOT_UTF8"), BString::from(b"\xff".as_slice())),
+    ]);
+
+    assert_eq!(systemd::export(&env_vars), "SIMPLE=value\n");
+    assert_eq!(
+        systemd::skipped_keys(&env_vars),
+        vec!["BASH_FUNC_f%%", "1ST", "NOT_UTF8"]
+    );
+}
+
+#[test]
+fn docker_export_skips_multi_line_vars() {
+    assert_eq!(docker::export(&EnvVars::new()), "");
+
+    let result = docker::export(&full_test_env_vars()).to_string();
+
+    assert_eq!(
+        result.lines().collect::<Vec<_>>(),
+        vec![
+            "SIMPLE=value",
+            "WITH_SPACES=value with spaces",
+            "DOLLAR=$VAR",
+            "EMPTY=",
+            "PATH=/usr/bin:/usr/local/bin",
+            "VAR123=numeric",
+            "_PRIVATE=private",
+            r#"QUOTES=it's "quoted""#,
+        ]
+    );
+    assert_eq!(
+        docker::skipped_keys(&full_test_env_vars()),
+        vec!["MULTI_LINE_VAR"]
+    );
+}
+
+#[test]
+fn github_actions_export_delimits_multi_line_vars() {
+    assert_eq!(github_actions::export(&EnvVars::new()), "");
+
+    let env_vars = EnvVars::from_iter([
+        (BString::from("SIMPLE"), BString::from("value")),
+        (
+            BString::from("MULTI_LINE_VAR"),
+            BString::from("Hello,\nWorld!"),
+        ),
+        (
+            BString::from("WITH_DELIMITER"),
+            BString::from("a\nENVOLUNTARY_EOF\nb"),
+        ),
+    ]);
+    let result = github_actions::export(&env_vars);
+
+    assert_eq!(
+        result,
+        concat!(
+            "SIMPLE=value\n",
+            "MULTI_LINE_VAR<<ENVOLUNTARY_EOF\n",
+            "Hello,\n",
+            "World!\n",
+            "ENVOLUNTARY_EOF\n",
+            "WITH_DELIMITER<<ENVOLUNTARY_EOF_1\n",
+            "a\n",
+            "ENVOLUNTARY_EOF\n",
+            "b\n",
+            "ENVOLUNTARY_EOF_1\n",
+        )
+    );
+}
+
 #[test]
 fn json_export_set_unset_and_special_vars() {
     assert_eq!(
@@ -3,7 +3,10 @@ use std::env;
 use std::path::PathBuf;
 
 use assert_matches::assert_matches;
-use env_hooks::state::{self, GetEnvStateVar, MatchRcs};
+use env_hooks::{
+    state::{self, GetEnvStateVar, MatchRcs},
+    store::EnvStateStore,
+};
 
 #[test]
 fn workflow_no_rcs_found_resets_existing_state() {
@@ -19,7 +22,10 @@ fn workflow_no_rcs_found_resets_existing_state() {
         .match_rcs(|_| Ok::<Vec<String>, anyhow::Error>(vec![]))
         .unwrap();
     let no_rcs_state = assert_matches!(match_rcs, MatchRcs::NoRcs(no_rcs_state) => no_rcs_state);
-    let ready_for_reset = no_rcs_state.get_env_state_var(ENV_STATE_VAR_KEY).unwrap();
+    let ready_for_reset = no_rcs_state
+        .get_env_state_var(&EnvStateStore::new(ENV_STATE_VAR_KEY))
+        .unwrap()
+        .unwrap();
 
     let reset_executed = Cell::new(false);
     let reset_value = RefCell::new(String::new());
@@ -27,7 +33,7 @@ fn workflow_no_rcs_found_resets_existing_state() {
         .reset_env_vars(|value| {
             reset_executed.set(true);
             *reset_value.borrow_mut() = value.to_string_lossy().to_string();
-            Ok(())
+            Ok::<(), anyhow::Error>(())
         })
         .unwrap();
     assert!(reset_executed.get());
@@ -48,10 +54,12 @@ fn workflow_rcs_found_new_environment_sets_initial_state() {
 
     let current_dir_state = state::ShellPromptState::get_current_dir(None).unwrap();
     let match_rcs = current_dir_state
-        .match_rcs(|_| Ok(matched_rcs.clone()))
+        .match_rcs(|_| Ok::<Vec<PathBuf>, anyhow::Error>(matched_rcs.clone()))
         .unwrap();
     let rcs_state = assert_matches!(match_rcs, MatchRcs::Rcs(rcs_state) => rcs_state);
-    let get_env_state_var = rcs_state.get_env_state_var("WORKFLOW_STATE_VAR");
+    let get_env_state_var = rcs_state
+        .get_env_state_var(&EnvStateStore::new("WORKFLOW_STATE_VAR"))
+        .unwrap();
     let no_env_state_var_state = assert_matches!(get_env_state_var, GetEnvStateVar::NoEnvStateVar(no_env_state_var_state) => no_env_state_var_state);
 
     let new_state_set = Cell::new(false);
@@ -60,7 +68,7 @@ fn workflow_rcs_found_new_environment_sets_initial_state() {
         .set_new_env_state_var(|rcs| {
             new_state_set.set(true);
             *rcs_paths.borrow_mut() = rcs;
-            Ok(())
+            Ok::<(), anyhow::Error>(())
         })
         .unwrap();
 
@@ -83,10 +91,12 @@ fn workflow_rcs_found_existing_environment_resets_and_updates_state() {
 
     let current_dir_state = state::ShellPromptState::get_current_dir(None).unwrap();
     let match_rcs = current_dir_state
-        .match_rcs(|_| Ok(matched_rcs.clone()))
+        .match_rcs(|_| Ok::<Vec<PathBuf>, anyhow::Error>(matched_rcs.clone()))
         .unwrap();
     let rcs_state = assert_matches!(match_rcs, MatchRcs::Rcs(rcs_state) => rcs_state);
-    let get_env_state_var = rcs_state.get_env_state_var(ENV_STATE_VAR_KEY);
+    let get_env_state_var = rcs_state
+        .get_env_state_var(&EnvStateStore::new(ENV_STATE_VAR_KEY))
+        .unwrap();
     let env_state_var_state = assert_matches!(get_env_state_var, GetEnvStateVar::EnvStateVar(env_state_var_state) => env_state_var_state);
 
     let reset_phase_ran = Cell::new(false);
@@ -103,7 +113,7 @@ fn workflow_rcs_found_existing_environment_resets_and_updates_state() {
                 setup_phase_ran.set(true);
                 *new_rcs_paths.borrow_mut() = rcs;
                 *old_state.borrow_mut() = env_state_var_value.to_string_lossy().to_string();
-                Ok(())
+                Ok::<(), anyhow::Error>(())
             },
         )
         .unwrap();
@@ -117,3 +127,39 @@ fn workflow_rcs_found_existing_environment_resets_and_updates_state() {
         std::env::remove_var(ENV_STATE_VAR_KEY);
     }
 }
+
+#[test]
+fn workflow_joins_chunked_env_state_var() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_CHUNKED_ENV_STATE_VAR_KEY";
+
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, "chunked:2");
+        env::set_var(format!("{ENV_STATE_VAR_KEY}_0"), "old_environment");
+        env::set_var(format!("{ENV_STATE_VAR_KEY}_1"), "_state");
+    }
+
+    let current_dir_state = state::ShellPromptState::get_current_dir(None).unwrap();
+    let match_rcs = current_dir_state
+        .match_rcs(|_| Ok::<Vec<String>, anyhow::Error>(vec![]))
+        .unwrap();
+    let no_rcs_state = assert_matches!(match_rcs, MatchRcs::NoRcs(no_rcs_state) => no_rcs_state);
+    let ready_for_reset = no_rcs_state
+        .get_env_state_var(&EnvStateStore::new(ENV_STATE_VAR_KEY))
+        .unwrap()
+        .unwrap();
+
+    let reset_value = RefCell::new(String::new());
+    ready_for_reset
+        .reset_env_vars(|value| {
+            *reset_value.borrow_mut() = value.to_string_lossy().to_string();
+            Ok::<(), anyhow::Error>(())
+        })
+        .unwrap();
+    assert_eq!(reset_value.borrow().as_str(), "old_environment_state");
+
+    unsafe {
+        env::remove_var(ENV_STATE_VAR_KEY);
+        env::remove_var(format!("{ENV_STATE_VAR_KEY}_0"));
+        env::remove_var(format!("{ENV_STATE_VAR_KEY}_1"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    env,
+    fs::{self, File},
+    process::{self, Command},
+    time::{Duration, SystemTime},
+};
+
+use assert_matches::assert_matches;
+use bstr::{B, BString};
+use env_hooks::{
+    EnvVars, Error,
+    store::{EnvStateStore, FileStateStore, StateStore},
+};
+
+fn token_file_name(value: &BString) -> String {
+    String::from_utf8(value.strip_prefix(B("file:")).unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn env_state_store_keeps_state_in_env_var() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_ENV_STORE_STATE_VAR_KEY";
+    let store = EnvStateStore::new(ENV_STATE_VAR_KEY);
+
+    let env_vars_state = store.save("state", &EnvVars::new()).unwrap();
+    assert_eq!(
+        env_vars_state.get(B(ENV_STATE_VAR_KEY)),
+        Some(&Some(BString::from("state")))
+    );
+
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, "state");
+    }
+    assert_eq!(store.load().unwrap().unwrap(), "state");
+    unsafe {
+        env::remove_var(ENV_STATE_VAR_KEY);
+    }
+    assert_eq!(store.load().unwrap(), None);
+}
+
+#[test]
+fn file_state_store_keeps_only_a_token_in_env_var() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_FILE_STORE_STATE_VAR_KEY";
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "1234-5678").unwrap();
+
+    let env_vars_state = store.save("state", &EnvVars::new()).unwrap();
+    let token = env_vars_state
+        .get(B(ENV_STATE_VAR_KEY))
+        .cloned()
+        .flatten()
+        .unwrap();
+    let file_name = token_file_name(&token);
+    assert!(file_name.starts_with("1234-5678."));
+    assert_eq!(
+        fs::read_to_string(dir.path().join(&file_name)).unwrap(),
+        "state"
+    );
+
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, token.to_string());
+    }
+    assert_eq!(store.load().unwrap().unwrap(), "state");
+    unsafe {
+        env::remove_var(ENV_STATE_VAR_KEY);
+    }
+
+    assert_eq!(
+        store.clear(&EnvVars::new()).get(B(ENV_STATE_VAR_KEY)),
+        Some(&None)
+    );
+}
+
+#[test]
+fn file_state_store_removes_stale_files_of_its_session_only() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_FILE_STORE_STALE_STATE_VAR_KEY";
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "session").unwrap();
+    let other_store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "other").unwrap();
+
+    let other_token = other_store.save("other", &EnvVars::new()).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+    let first_token = store.save("first", &EnvVars::new()).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+    let current_env_vars = EnvVars::from_iter([(BString::from(ENV_STATE_VAR_KEY), first_token)]);
+    let second_token = store.save("second", &current_env_vars).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+    let current_env_vars = EnvVars::from_iter([(BString::from(ENV_STATE_VAR_KEY), second_token)]);
+    let third_token = store.save("third", &current_env_vars).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+
+    let mut file_names = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    file_names.sort();
+    let mut expected_file_names = [
+        &other_token,
+        &current_env_vars[B(ENV_STATE_VAR_KEY)],
+        &third_token,
+    ]
+    .map(token_file_name);
+    expected_file_names.sort();
+    assert_eq!(file_names, expected_file_names);
+}
+
+#[test]
+fn file_state_store_reads_values_that_arent_tokens_as_state() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_FILE_STORE_INLINE_STATE_VAR_KEY";
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "session").unwrap();
+
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, "ehs1:1:zstd:00000000:state");
+    }
+    assert_eq!(store.load().unwrap().unwrap(), "ehs1:1:zstd:00000000:state");
+
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, "file:session.0badf00d");
+    }
+    assert_matches!(store.load(), Err(Error::StateFileNotFound { path }) => {
+        assert_eq!(path, dir.path().join("session.0badf00d"));
+    });
+
+    unsafe {
+        env::remove_var(ENV_STATE_VAR_KEY);
+    }
+}
+
+#[test]
+fn file_state_store_rejects_session_ids_that_arent_file_names() {
+    for session_id in ["", "../escape", "with space", "dot.ted"] {
+        assert_matches!(
+            FileStateStore::new("KEY", "/tmp", session_id),
+            Err(Error::InvalidSessionId { .. })
+        );
+    }
+}
+
+#[test]
+fn file_state_store_keeps_state_of_shells_started_from_another() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_FILE_STORE_INHERITED_STATE_VAR_KEY";
+    let dir = tempfile::tempdir().unwrap();
+    let parent_store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "parent").unwrap();
+    let child_store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "child").unwrap();
+
+    let first_token = parent_store.save("first", &EnvVars::new()).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+    unsafe {
+        env::set_var(ENV_STATE_VAR_KEY, first_token.to_string());
+    }
+    assert_eq!(child_store.load().unwrap().unwrap(), "first");
+
+    // NB: The parent moving on removes the file its token pointed at, which the child inherited
+    let current_env_vars =
+        EnvVars::from_iter([(BString::from(ENV_STATE_VAR_KEY), first_token.clone())]);
+    let second_token = parent_store.save("second", &current_env_vars).unwrap()
+        [B(ENV_STATE_VAR_KEY)]
+    .clone()
+    .unwrap();
+    let current_env_vars = EnvVars::from_iter([(BString::from(ENV_STATE_VAR_KEY), second_token)]);
+    parent_store.save("third", &current_env_vars).unwrap();
+    assert!(!dir.path().join(token_file_name(&first_token)).exists());
+    assert_eq!(child_store.load().unwrap().unwrap(), "first");
+
+    unsafe {
+        env::remove_var(ENV_STATE_VAR_KEY);
+    }
+}
+
+#[test]
+fn file_state_store_removes_files_of_ended_sessions() {
+    const ENV_STATE_VAR_KEY: &str = "TEST_FILE_STORE_ENDED_STATE_VAR_KEY";
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileStateStore::new(ENV_STATE_VAR_KEY, dir.path(), "session").unwrap();
+
+    let mut exited = Command::new("true").spawn().unwrap();
+    exited.wait().unwrap();
+    let running_file_name = format!("{}-1.0badf00d", process::id());
+    let exited_file_name = format!("{}-1.0badf00d", exited.id());
+    let over_a_week_ago = SystemTime::now() - Duration::from_secs(8 * 24 * 60 * 60);
+    for file_name in [
+        &running_file_name,
+        &exited_file_name,
+        "old.0badf00d",
+        "new.0badf00d",
+    ] {
+        let file = File::create(dir.path().join(file_name)).unwrap();
+        if file_name.starts_with("old.") {
+            file.set_modified(over_a_week_ago).unwrap();
+        }
+    }
+    let token = store.save("state", &EnvVars::new()).unwrap()[B(ENV_STATE_VAR_KEY)]
+        .clone()
+        .unwrap();
+
+    let mut file_names = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    file_names.sort();
+    let mut expected_file_names = [
+        running_file_name,
+        "new.0badf00d".to_string(),
+        token_file_name(&token),
+    ];
+    expected_file_names.sort();
+    assert_eq!(file_names, expected_file_names);
+}
@@ -1,11 +1,17 @@
 use std::env;
 use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
 use std::{collections::HashSet, fs};
 
+use bstr::{B, BString};
+
 use env_hooks::{
-    BashSource, EnvVars, EnvVarsState, get_env_vars_from_bash, get_env_vars_from_current_process,
-    get_env_vars_reset, get_old_env_vars_to_be_updated, merge_delimited_env_var,
-    remove_ignored_env_vars,
+    BashLimits, BashSource, DelimitedEnvVar, DelimitedEnvVars, EnvVars, EnvVarsFilter,
+    EnvVarsState, MergeStrategy, get_bash_env, get_bash_env_within_limits,
+    get_env_vars_and_shell_definitions_from_bash, get_env_vars_from_bash,
+    get_env_vars_from_current_process, get_env_vars_reset, get_env_vars_restore,
+    get_old_env_vars_to_be_updated, merge_delimited_env_var, merge_delimited_env_vars,
+    remove_filtered_env_vars, remove_ignored_env_vars,
 };
 
 #[test]
@@ -61,7 +67,7 @@ fn get_env_vars_reset_returns_state_to_old_env_vars() {
         ("VAR2".to_string(), "old2".to_string()),
     ]);
 
-    let new_vars = HashSet::from_iter(["VAR1".to_string(), "VAR3".to_string()]);
+    let new_vars = HashSet::from_iter([BString::from("VAR1"), BString::from("VAR3")]);
 
     let result = get_env_vars_reset(old_env_vars, new_vars, "STATE_VAR".to_string());
 
@@ -75,12 +81,53 @@ fn get_env_vars_reset_returns_state_to_old_env_vars() {
     );
 }
 
+#[test]
+fn get_env_vars_restore_keeps_changes_made_since_setting() {
+    let env_vars_reset = EnvVarsState::from_iter([
+        ("UNCHANGED", Some("old")),
+        ("CHANGED", None),
+        ("UNSET", Some("old")),
+        ("PATH", Some("/usr/bin")),
+        ("STATE_VAR", None),
+    ]);
+    let env_vars_set = EnvVars::from_iter([
+        ("UNCHANGED", "new"),
+        ("CHANGED", "new"),
+        ("UNSET", "new"),
+        ("PATH", "/nix/store/a/bin:/usr/bin"),
+    ]);
+    let current_env_vars = EnvVars::from_iter([
+        ("UNCHANGED", "new"),
+        ("CHANGED", "changed by hand"),
+        ("PATH", "/home/user/bin:/nix/store/a/bin:/usr/bin"),
+        ("STATE_VAR", "state"),
+    ]);
+    let delimited_env_vars =
+        DelimitedEnvVars::from_iter([("PATH".to_string(), DelimitedEnvVar::default())]);
+
+    let result = get_env_vars_restore(
+        env_vars_reset,
+        &env_vars_set,
+        &current_env_vars,
+        &delimited_env_vars,
+    );
+
+    assert_eq!(
+        result,
+        EnvVarsState::from_iter([
+            ("UNCHANGED", Some("old")),
+            ("PATH", Some("/home/user/bin:/usr/bin")),
+            ("STATE_VAR", None),
+        ])
+    );
+}
+
 #[test]
 fn get_env_vars_from_current_process_returns_current_env() {
     let result = get_env_vars_from_current_process();
-    let path = env::var("PATH").ok();
+    let path = env::var("PATH").ok().map(BString::from);
     assert!(path.is_some());
-    assert_eq!(result.get("PATH"), path.as_ref());
+    assert_eq!(result.get(B("PATH")), path.as_ref());
 }
 
 #[test]
@@ -110,6 +157,65 @@ fn merge_delimited_values_combines_paths_and_preserves_order_with_new_paths_in_f
     );
 }
 
+#[test]
+fn merge_delimited_env_vars_uses_each_strategy() {
+    let delimited_env_var = |separator: &str, strategy| DelimitedEnvVar {
+        separator: separator.to_string(),
+        strategy,
+    };
+    let delimited_env_vars = DelimitedEnvVars::from_iter([
+        (
+            "PREPEND".to_string(),
+            delimited_env_var(":", MergeStrategy::Prepend),
+        ),
+        (
+            "APPEND".to_string(),
+            delimited_env_var(":", MergeStrategy::Append),
+        ),
+        (
+            "REPLACE".to_string(),
+            delimited_env_var(":", MergeStrategy::Replace),
+        ),
+        (
+            "DEDUPE".to_string(),
+            delimited_env_var(" ", MergeStrategy::Dedupe),
+        ),
+        (
+            "NOT_SET".to_string(),
+            delimited_env_var(":", MergeStrategy::Prepend),
+        ),
+    ]);
+    let old_env_vars = EnvVars::from_iter([
+        ("PREPEND", "/old:/both"),
+        ("APPEND", "/old:/both"),
+        ("REPLACE", "/old:/both"),
+        ("DEDUPE", "-L/old -L/both"),
+        ("UNLISTED", "/old"),
+    ]);
+    let mut new_env_vars = EnvVars::from_iter([
+        ("PREPEND", "/new:/both"),
+        ("APPEND", "/new:/both"),
+        ("REPLACE", "/new:/both"),
+        ("DEDUPE", "-L/new -L/both"),
+        ("UNLISTED", "/new"),
+        ("NOT_SET", "/new"),
+    ]);
+
+    merge_delimited_env_vars(&delimited_env_vars, &old_env_vars, &mut new_env_vars);
+
+    assert_eq!(
+        new_env_vars,
+        EnvVars::from_iter([
+            ("PREPEND", "/new:/both:/old:/both"),
+            ("APPEND", "/old:/both:/new:/both"),
+            ("REPLACE", "/new:/both"),
+            ("DEDUPE", "-L/new -L/both -L/old"),
+            ("UNLISTED", "/new"),
+            ("NOT_SET", "/new"),
+        ])
+    );
+}
+
 #[test]
 fn env_vars_into_env_vars_state_conversion() {
     assert_eq!(
@@ -148,11 +254,162 @@ fn test_getting_env_vars_from_bash() {
     .unwrap();
     remove_ignored_env_vars(&mut new_env_vars);
 
-    assert_eq!(new_env_vars.get("PATH").unwrap(), &old_path);
-    new_env_vars.shift_remove("PATH");
+    assert_eq!(new_env_vars.get(B("PATH")).unwrap(), &old_path);
+    new_env_vars.shift_remove(B("PATH"));
 
     assert_eq!(
         new_env_vars,
         EnvVars::from_iter([(String::from("TEST_VAR"), String::from("true"))])
     );
 }
+
+#[test]
+fn test_getting_non_utf8_env_vars_from_bash() {
+    let mut new_env_vars = get_env_vars_from_bash(
+        BashSource::Script(BString::from(r#"export LATIN1_VAR=$'/opt/caf\xe9/lib'"#)),
+        None,
+    )
+    .unwrap();
+    remove_ignored_env_vars(&mut new_env_vars);
+
+    assert_eq!(
+        new_env_vars.get(B("LATIN1_VAR")),
+        Some(&BString::from(B(b"/opt/caf\xe9/lib")))
+    );
+}
+
+#[test]
+fn env_vars_serialize_non_utf8_values_as_bytes() {
+    let env_vars_state = EnvVarsState::from_iter([
+        ("VAR1", Some(BString::from("value1"))),
+        ("VAR2", Some(BString::from(B(b"caf\xe9")))),
+        ("VAR3", None),
+    ]);
+
+    let json = serde_json::to_string(&env_vars_state).unwrap();
+    assert_eq!(
+        json,
+        r#"{"VAR1":"value1","VAR2":[99,97,102,233],"VAR3":null}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<EnvVarsState>(&json).unwrap(),
+        env_vars_state
+    );
+}
+
+#[test]
+fn env_vars_serialize_non_utf8_keys_as_base64() {
+    let env_vars_state = EnvVarsState::from_iter([
+        (BString::from("VAR1"), Some(BString::from("value1"))),
+        (
+            BString::from(B(b"caf\xe9")),
+            Some(BString::from(B(b"caf\xe9"))),
+        ),
+        (BString::from(B(b"\xff")), None),
+    ]);
+
+    let json = serde_json::to_string(&env_vars_state).unwrap();
+    assert_eq!(
+        json,
+        r#"{"VAR1":"value1","=base64:Y2Fm6Q==":[99,97,102,233],"=base64:/w==":null}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<EnvVarsState>(&json).unwrap(),
+        env_vars_state
+    );
+
+    let env_vars = EnvVars::from_iter([(B(b"caf\xe9"), "value")]);
+    assert_eq!(
+        serde_json::from_str::<EnvVars>(&serde_json::to_string(&env_vars).unwrap()).unwrap(),
+        env_vars
+    );
+}
+
+#[test]
+fn remove_filtered_env_vars_applies_filter() {
+    let env_vars = EnvVars::from_iter([
+        ("FOO", "1"),
+        ("FOO_BAR", "2"),
+        ("TERM", "xterm"),
+        ("SSH_AUTH_SOCK", "/tmp/agent"),
+        ("SHLVL", "2"),
+    ]);
+
+    let mut ignored_env_vars = env_vars.clone();
+    remove_filtered_env_vars(
+        &mut ignored_env_vars,
+        &EnvVarsFilter::new()
+            .with_ignored(["TERM", "SSH_.*"])
+            .unwrap(),
+    );
+    assert_eq!(
+        ignored_env_vars,
+        EnvVars::from_iter([("FOO", "1"), ("FOO_BAR", "2")])
+    );
+
+    // NB: Patterns match the whole name, and the built-in list still applies
+    let mut allowed_env_vars = env_vars.clone();
+    remove_filtered_env_vars(
+        &mut allowed_env_vars,
+        &EnvVarsFilter::new().with_allowed(["FOO", "SHLVL"]).unwrap(),
+    );
+    assert_eq!(allowed_env_vars, EnvVars::from_iter([("FOO", "1")]));
+}
+
+#[test]
+fn test_getting_shell_definitions_from_bash() {
+    let (env_vars, shell_definitions) = get_env_vars_and_shell_definitions_from_bash(
+        BashSource::Script(BString::from(
+            r#"export TEST_VAR=true; greet() { echo "hello $1"; }; alias ll='ls -l'"#,
+        )),
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(env_vars.get(B("TEST_VAR")), Some(&BString::from("true")));
+    assert_eq!(
+        shell_definitions.functions.get("greet").map(String::as_str),
+        Some("greet () \n{ \n    echo \"hello $1\"\n}\n")
+    );
+    assert_eq!(
+        shell_definitions.aliases.get("ll").map(String::as_str),
+        Some("ls -l")
+    );
+}
+
+#[test]
+fn test_getting_output_from_bash() {
+    let bash_env = get_bash_env(
+        BashSource::Script(BString::from(
+            "echo 'welcome'; echo 'warning' >&2; export TEST_VAR=true",
+        )),
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(
+        bash_env.env_vars.get(B("TEST_VAR")),
+        Some(&BString::from("true"))
+    );
+    assert_eq!(bash_env.output, BString::from("welcome\nwarning\n"));
+}
+
+#[test]
+fn test_getting_env_vars_from_bash_stops_at_deadline() {
+    let started_at = Instant::now();
+    let result = get_bash_env_within_limits(
+        BashSource::Script(BString::from("sleep 30 & sleep 30; export TEST_VAR=true")),
+        None,
+        BashLimits {
+            deadline: Some(started_at + Duration::from_millis(200)),
+            cancelled: None,
+        },
+    );
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Bash command to retrieve env vars timed out"
+    );
+    // NB: The background `sleep` holds on to the output, so this only returns once it's stopped too
+    assert!(started_at.elapsed() < Duration::from_secs(10));
+}
@@ -0,0 +1,88 @@
+use std::{io, path::PathBuf, process::ExitStatus};
+
+use semver::{Version, VersionReq};
+
+/// Everything that can go wrong while checking Nix or building and caching a profile.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The Nix executable isn't installed, or isn't on `PATH`.
+    #[error("`{program}` could not be found, is Nix installed?")]
+    NixNotFound { program: String },
+
+    /// The installed Nix doesn't support flakes.
+    #[error("`nix` version {found} too old for flakes, {required} is required.")]
+    NixTooOld {
+        found: Version,
+        required: VersionReq,
+    },
+
+    /// `nix --version` didn't print a version.
+    #[error("SemVer from `nix --version` could not be found.")]
+    NixVersionUnknown { output: String },
+
+    /// A Nix command exited unsuccessfully, e.g. because the flake doesn't evaluate.
+    #[error(
+        "`{command}` failed with error:\nprocess exited unsuccessfully: {status}{}",
+        with_leading_newline(stderr)
+    )]
+    EvalFailed {
+        command: String,
+        status: ExitStatus,
+        /// Errors Nix logged, along with anything else it printed.
+        stderr: String,
+    },
+
+    /// A Nix command exited unsuccessfully because a flake, one of its inputs or a store path
+    /// couldn't be fetched.
+    #[error(
+        "`{command}` failed with error:\nprocess exited unsuccessfully: {status}{}",
+        with_leading_newline(stderr)
+    )]
+    FetchFailed {
+        command: String,
+        status: ExitStatus,
+        /// Errors Nix logged, along with anything else it printed.
+        stderr: String,
+    },
+
+    /// A Nix command didn't finish before the deadline.
+    #[error("`{command}` failed with error:\nprocess timed out")]
+    Timeout { command: String },
+
+    /// A Nix command was stopped because it was cancelled.
+    #[error("`{command}` failed with error:\nprocess was cancelled")]
+    Cancelled { command: String },
+
+    /// The flake reference couldn't be parsed or expanded.
+    #[error("Invalid flake reference `{flake_reference}`: {reason}")]
+    InvalidFlakeReference {
+        flake_reference: String,
+        reason: String,
+    },
+
+    /// A local flake reference points to a directory without a flake.
+    #[error("No flake.nix, flake.lock or devshell.toml found in `{}`", flake_dir.display())]
+    FlakeNotFound { flake_dir: PathBuf },
+
+    #[error("Unknown gcroots mode `{value}`, expected `none`, `profile` or `profile+inputs`")]
+    InvalidGcRootsMode { value: String },
+
+    /// A Nix command printed something that couldn't be understood.
+    #[error("Unexpected `{command}` output")]
+    UnexpectedOutput { command: String },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+fn with_leading_newline(message: &str) -> String {
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!("\n{message}")
+    }
+}
@@ -1,6 +1,12 @@
+mod error;
+mod nix_cache_key;
 mod nix_command;
 mod nix_profile_cache;
+mod nix_progress;
 mod nix_version_check;
 
+pub use error::Error;
+pub use nix_cache_key::*;
 pub use nix_profile_cache::*;
+pub use nix_progress::{NixProgress, NixProgressReporter};
 pub use nix_version_check::*;
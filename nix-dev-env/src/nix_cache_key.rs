@@ -0,0 +1,284 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Error, EvaluationMode, NixProfileCacheMetadata, has_cached_profile_files,
+    nix_command::{self, NixCommandOptions},
+};
+
+/// Bumped whenever the fields of [`NixCacheKey`] or the way they're hashed changes, so that
+/// profiles cached under an older scheme are never reused.
+pub const NIX_CACHE_KEY_VERSION: u32 = 1;
+
+/// Everything that affects the result of evaluating a flake's dev shell.
+///
+/// Cached profiles are stored in a directory named after the digest of this key, so changing any
+/// of these inputs results in a fresh profile being built instead of reusing an incompatible one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NixCacheKey {
+    pub version: u32,
+    pub flake_reference: String,
+    pub evaluation_mode: EvaluationMode,
+    /// The Nix system double, e.g. `x86_64-linux`.
+    pub system: String,
+    pub nix_version: String,
+    /// Extra arguments passed to Nix when evaluating the flake.
+    pub extra_args: Vec<String>,
+    /// Version of the tool that built the profile.
+    pub tool_version: String,
+}
+
+impl NixCacheKey {
+    pub fn new(
+        flake_reference: &str,
+        evaluation_mode: EvaluationMode,
+        system: &str,
+        nix_version: &Version,
+        tool_version: &str,
+    ) -> Self {
+        Self {
+            version: NIX_CACHE_KEY_VERSION,
+            flake_reference: String::from(flake_reference),
+            evaluation_mode,
+            system: String::from(system),
+            nix_version: nix_version.to_string(),
+            extra_args: vec![],
+            tool_version: String::from(tool_version),
+        }
+    }
+
+    pub fn with_extra_args(mut self, extra_args: Vec<String>) -> Self {
+        self.extra_args = extra_args;
+        self
+    }
+
+    /// Hex encoded SHA-256 digest of the key, used as the name of the cache directory.
+    pub fn digest(&self) -> Result<String, Error> {
+        Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(self)?)))
+    }
+}
+
+/// Whether the name is one cache directories get: the [`NixCacheKey::digest`], or the SHA-1 of only
+/// the flake reference they were named with before.
+pub fn is_cache_dir_name(name: &str) -> bool {
+    (name.len() == 64 || name.len() == 40) && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Removes cache directories that can never be used again: those named with the SHA-1 of only the
+/// flake reference, those created with an older version of [`NixCacheKey`], and those built for
+/// the same flake and arguments as `cache_key` but by another version of Nix or of the tool, or for
+/// another system.
+///
+/// Returns the directories that were removed.
+pub fn remove_stale_cache_dirs(
+    cache_dir: &Path,
+    cache_key: &NixCacheKey,
+) -> Result<Vec<PathBuf>, Error> {
+    let read_dir = match fs::read_dir(cache_dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = vec![];
+    for dir_entry in read_dir.filter_map(Result::ok) {
+        if !dir_entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let dir = dir_entry.path();
+        // NB: Only directories with the files profiles are cached in get removed, as the cache dir
+        // may be shared with other data
+        let is_sha1_dir = dir_entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.len() == 40 && is_cache_dir_name(name))
+            && has_cached_profile_files(&dir);
+        // NB: Profiles of the same flake with other extra arguments, e.g. from another entry, are
+        // still in use
+        let is_outdated = NixProfileCacheMetadata::find(&dir)
+            .ok()
+            .flatten()
+            .and_then(|metadata| metadata.cache_key)
+            .is_some_and(|dir_cache_key| {
+                dir_cache_key.version < NIX_CACHE_KEY_VERSION
+                    || (dir_cache_key.flake_reference == cache_key.flake_reference
+                        && dir_cache_key.evaluation_mode == cache_key.evaluation_mode
+                        && dir_cache_key.extra_args == cache_key.extra_args
+                        && &dir_cache_key != cache_key)
+            });
+        if is_sha1_dir || is_outdated {
+            fs::remove_dir_all(&dir)?;
+            removed.push(dir);
+        }
+    }
+    Ok(removed)
+}
+
+/// The system Nix builds for, e.g. `x86_64-linux`, as `builtins.currentSystem` would return.
+///
+/// It's read from the Nix configuration rather than derived from the host, as it differs under
+/// Rosetta or when Nix is configured to build for another system.
+pub fn get_nix_system(nix_version: &Version) -> Result<String, Error> {
+    // NB: `nix config show` was added in Nix 2.20, and `nix show-config` is deprecated since
+    if *nix_version >= Version::new(2, 20, 0) {
+        let stdout_content =
+            nix_command::nix(["config", "show", "system"], &NixCommandOptions::default())?;
+        let system = stdout_content.trim();
+        if !system.is_empty() {
+            return Ok(String::from(system));
+        }
+    } else {
+        let stdout_content =
+            nix_command::nix(["show-config", "--json"], &NixCommandOptions::default())?;
+        let json = serde_json::from_str::<Value>(&stdout_content)?;
+        if let Some(system) = json.pointer("/system/value").and_then(Value::as_str) {
+            return Ok(String::from(system));
+        }
+    }
+    Err(Error::UnexpectedOutput {
+        command: String::from("nix config show system"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+
+    use semver::Version;
+
+    use super::{NixCacheKey, remove_stale_cache_dirs};
+    use crate::{EvaluationMode, GcRootsMode, NixProfileCacheMetadata};
+
+    fn cache_key(evaluation_mode: EvaluationMode, nix_version: &str) -> NixCacheKey {
+        NixCacheKey::new(
+            "github:owner/repo",
+            evaluation_mode,
+            "x86_64-linux",
+            &Version::parse(nix_version).unwrap(),
+            "0.1.4",
+        )
+    }
+
+    fn write_cached_profile(cache_dir: &Path, cache_key: &NixCacheKey) -> [PathBuf; 2] {
+        let dir = cache_dir.join(cache_key.digest().unwrap());
+        fs::create_dir_all(&dir).unwrap();
+        let gcroot = dir.join("flake-profile-0000");
+        symlink("/nix/store/profile", &gcroot).unwrap();
+        NixProfileCacheMetadata {
+            flake_reference: cache_key.flake_reference.clone(),
+            evaluation_mode: cache_key.evaluation_mode,
+            gcroots_mode: GcRootsMode::Profile,
+            cache_key: Some(cache_key.clone()),
+            gcroots: vec![gcroot.clone()],
+            built_at: SystemTime::now(),
+            last_used_at: SystemTime::now(),
+        }
+        .write(&gcroot.with_extension("json"))
+        .unwrap();
+        [dir, gcroot]
+    }
+
+    #[test]
+    fn test_digest_is_stable() {
+        let digest = cache_key(EvaluationMode::Pure, "2.30.0").digest().unwrap();
+        assert_eq!(digest.len(), 64);
+        assert_eq!(
+            digest,
+            cache_key(EvaluationMode::Pure, "2.30.0").digest().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_digest_covers_all_inputs() {
+        let digest = cache_key(EvaluationMode::Pure, "2.30.0").digest().unwrap();
+        assert_ne!(
+            digest,
+            cache_key(EvaluationMode::Impure, "2.30.0")
+                .digest()
+                .unwrap()
+        );
+        assert_ne!(
+            digest,
+            cache_key(EvaluationMode::Pure, "2.31.0").digest().unwrap()
+        );
+        assert_ne!(
+            digest,
+            cache_key(EvaluationMode::Pure, "2.30.0")
+                .with_extra_args(vec![String::from("--refresh")])
+                .digest()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_remove_stale_cache_dirs() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let sha1_dir = cache_dir
+            .path()
+            .join("da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        let unrelated_sha1_dir = cache_dir
+            .path()
+            .join("356a192b7913b04c54574d18c28d46e6395428ab");
+        let current_dir = cache_dir
+            .path()
+            .join(cache_key(EvaluationMode::Pure, "2.30.0").digest().unwrap());
+        fs::create_dir_all(&sha1_dir).unwrap();
+        fs::write(sha1_dir.join("flake-profile-0000.rc"), "").unwrap();
+        fs::create_dir_all(&unrelated_sha1_dir).unwrap();
+        fs::write(unrelated_sha1_dir.join("data"), "").unwrap();
+        fs::create_dir_all(&current_dir).unwrap();
+
+        assert_eq!(
+            remove_stale_cache_dirs(cache_dir.path(), &cache_key(EvaluationMode::Pure, "2.30.0"))
+                .unwrap(),
+            vec![sha1_dir.clone()]
+        );
+        assert!(!sha1_dir.exists());
+        assert!(unrelated_sha1_dir.exists());
+        assert!(current_dir.exists());
+    }
+
+    #[test]
+    fn test_remove_stale_cache_dirs_built_by_other_versions() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let old_cache_key = cache_key(EvaluationMode::Pure, "2.30.0");
+        let new_cache_key = NixCacheKey {
+            tool_version: String::from("0.1.5"),
+            ..old_cache_key.clone()
+        };
+        let [old_dir, old_gcroot] = write_cached_profile(cache_dir.path(), &old_cache_key);
+        let [new_dir, _] = write_cached_profile(cache_dir.path(), &new_cache_key);
+        // NB: Other modes and arguments of the same flake are left alone
+        let [impure_dir, _] = write_cached_profile(
+            cache_dir.path(),
+            &cache_key(EvaluationMode::Impure, "2.30.0"),
+        );
+        let [refresh_dir, _] = write_cached_profile(
+            cache_dir.path(),
+            &old_cache_key
+                .clone()
+                .with_extra_args(vec![String::from("--refresh")]),
+        );
+
+        assert_eq!(
+            remove_stale_cache_dirs(cache_dir.path(), &new_cache_key).unwrap(),
+            vec![old_dir.clone()]
+        );
+        assert!(!old_dir.exists());
+        assert!(!old_gcroot.is_symlink());
+        assert!(new_dir.exists());
+        assert!(impure_dir.exists());
+        assert!(refresh_dir.exists());
+    }
+}
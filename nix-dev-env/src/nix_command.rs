@@ -1,68 +1,263 @@
 use std::{
     ffi::OsStr,
+    io::{self, BufRead, BufReader, Read},
     num,
-    process::{Command, ExitStatus, Stdio},
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use bstr::BString;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use shell_quote::Sh;
 
+use crate::{
+    Error,
+    nix_progress::{NixProgress, NixProgressParser, NixProgressReporter},
+};
+
 pub(crate) trait SimplifiedExitOk {
-    fn simplified_exit_ok(&self) -> anyhow::Result<()>;
+    fn simplified_exit_ok(&self) -> Result<(), ExitStatus>;
 }
 
 impl SimplifiedExitOk for ExitStatus {
     /// Simplified implementation of <https://github.com/rust-lang/rust/issues/84908>
     // TODO: Remove this and use `exit_ok` when it's stabilized.
-    fn simplified_exit_ok(&self) -> anyhow::Result<()> {
+    fn simplified_exit_ok(&self) -> Result<(), ExitStatus> {
         match num::NonZero::try_from(self.code().unwrap_or(-1)) {
-            Ok(_) => Err(anyhow::format_err!(
-                "process exited unsuccessfully: {}",
-                &self
-            )),
+            Ok(_) => Err(*self),
             Err(_) => Ok(()),
         }
     }
 }
 
-pub(crate) fn nix(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> anyhow::Result<String> {
-    nix_program("nix", args)
+/// Errors Nix logs when a flake, one of its inputs or a store path can't be fetched.
+static FETCH_ERROR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)unable to download|failed to fetch|cannot fetch|could not resolve host")
+        .unwrap()
+});
+
+/// How long to wait between checks on whether a running command should be stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a stopped command gets to exit after `SIGTERM`, before it's sent `SIGKILL`.
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often progress gets reported while it isn't changing, so that the time spent can be shown.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a Nix command is run.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NixCommandOptions {
+    /// The time by which the command has to have finished.
+    pub(crate) deadline: Option<Instant>,
+    /// Set, e.g. by a signal handler, once the command should be cancelled.
+    pub(crate) cancelled: Option<Arc<AtomicBool>>,
+    /// Gets the command's progress, parsed from its `--log-format internal-json` logs.
+    pub(crate) progress_reporter: Option<NixProgressReporter>,
+}
+
+pub(crate) fn nix(
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    options: &NixCommandOptions,
+) -> Result<String, Error> {
+    nix_program("nix", args, options)
 }
 
+/// Runs the program in its own process group, so that stopping it also stops everything it
+/// spawned, e.g. builders and substituters.
 pub(crate) fn nix_program(
     program: impl AsRef<OsStr>,
     args: impl IntoIterator<Item = impl AsRef<OsStr>>,
-) -> anyhow::Result<String> {
+    options: &NixCommandOptions,
+) -> Result<String, Error> {
     let mut command = Command::new(program.as_ref());
+    command.args(["--extra-experimental-features", "nix-command flakes"]);
+    if options.progress_reporter.is_some() {
+        command.args(["--log-format", "internal-json"]);
+    }
     command
-        .args(["--extra-experimental-features", "nix-command flakes"])
         .args(args)
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-    let output = command.output()?;
-    output.status.simplified_exit_ok().map_err(|err| {
-        anyhow::format_err!(
-            "`{} {}` failed with error:\n{}",
-            BString::new(Sh::quote_vec(command.get_program())),
-            BString::new(bstr::join(
-                " ",
-                command
-                    .get_args()
-                    .map(|arg| { BString::new(Sh::quote_vec(arg)) })
-                    .collect::<Vec<_>>()
-            )),
-            err
-        )
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let command_string = format!(
+        "{} {}",
+        BString::new(Sh::quote_vec(command.get_program())),
+        BString::new(bstr::join(
+            " ",
+            command
+                .get_args()
+                .map(|arg| { BString::new(Sh::quote_vec(arg)) })
+                .collect::<Vec<_>>()
+        )),
+    );
+    let mut child = command.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::NixNotFound {
+            program: program.as_ref().to_string_lossy().into_owned(),
+        },
+        _ => Error::Io(e),
     })?;
-    let stdout_content = String::from_utf8_lossy(&output.stdout).to_string();
+    let stdout = read_to_end_in_background(child.stdout.take());
+    let progress_parser = Arc::new(Mutex::new(NixProgressParser::default()));
+    let stderr = parse_lines_in_background(child.stderr.take(), Arc::clone(&progress_parser));
+    let status = wait_with_options(&mut child, options, &progress_parser);
+    let stdout = stdout
+        .join()
+        .map_err(|_| io::Error::other("reading stdout panicked"))??;
+    stderr
+        .join()
+        .map_err(|_| io::Error::other("reading stderr panicked"))??;
+    let error_messages = progress_parser
+        .lock()
+        .map_err(|_| io::Error::other("parsing stderr panicked"))?
+        .error_messages();
+    let status = match status? {
+        WaitOutcome::Exited(status) => status,
+        WaitOutcome::TimedOut => {
+            return Err(Error::Timeout {
+                command: command_string,
+            });
+        }
+        WaitOutcome::Cancelled => {
+            return Err(Error::Cancelled {
+                command: command_string,
+            });
+        }
+    };
+    if let Err(status) = status.simplified_exit_ok() {
+        return Err(if FETCH_ERROR_RE.is_match(&error_messages) {
+            Error::FetchFailed {
+                command: command_string,
+                status,
+                stderr: error_messages,
+            }
+        } else {
+            Error::EvalFailed {
+                command: command_string,
+                status,
+                stderr: error_messages,
+            }
+        });
+    }
+    let stdout_content = String::from_utf8_lossy(&stdout).to_string();
     Ok(stdout_content)
 }
 
+// NB: Both pipes have to be drained while waiting, or the command blocks once either one fills up
+fn read_to_end_in_background(
+    reader: Option<impl Read + Send + 'static>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some(mut reader) = reader {
+            reader.read_to_end(&mut bytes)?;
+        }
+        Ok(bytes)
+    })
+}
+
+fn parse_lines_in_background(
+    reader: Option<impl Read + Send + 'static>,
+    progress_parser: Arc<Mutex<NixProgressParser>>,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let Some(reader) = reader else {
+            return Ok(());
+        };
+        for line in BufReader::new(reader).split(b'\n') {
+            let line = line?;
+            if let Ok(mut progress_parser) = progress_parser.lock() {
+                progress_parser.parse_line(&String::from_utf8_lossy(&line));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// How waiting on a command ended.
+enum WaitOutcome {
+    Exited(ExitStatus),
+    TimedOut,
+    Cancelled,
+}
+
+fn wait_with_options(
+    child: &mut Child,
+    options: &NixCommandOptions,
+    progress_parser: &Mutex<NixProgressParser>,
+) -> io::Result<WaitOutcome> {
+    let mut last_report: Option<(NixProgress, Instant)> = None;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(WaitOutcome::Exited(status));
+        }
+        let stop_outcome = if options
+            .cancelled
+            .as_ref()
+            .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+        {
+            Some(WaitOutcome::Cancelled)
+        } else if options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(WaitOutcome::TimedOut)
+        } else {
+            None
+        };
+        if let Some(stop_outcome) = stop_outcome {
+            stop_process_group(child)?;
+            return Ok(stop_outcome);
+        }
+        if let Some(progress_reporter) = &options.progress_reporter
+            && let Ok(progress) = progress_parser.lock().map(|parser| parser.progress())
+            && last_report.is_none_or(|(last_progress, last_reported_at)| {
+                progress != last_progress || last_reported_at.elapsed() >= PROGRESS_REPORT_INTERVAL
+            })
+        {
+            progress_reporter.report(&progress);
+            last_report = Some((progress, Instant::now()));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn stop_process_group(child: &mut Child) -> io::Result<()> {
+    // NB: The child was spawned as the leader of its own process group, so its ID is the group's
+    let process_group_id = child.id() as libc::pid_t;
+    // SAFETY: `killpg` doesn't access any memory, and only signals the child's process group
+    unsafe { libc::killpg(process_group_id, libc::SIGTERM) };
+    let grace_period_end = Instant::now() + TERMINATION_GRACE_PERIOD;
+    while child.try_wait()?.is_none() && Instant::now() < grace_period_end {
+        thread::sleep(POLL_INTERVAL);
+    }
+    // NB: Processes the child spawned may still be running even once the child itself has exited
+    // SAFETY: See above
+    unsafe { libc::killpg(process_group_id, libc::SIGKILL) };
+    child.wait()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{env, fs, os::unix::fs::PermissionsExt, path::PathBuf};
+    use std::{
+        env, fs,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        sync::{Arc, Mutex, atomic::AtomicBool},
+        time::{Duration, Instant},
+    };
 
-    use super::nix_program;
+    use super::{NixCommandOptions, nix_program};
+    use crate::{Error, NixProgress, NixProgressReporter};
 
     #[derive(Debug)]
     struct NixExecutable {
@@ -93,15 +288,23 @@ mod tests {
             r#"#! /bin/bash
 exit 0;"#,
         );
-        let stdout_content =
-            nix_program(nix_executable.file_path, Vec::<&str>::with_capacity(0)).unwrap();
+        let stdout_content = nix_program(
+            nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        )
+        .unwrap();
         assert_eq!(stdout_content, "");
     }
 
     #[test]
     fn test_run_process_failure() {
         let nix_executable = NixExecutable::new(r#"exit 1;"#);
-        let result = nix_program(&nix_executable.file_path, Vec::<&str>::with_capacity(0));
+        let result = nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        );
         assert_eq!(
             result.unwrap_err().to_string(),
             format!(
@@ -114,8 +317,125 @@ exit 0;"#,
     #[test]
     fn test_run_process_stdout() {
         let nix_executable = NixExecutable::new(r#"echo "echoed";"#);
-        let stdout_content =
-            nix_program(nix_executable.file_path, Vec::<&str>::with_capacity(0)).unwrap();
+        let stdout_content = nix_program(
+            nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        )
+        .unwrap();
         assert_eq!(stdout_content, "echoed\n");
     }
+
+    #[test]
+    fn test_run_process_timeout() {
+        let nix_executable = NixExecutable::new(r#"sleep 30 & sleep 30; wait;"#);
+        let started_at = Instant::now();
+        let result = nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions {
+                deadline: Some(started_at + Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("failed with error:\nprocess timed out")
+        );
+        assert!(matches!(err, Error::Timeout { .. }));
+        // NB: The background `sleep` holds on to stdout, so this only returns once it's stopped too
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_run_process_cancelled() {
+        let nix_executable = NixExecutable::new(r#"sleep 30;"#);
+        let result = nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions {
+                cancelled: Some(Arc::new(AtomicBool::new(true))),
+                ..Default::default()
+            },
+        );
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("failed with error:\nprocess was cancelled")
+        );
+        assert!(matches!(err, Error::Cancelled { .. }));
+    }
+
+    #[test]
+    fn test_run_process_failure_includes_stderr() {
+        let nix_executable = NixExecutable::new(r#"echo "error: no such flake" >&2; exit 1;"#);
+        let result = nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        );
+        let err = result.unwrap_err();
+        assert!(err.to_string().ends_with(
+            "failed with error:\nprocess exited unsuccessfully: exit status: 1\nerror: no such flake"
+        ));
+        assert!(
+            matches!(err, Error::EvalFailed { stderr, .. } if stderr == "error: no such flake")
+        );
+    }
+
+    #[test]
+    fn test_run_process_fetch_failure() {
+        let nix_executable = NixExecutable::new(
+            r#"echo "error: unable to download 'https://example.com/a.tar.gz': HTTP error 404" >&2; exit 1;"#,
+        );
+        let result = nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        );
+        assert!(matches!(result.unwrap_err(), Error::FetchFailed { .. }));
+    }
+
+    #[test]
+    fn test_run_process_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = nix_program(
+            dir.path().join("nix"),
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions::default(),
+        );
+        assert!(matches!(result.unwrap_err(), Error::NixNotFound { .. }));
+    }
+
+    #[test]
+    fn test_run_process_progress() {
+        let nix_executable = NixExecutable::new(
+            r#"[[ "$*" == *"--log-format internal-json"* ]] || exit 1;
+echo '@nix {"action":"start","id":1,"level":3,"type":105,"text":"building"}' >&2;
+echo '@nix {"action":"stop","id":1}' >&2;
+sleep 0.2;"#,
+        );
+        let reported = Arc::new(Mutex::new(vec![]));
+        let progress_reporter = {
+            let reported = Arc::clone(&reported);
+            NixProgressReporter::new(move |progress| reported.lock().unwrap().push(*progress))
+        };
+        nix_program(
+            &nix_executable.file_path,
+            Vec::<&str>::with_capacity(0),
+            &NixCommandOptions {
+                progress_reporter: Some(progress_reporter),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            reported.lock().unwrap().last(),
+            Some(&NixProgress {
+                builds_done: 1,
+                ..Default::default()
+            })
+        );
+    }
 }
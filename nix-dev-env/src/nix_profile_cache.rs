@@ -1,16 +1,30 @@
 use std::{
     ffi::OsStr,
-    fs,
+    fmt, fs,
     io::{self, Write},
     path::{Path, PathBuf},
     process,
-    time::SystemTime,
+    str::FromStr,
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant, SystemTime},
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sha1::{Digest, Sha1};
+use sha2::{Digest, Sha256};
 
-use crate::nix_command;
+use crate::{
+    Error, NixCacheKey, NixProgressReporter,
+    nix_command::{self, NixCommandOptions},
+};
+
+/// How long after a profile's usage was recorded it's recorded again, so that the metadata isn't
+/// rewritten on every prompt.
+const RECORD_USAGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How many directories the environment of a profile is kept for, see
+/// [`NixProfileCache::profile_env`].
+const MAX_PROFILE_ENVS: usize = 16;
 
 #[derive(Debug, Clone)]
 pub struct NixProfileCache {
@@ -18,9 +32,16 @@ pub struct NixProfileCache {
     flake_inputs_dir: PathBuf,
     flake_reference: FlakeReference,
     evaluation_mode: EvaluationMode,
+    gcroots_mode: GcRootsMode,
+    offline: bool,
+    extra_args: Vec<String>,
+    cache_key: Option<NixCacheKey>,
+    options: NixCommandOptions,
     files_to_watch: Vec<PathBuf>,
     profile_symlink: PathBuf,
     profile_rc_file: PathBuf,
+    profile_env_file: PathBuf,
+    profile_metadata_file: PathBuf,
 }
 
 impl NixProfileCache {
@@ -28,7 +49,7 @@ impl NixProfileCache {
         cache_dir: PathBuf,
         flake_reference: &str,
         evaluation_mode: EvaluationMode,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, Error> {
         let flake_inputs_dir = cache_dir.join("flake-inputs");
 
         let flake_reference = FlakeReference::parse(flake_reference)?;
@@ -40,28 +61,106 @@ impl NixProfileCache {
                 flake_dir.join("flake.lock"),
                 flake_dir.join("devshell.toml"),
             ]);
+            if !files_to_watch.iter().any(|file| file.exists()) {
+                return Err(Error::FlakeNotFound {
+                    flake_dir: flake_dir.clone(),
+                });
+            }
             hash_files(&files_to_watch)?
         } else {
-            hash_flake_reference(&flake_reference.flake_reference_string)?
+            hash_flake_reference(&flake_reference.flake_reference_string)
         };
 
         let profile_symlink = cache_dir.join(format!("flake-profile-{}", hash));
         let profile_rc_file = profile_symlink.with_extension("rc");
+        let profile_env_file = profile_symlink.with_extension("env");
+        let profile_metadata_file = profile_symlink.with_extension("json");
         Ok(Self {
             cache_dir,
             flake_inputs_dir,
             flake_reference,
             evaluation_mode,
+            gcroots_mode: GcRootsMode::default(),
+            offline: false,
+            extra_args: vec![],
+            cache_key: None,
+            options: NixCommandOptions::default(),
             files_to_watch,
             profile_symlink,
             profile_rc_file,
+            profile_env_file,
+            profile_metadata_file,
         })
     }
 
-    pub fn needs_update(&self) -> anyhow::Result<bool> {
+    /// Sets which store paths get pinned with garbage collector roots when the profile is built.
+    pub fn with_gcroots_mode(mut self, gcroots_mode: GcRootsMode) -> Self {
+        self.gcroots_mode = gcroots_mode;
+        self
+    }
+
+    /// Passes `--offline` to every Nix command, and reuses whatever profile is already cached.
+    ///
+    /// When the watched files have changed since the profile was cached, the previously cached
+    /// profile is used until it can be rebuilt while online.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        if offline
+            && !self.profile_rc_file.is_file()
+            && let Some(profile_rc_file) = find_cached_profile_rc(&self.cache_dir)
+        {
+            self.profile_symlink = profile_rc_file.with_extension("");
+            self.profile_env_file = profile_rc_file.with_extension("env");
+            self.profile_metadata_file = profile_rc_file.with_extension("json");
+            self.profile_rc_file = profile_rc_file;
+        }
+        self
+    }
+
+    /// Passes extra arguments to Nix when evaluating the flake, e.g. `--override-input`.
+    pub fn with_extra_args(mut self, extra_args: Vec<String>) -> Self {
+        self.extra_args = extra_args;
+        self
+    }
+
+    /// Records the key the cache directory was derived from in the profile's metadata.
+    pub fn with_cache_key(mut self, cache_key: NixCacheKey) -> Self {
+        self.cache_key = Some(cache_key);
+        self
+    }
+
+    /// Stops the Nix commands run while updating the profile once the deadline passes.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.options.deadline = deadline;
+        self
+    }
+
+    /// Stops the Nix commands run while updating the profile once the flag gets set, e.g. by a
+    /// signal handler.
+    ///
+    /// Stopping a command also stops every process it spawned.
+    pub fn with_cancellation(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.options.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Reports the progress of the Nix commands run while updating the profile, e.g. how many
+    /// derivations are building.
+    pub fn with_progress_reporter(mut self, progress_reporter: NixProgressReporter) -> Self {
+        self.options.progress_reporter = Some(progress_reporter);
+        self
+    }
+
+    pub fn needs_update(&self) -> Result<bool, Error> {
+        if self.offline && self.profile_rc_file.is_file() {
+            return Ok(false);
+        }
+
         let mut need_update = true;
 
-        if self.profile_rc_file.is_file() && self.profile_symlink.is_symlink() {
+        let profile_gcroot_exists =
+            self.gcroots_mode == GcRootsMode::None || self.profile_symlink.is_symlink();
+        if self.profile_rc_file.is_file() && profile_gcroot_exists {
             let profile_rc_mtime = fs::metadata(&self.profile_rc_file)?.modified()?;
 
             need_update = self.files_to_watch.iter().any(|file| {
@@ -72,10 +171,20 @@ impl NixProfileCache {
             });
         }
 
+        // NB: Updating removes the gcroots of the previous mode and adds those of the new one
+        if !need_update {
+            need_update = match NixProfileCacheMetadata::read(&self.profile_metadata_file) {
+                Ok(metadata) => {
+                    metadata.is_some_and(|metadata| metadata.gcroots_mode != self.gcroots_mode)
+                }
+                Err(_) => true,
+            };
+        }
+
         Ok(need_update)
     }
 
-    pub fn update(&self) -> anyhow::Result<()> {
+    pub fn update(&self) -> Result<(), Error> {
         clean_old_gcroots(&self.cache_dir, &self.flake_inputs_dir)?;
 
         let tmp_profile = self
@@ -86,34 +195,143 @@ impl NixProfileCache {
         if let Some(impure_arg) = self.impure_arg() {
             args.push(OsStr::new(impure_arg));
         }
+        if let Some(offline_arg) = self.offline_arg() {
+            args.push(OsStr::new(offline_arg));
+        }
+        args.extend(self.extra_args.iter().map(OsStr::new));
         args.extend_from_slice(&[
             OsStr::new("--no-write-lock-file"),
             OsStr::new("--profile"),
             tmp_profile.as_os_str(),
             OsStr::new(&self.flake_reference.flake_reference_string),
         ]);
-        let stdout_content = nix_command::nix(args)?;
+        let stdout_content = nix_command::nix(args, &self.options)?;
 
         fs::File::create(&self.profile_rc_file)?.write_all(stdout_content.as_bytes())?;
 
-        self.add_gcroot(&tmp_profile, &self.profile_symlink)?;
+        let mut gcroots = vec![];
+        if self.gcroots_mode != GcRootsMode::None {
+            self.add_gcroot(&tmp_profile, &self.profile_symlink)?;
+            gcroots.push(self.profile_symlink.clone());
+        }
         fs::remove_file(&tmp_profile)?;
 
-        if self.flake_reference.flake_dir.is_some() {
+        // Archiving a flake fetches all of its inputs, so only do it for local flakes
+        if self.gcroots_mode == GcRootsMode::ProfileAndInputs
+            && self.flake_reference.flake_dir.is_some()
+        {
             for input in self.get_flake_input_paths()? {
                 let store_path = PathBuf::from("/nix/store").join(&input);
                 let symlink_path = self.flake_inputs_dir.join(&input);
                 self.add_gcroot(&store_path, &symlink_path)?;
+                gcroots.push(symlink_path);
             }
         }
 
+        let now = SystemTime::now();
+        NixProfileCacheMetadata {
+            flake_reference: self.flake_reference.flake_reference_string.clone(),
+            evaluation_mode: self.evaluation_mode,
+            gcroots_mode: self.gcroots_mode,
+            cache_key: self.cache_key.clone(),
+            gcroots,
+            built_at: now,
+            last_used_at: now,
+        }
+        .write(&self.profile_metadata_file)?;
+
         Ok(())
     }
 
+    /// Marks the cached profile as used now, so that pruning by age keeps it around. Usage is only
+    /// recorded again once an hour has passed since it last was.
+    ///
+    /// Profiles cached before metadata was recorded get a metadata file written for them, using
+    /// the modification time of the rc file as the time they were built.
+    pub fn record_usage(&self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let metadata = match NixProfileCacheMetadata::read(&self.profile_metadata_file)? {
+            Some(metadata)
+                if now
+                    .duration_since(metadata.last_used_at)
+                    .is_ok_and(|elapsed| elapsed < RECORD_USAGE_INTERVAL) =>
+            {
+                return Ok(());
+            }
+            Some(metadata) => NixProfileCacheMetadata {
+                last_used_at: now,
+                ..metadata
+            },
+            None => {
+                let mut gcroots = vec![];
+                if self.profile_symlink.is_symlink() {
+                    gcroots.push(self.profile_symlink.clone());
+                }
+                if let Ok(read_dir) = fs::read_dir(&self.flake_inputs_dir) {
+                    gcroots.extend(read_dir.filter_map(Result::ok).map(|entry| entry.path()));
+                }
+                NixProfileCacheMetadata {
+                    flake_reference: self.flake_reference.flake_reference_string.clone(),
+                    evaluation_mode: self.evaluation_mode,
+                    gcroots_mode: self.gcroots_mode,
+                    cache_key: self.cache_key.clone(),
+                    gcroots,
+                    built_at: fs::metadata(&self.profile_rc_file)?.modified()?,
+                    last_used_at: now,
+                }
+            }
+        };
+        metadata.write(&self.profile_metadata_file)
+    }
+
     pub fn profile_rc(&self) -> &Path {
         &self.profile_rc_file
     }
 
+    /// Where the environment resulting from sourcing the rc file in `dir` can be persisted, so that
+    /// it only needs to be captured once per build. It's removed whenever the profile is updated.
+    ///
+    /// Shell hooks can depend on the directory they're sourced in, e.g. by reading `$PWD`, so
+    /// there's a file for each directory.
+    pub fn profile_env(&self, dir: &Path) -> PathBuf {
+        let dir_hash = format!("{:x}", Sha256::digest(dir.as_os_str().as_encoded_bytes()));
+        self.profile_env_file
+            .with_extension(format!("{}.env", &dir_hash[..16]))
+    }
+
+    /// Removes the environments persisted for the directories they were least recently captured
+    /// in, so that only the ones of the last few directories are kept.
+    pub fn remove_old_profile_envs(&self) -> Result<(), Error> {
+        let Some(profile_name) = self.profile_symlink.file_name().and_then(OsStr::to_str) else {
+            return Ok(());
+        };
+        let profile_env_prefix = format!("{profile_name}.");
+        let mut profile_envs = fs::read_dir(&self.cache_dir)?
+            .filter_map(Result::ok)
+            .filter(|dir_entry| {
+                dir_entry.file_name().to_str().is_some_and(|file_name| {
+                    file_name.starts_with(&profile_env_prefix) && file_name.ends_with(".env")
+                })
+            })
+            .map(|dir_entry| {
+                let mtime = dir_entry.metadata().and_then(|meta| meta.modified());
+                (mtime.unwrap_or(SystemTime::UNIX_EPOCH), dir_entry.path())
+            })
+            .collect::<Vec<_>>();
+        profile_envs.sort_by(|a, b| b.cmp(a));
+        for (_, path) in profile_envs.into_iter().skip(MAX_PROFILE_ENVS) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn profile_metadata(&self) -> &Path {
+        &self.profile_metadata_file
+    }
+
     fn impure_arg(&self) -> Option<&str> {
         match self.evaluation_mode {
             EvaluationMode::Impure => Some("--impure"),
@@ -121,25 +339,36 @@ impl NixProfileCache {
         }
     }
 
-    fn add_gcroot(&self, store_path: &Path, symlink: &Path) -> anyhow::Result<()> {
+    fn offline_arg(&self) -> Option<&str> {
+        self.offline.then_some("--offline")
+    }
+
+    fn add_gcroot(&self, store_path: &Path, symlink: &Path) -> Result<(), Error> {
         let mut args = vec![OsStr::new("build")];
         if let Some(impure_arg) = self.impure_arg() {
             args.push(OsStr::new(impure_arg));
         }
+        if let Some(offline_arg) = self.offline_arg() {
+            args.push(OsStr::new(offline_arg));
+        }
         args.extend_from_slice(&[
             OsStr::new("--out-link"),
             symlink.as_os_str(),
             store_path.as_os_str(),
         ]);
-        nix_command::nix(args)?;
+        nix_command::nix(args, &self.options)?;
         Ok(())
     }
 
-    fn get_flake_input_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+    fn get_flake_input_paths(&self) -> Result<Vec<PathBuf>, Error> {
         let mut args = vec!["flake", "archive"];
         if let Some(impure_arg) = self.impure_arg() {
             args.push(impure_arg);
         }
+        if let Some(offline_arg) = self.offline_arg() {
+            args.push(offline_arg);
+        }
+        args.extend(self.extra_args.iter().map(String::as_str));
         args.extend_from_slice(&[
             "--json",
             "--no-write-lock-file",
@@ -149,18 +378,160 @@ impl NixProfileCache {
                 .map(|(flake_reference_without_hash, ..)| flake_reference_without_hash)
                 .unwrap_or(&self.flake_reference.flake_reference_string),
         ]);
-        let stdout_content = nix_command::nix(args)?;
+        let stdout_content = nix_command::nix(args, &self.options)?;
         let json = serde_json::from_str::<Value>(&stdout_content)?;
         Ok(get_paths_from_doc(&json))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EvaluationMode {
     Impure,
     Pure,
 }
 
+/// Which store paths of a cached profile are protected from `nix store gc`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GcRootsMode {
+    /// Nothing is pinned, so the profile gets rebuilt once its store paths are collected.
+    #[serde(rename = "none")]
+    None,
+    /// Only the profile itself is pinned.
+    #[serde(rename = "profile")]
+    Profile,
+    /// The profile and, for local flakes, every flake input are pinned.
+    #[default]
+    #[serde(rename = "profile+inputs")]
+    ProfileAndInputs,
+}
+
+impl fmt::Display for GcRootsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Profile => "profile",
+            Self::ProfileAndInputs => "profile+inputs",
+        })
+    }
+}
+
+impl FromStr for GcRootsMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "profile" => Ok(Self::Profile),
+            "profile+inputs" => Ok(Self::ProfileAndInputs),
+            _ => Err(Error::InvalidGcRootsMode {
+                value: String::from(s),
+            }),
+        }
+    }
+}
+
+/// Information recorded next to each cached profile, so that cached profiles can be attributed to
+/// the flake reference they were built from and pruned once they're no longer used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NixProfileCacheMetadata {
+    pub flake_reference: String,
+    pub evaluation_mode: EvaluationMode,
+    #[serde(default)]
+    pub gcroots_mode: GcRootsMode,
+    /// Missing for profiles cached before cache keys were recorded.
+    #[serde(default)]
+    pub cache_key: Option<NixCacheKey>,
+    /// Symlinks registered as Nix garbage collector roots for the profile and its flake inputs.
+    pub gcroots: Vec<PathBuf>,
+    pub built_at: SystemTime,
+    pub last_used_at: SystemTime,
+}
+
+impl NixProfileCacheMetadata {
+    /// Finds the metadata of the profile cached in `cache_dir`, if there is one.
+    ///
+    /// When there are several, e.g. because an update was interrupted, the most recently written
+    /// one is used.
+    pub fn find(cache_dir: &Path) -> Result<Option<Self>, Error> {
+        let read_dir = match fs::read_dir(cache_dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let newest_path = read_dir
+            .filter_map(Result::ok)
+            .map(|dir_entry| dir_entry.path())
+            .filter(|path| {
+                path.extension() == Some(OsStr::new("json"))
+                    && path
+                        .file_name()
+                        .and_then(OsStr::to_str)
+                        .is_some_and(|file_name| file_name.starts_with("flake-profile-"))
+            })
+            .max_by_key(|path| {
+                let mtime = fs::metadata(path).and_then(|meta| meta.modified());
+                (mtime.unwrap_or(SystemTime::UNIX_EPOCH), path.clone())
+            });
+        match newest_path {
+            Some(path) => Self::read(&path),
+            None => Ok(None),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Option<Self>, Error> {
+        match fs::read(path) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Total size in bytes of the store paths kept alive by the garbage collector roots.
+    pub fn pinned_size(&self) -> Result<u64, Error> {
+        let mut args = vec![
+            OsStr::new("path-info"),
+            OsStr::new("--json"),
+            OsStr::new("--recursive"),
+        ];
+        args.extend(
+            self.gcroots
+                .iter()
+                .filter(|gcroot| gcroot.exists())
+                .map(|gcroot| gcroot.as_os_str()),
+        );
+        if args.len() == 3 {
+            return Ok(0);
+        }
+        let stdout_content = nix_command::nix(args, &NixCommandOptions::default())?;
+        let json = serde_json::from_str::<Value>(&stdout_content)?;
+        let path_infos: Vec<&Value> = match &json {
+            // Nix 2.19 and newer key path info by store path
+            Value::Object(path_infos) => path_infos.values().collect(),
+            Value::Array(path_infos) => path_infos.iter().collect(),
+            _ => {
+                return Err(Error::UnexpectedOutput {
+                    command: String::from("nix path-info"),
+                });
+            }
+        };
+        Ok(path_infos
+            .into_iter()
+            .filter_map(|path_info| path_info.get("narSize").and_then(Value::as_u64))
+            .sum())
+    }
+
+    /// Whether every garbage collector root still points to an existing store path.
+    pub fn gcroots_alive(&self) -> bool {
+        self.gcroots.iter().all(|gcroot| gcroot.exists())
+    }
+}
+
 #[derive(Debug, Clone)]
 struct FlakeReference {
     pub flake_reference_string: String,
@@ -168,17 +539,25 @@ struct FlakeReference {
 }
 
 impl FlakeReference {
-    pub fn parse(flake_reference: &str) -> anyhow::Result<Self> {
+    pub fn parse(flake_reference: &str) -> Result<Self, Error> {
         let mut flake_reference_iter = flake_reference.split('#');
-        let flake_uri = flake_reference_iter
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("Missing flake URI"))?;
+        let flake_uri =
+            flake_reference_iter
+                .next()
+                .ok_or_else(|| Error::InvalidFlakeReference {
+                    flake_reference: String::from(flake_reference),
+                    reason: String::from("missing flake URI"),
+                })?;
         let flake_specifier = flake_reference_iter.next();
 
         let expanded_flake_reference_and_flake_dir =
             if FlakeReference::is_path_type(flake_reference) {
                 let flake_dir_str =
-                    shellexpand::full(flake_uri.strip_prefix("path:").unwrap_or(flake_uri))?;
+                    shellexpand::full(flake_uri.strip_prefix("path:").unwrap_or(flake_uri))
+                        .map_err(|e| Error::InvalidFlakeReference {
+                            flake_reference: String::from(flake_reference),
+                            reason: e.to_string(),
+                        })?;
                 let expanded_flake_reference = format!(
                     "{}{}{}",
                     &flake_dir_str,
@@ -209,33 +588,56 @@ impl FlakeReference {
     }
 }
 
-fn hash_files(filenames: impl AsRef<[PathBuf]>) -> anyhow::Result<String> {
-    let (hasher, no_files) = filenames
+fn hash_files(filenames: impl AsRef<[PathBuf]>) -> io::Result<String> {
+    let hasher = filenames
         .as_ref()
         .iter()
         .filter(|f| {
             // TODO: figure out what to do if the file doesn't exist
             f.exists()
         })
-        .try_fold((Sha1::new(), true), |(mut acc, ..), f| {
+        .try_fold(Sha256::new(), |mut acc, f| {
             acc.update(fs::read(f)?);
-            anyhow::Result::<(Sha1, bool)>::Ok((acc, false))
+            io::Result::Ok(acc)
         })?;
 
-    if no_files {
-        return Err(anyhow::anyhow!("No files found to hash"));
-    }
-
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-fn hash_flake_reference(flake_reference: &str) -> anyhow::Result<String> {
-    let mut hasher = Sha1::new();
+fn hash_flake_reference(flake_reference: &str) -> String {
+    let mut hasher = Sha256::new();
     hasher.update(flake_reference);
-    Ok(format!("{:x}", hasher.finalize()))
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether the directory holds files [`NixProfileCache`] writes, which are all named
+/// `flake-profile-*`.
+pub fn has_cached_profile_files(cache_dir: &Path) -> bool {
+    fs::read_dir(cache_dir).is_ok_and(|mut read_dir| {
+        read_dir.any(|dir_entry| {
+            dir_entry.is_ok_and(|dir_entry| {
+                dir_entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|file_name| file_name.starts_with("flake-profile-"))
+            })
+        })
+    })
+}
+
+fn find_cached_profile_rc(cache_dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(cache_dir).ok()?.find_map(|dir_entry| {
+        let path = dir_entry.ok()?.path();
+        let is_profile_rc = path.extension() == Some(OsStr::new("rc"))
+            && path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|file_name| file_name.starts_with("flake-profile-"));
+        is_profile_rc.then_some(path)
+    })
 }
 
-fn clean_old_gcroots(cache_dir: &Path, flake_inputs_dir: &Path) -> anyhow::Result<()> {
+fn clean_old_gcroots(cache_dir: &Path, flake_inputs_dir: &Path) -> Result<(), Error> {
     let res = fs::remove_dir_all(cache_dir);
     if let Err(e) = &res
         && e.kind() != io::ErrorKind::NotFound
@@ -295,7 +697,7 @@ mod tests {
     fn test_hash_one() {
         assert_eq!(
             hash_files([TEST_FILE.path().to_path_buf()]).unwrap(),
-            "6ead949bf4bcae230b9ed9cd11e578e34ce9f9ea"
+            "ad739a450dfc81900e69a3a2557b41ec93a195f68c777e10ece38dd70f7e52ba"
         );
     }
 
@@ -307,7 +709,7 @@ mod tests {
                 TEST_FILE.path().to_path_buf(),
             ])
             .unwrap(),
-            "f109b7892a541ed1e3cf39314cd25d21042b984f"
+            "14c8bdfa069b2e3ca81abd34f50e130718d7743498216f07be6a814f3c5793cf"
         );
     }
 
@@ -315,7 +717,7 @@ mod tests {
     fn test_hash_filters_nonexistent() {
         assert_eq!(
             hash_files([TEST_FILE.path().to_path_buf(), PathBuf::from("FOOBARBAZ"),]).unwrap(),
-            "6ead949bf4bcae230b9ed9cd11e578e34ce9f9ea"
+            "ad739a450dfc81900e69a3a2557b41ec93a195f68c777e10ece38dd70f7e52ba"
         );
     }
 
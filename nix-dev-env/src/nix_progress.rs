@@ -0,0 +1,153 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde_json::Value;
+
+/// Prefix of every line Nix logs with `--log-format internal-json`.
+const INTERNAL_JSON_PREFIX: &str = "@nix ";
+
+/// Activity types, from Nix's `src/libutil/logging.hh`.
+const ACTIVITY_TYPE_FILE_TRANSFER: u64 = 101;
+const ACTIVITY_TYPE_BUILD: u64 = 105;
+
+/// Log level of error messages.
+const LOG_LEVEL_ERROR: u64 = 0;
+
+/// What a running Nix command is busy with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NixProgress {
+    pub builds_running: usize,
+    pub builds_done: usize,
+    pub downloads_running: usize,
+    pub downloads_done: usize,
+}
+
+/// Called with the progress of every Nix command run while building a profile.
+#[derive(Clone)]
+pub struct NixProgressReporter(Arc<dyn Fn(&NixProgress) + Send + Sync>);
+
+impl NixProgressReporter {
+    pub fn new(report: impl Fn(&NixProgress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(report))
+    }
+
+    pub(crate) fn report(&self, progress: &NixProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for NixProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("NixProgressReporter").finish_non_exhaustive()
+    }
+}
+
+/// Follows the activities Nix logs with `--log-format internal-json`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NixProgressParser {
+    /// Types of the activities that were started but haven't stopped yet, keyed by ID.
+    activities: HashMap<u64, u64>,
+    progress: NixProgress,
+    error_messages: Vec<String>,
+}
+
+impl NixProgressParser {
+    pub(crate) fn parse_line(&mut self, line: &str) {
+        let Some(Ok(Value::Object(entry))) = line
+            .strip_prefix(INTERNAL_JSON_PREFIX)
+            .map(serde_json::from_str::<Value>)
+        else {
+            // NB: Anything that isn't JSON, e.g. output from a builder, is kept for error messages
+            if !line.trim().is_empty() {
+                self.error_messages.push(String::from(line));
+            }
+            return;
+        };
+        let id = entry.get("id").and_then(Value::as_u64);
+        match (entry.get("action").and_then(Value::as_str), id) {
+            (Some("start"), Some(id)) => {
+                let activity_type = entry.get("type").and_then(Value::as_u64).unwrap_or(0);
+                match activity_type {
+                    ACTIVITY_TYPE_BUILD => self.progress.builds_running += 1,
+                    ACTIVITY_TYPE_FILE_TRANSFER => self.progress.downloads_running += 1,
+                    _ => {}
+                }
+                self.activities.insert(id, activity_type);
+            }
+            (Some("stop"), Some(id)) => match self.activities.remove(&id) {
+                Some(ACTIVITY_TYPE_BUILD) => {
+                    self.progress.builds_running -= 1;
+                    self.progress.builds_done += 1;
+                }
+                Some(ACTIVITY_TYPE_FILE_TRANSFER) => {
+                    self.progress.downloads_running -= 1;
+                    self.progress.downloads_done += 1;
+                }
+                _ => {}
+            },
+            (Some("msg"), _)
+                if entry.get("level").and_then(Value::as_u64) == Some(LOG_LEVEL_ERROR) =>
+            {
+                if let Some(msg) = entry.get("msg").and_then(Value::as_str) {
+                    self.error_messages.push(String::from(msg));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn progress(&self) -> NixProgress {
+        self.progress
+    }
+
+    /// Errors Nix logged, along with anything else it printed that wasn't JSON.
+    pub(crate) fn error_messages(&self) -> String {
+        self.error_messages.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NixProgress, NixProgressParser};
+
+    #[test]
+    fn test_parse_builds_and_downloads() {
+        let mut parser = NixProgressParser::default();
+        for line in [
+            r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building 'a.drv'"}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"type":105,"text":"building 'b.drv'"}"#,
+            r#"@nix {"action":"start","id":3,"level":4,"type":101,"text":"downloading 'c'"}"#,
+            r#"@nix {"action":"result","id":1,"type":101,"fields":["compiling"]}"#,
+            r#"@nix {"action":"stop","id":1}"#,
+            r#"@nix {"action":"start","id":4,"level":3,"type":108,"text":"copying path"}"#,
+        ] {
+            parser.parse_line(line);
+        }
+
+        assert_eq!(
+            parser.progress(),
+            NixProgress {
+                builds_running: 1,
+                builds_done: 1,
+                downloads_running: 1,
+                downloads_done: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_error_messages() {
+        let mut parser = NixProgressParser::default();
+        for line in [
+            r#"@nix {"action":"msg","level":3,"msg":"evaluating"}"#,
+            r#"@nix {"action":"msg","level":0,"msg":"error: flake has no devShell"}"#,
+            "builder output",
+        ] {
+            parser.parse_line(line);
+        }
+
+        assert_eq!(
+            parser.error_messages(),
+            "error: flake has no devShell\nbuilder output"
+        );
+    }
+}
@@ -4,7 +4,10 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use semver::{Comparator, Op, Prerelease, Version, VersionReq};
 
-use crate::nix_command;
+use crate::{
+    Error,
+    nix_command::{self, NixCommandOptions},
+};
 
 static REQUIRED_NIX_VERSION: Lazy<VersionReq> = Lazy::new(|| VersionReq {
     comparators: vec![Comparator {
@@ -18,26 +21,32 @@ static REQUIRED_NIX_VERSION: Lazy<VersionReq> = Lazy::new(|| VersionReq {
 
 static SEMVER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"([0-9]+\.[0-9]+\.[0-9]+)").unwrap());
 
-pub fn check_nix_version() -> anyhow::Result<()> {
+/// Checks that the installed `nix` supports flakes, returning its version.
+pub fn check_nix_version() -> Result<Version, Error> {
     check_nix_program_version(OsStr::new("nix"))
 }
 
-fn check_nix_program_version(nix_executable_path: impl AsRef<OsStr>) -> anyhow::Result<()> {
-    let stdout_content = nix_command::nix_program(nix_executable_path.as_ref(), ["--version"])?;
+fn check_nix_program_version(nix_executable_path: impl AsRef<OsStr>) -> Result<Version, Error> {
+    let stdout_content = nix_command::nix_program(
+        nix_executable_path.as_ref(),
+        ["--version"],
+        &NixCommandOptions::default(),
+    )?;
 
-    if stdout_content.is_empty() {
-        return Err(anyhow::format_err!("`nix --version` failed to execute."));
-    }
-
-    let nix_version_match = SEMVER_RE
+    let nix_version = SEMVER_RE
         .find(&stdout_content)
-        .ok_or_else(|| anyhow::format_err!("SemVer from `nix --version` could not be found."))?;
-    let nix_version = Version::parse(nix_version_match.as_str())?;
+        .and_then(|nix_version_match| Version::parse(nix_version_match.as_str()).ok())
+        .ok_or_else(|| Error::NixVersionUnknown {
+            output: stdout_content.clone(),
+        })?;
 
     if REQUIRED_NIX_VERSION.matches(&nix_version) {
-        Ok(())
+        Ok(nix_version)
     } else {
-        Err(anyhow::format_err!("`nix` version too old for flakes."))
+        Err(Error::NixTooOld {
+            found: nix_version,
+            required: REQUIRED_NIX_VERSION.clone(),
+        })
     }
 }
 
@@ -45,7 +54,10 @@ fn check_nix_program_version(nix_executable_path: impl AsRef<OsStr>) -> anyhow::
 mod tests {
     use std::{env, fs, os::unix::fs::PermissionsExt, path::PathBuf};
 
+    use semver::Version;
+
     use super::check_nix_program_version;
+    use crate::Error;
 
     #[derive(Debug)]
     struct NixExecutable {
@@ -87,12 +99,10 @@ mod tests {
     #[test]
     fn test_error_on_empty_stdout() {
         let nix_executable = NixExecutable::new(r#"printf "";"#);
-        assert_eq!(
-            check_nix_program_version(nix_executable.file_path)
-                .unwrap_err()
-                .to_string(),
-            "`nix --version` failed to execute."
-        );
+        assert!(matches!(
+            check_nix_program_version(nix_executable.file_path).unwrap_err(),
+            Error::NixVersionUnknown { output } if output.is_empty()
+        ));
     }
 
     #[test]
@@ -113,7 +123,7 @@ mod tests {
             check_nix_program_version(nix_executable.file_path)
                 .unwrap_err()
                 .to_string(),
-            "`nix` version too old for flakes."
+            "`nix` version 0.0.0 too old for flakes, >=2.10.0 is required."
         );
     }
 
@@ -126,6 +136,9 @@ mod tests {
     #[test]
     fn test_version_matches_newer() {
         let nix_executable = NixExecutable::new(r#"echo "nix (Nix) 2.30.0";"#);
-        check_nix_program_version(nix_executable.file_path).unwrap();
+        assert_eq!(
+            check_nix_program_version(nix_executable.file_path).unwrap(),
+            Version::new(2, 30, 0)
+        );
     }
 }
@@ -3,12 +3,18 @@ use std::{
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process::{self, Command},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
 };
 
-use nix_dev_env::NixProfileCache;
+use nix_dev_env::{EvaluationMode, GcRootsMode, NixProfileCache, NixProfileCacheMetadata};
 use tempfile::{TempDir, tempdir, tempdir_in};
 
+// NB: Tests change `PATH` for the whole process, so they can't run concurrently
+static TEST_ENV_LOCK: Mutex<()> = Mutex::new(());
+
 struct TestEnv {
+    _guard: MutexGuard<'static, ()>,
     _work_dir: TempDir,
     cache_dir: TempDir,
     flake_dir: TempDir,
@@ -18,6 +24,9 @@ struct TestEnv {
 
 impl TestEnv {
     fn new() -> Self {
+        let guard = TEST_ENV_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
         let work_dir = tempdir().unwrap();
         let cache_dir = tempdir_in(work_dir.path()).unwrap();
         let bin_dir = work_dir.path().join("bin");
@@ -73,6 +82,7 @@ exit 0
         }
 
         Self {
+            _guard: guard,
             _work_dir: work_dir,
             cache_dir,
             flake_dir,
@@ -109,7 +119,7 @@ impl Drop for TestEnv {
 
 const NIXPKGS_DIR_NAME: &str = "yfzmnk75f009yb7b542kf4r7qaqq9kid-source";
 const NIXPKGS_PATH: &str = "/nix/store/yfzmnk75f009yb7b542kf4r7qaqq9kid-source";
-const PROFILE_HASH: &str = "bf21a9e8fbc5a3846fb05b4fa0859e0917b2202f";
+const PROFILE_HASH: &str = "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
 
 fn run_profile_cache_test(hash_fragment: Option<&str>) {
     let env = TestEnv::new();
@@ -135,7 +145,7 @@ fn run_profile_cache_test(hash_fragment: Option<&str>) {
     let nix_profile_cache = NixProfileCache::new(
         PathBuf::from(env.cache_dir_path()),
         &flake_reference,
-        nix_dev_env::EvaluationMode::Impure,
+        EvaluationMode::Impure,
     )
     .unwrap();
 
@@ -149,6 +159,41 @@ fn run_profile_cache_test(hash_fragment: Option<&str>) {
             .is_file()
     );
 
+    let metadata = NixProfileCacheMetadata::find(env.cache_dir_path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.flake_reference, expected_print_dev_env_ref);
+    assert_eq!(metadata.evaluation_mode, EvaluationMode::Impure);
+    assert_eq!(
+        metadata.gcroots,
+        [
+            profile_symlink.clone(),
+            flake_inputs_path.join(NIXPKGS_DIR_NAME)
+        ]
+    );
+    assert_eq!(metadata.built_at, metadata.last_used_at);
+
+    // NB: Usage is only recorded again once it's been a while since it last was
+    nix_profile_cache.record_usage().unwrap();
+    let used_metadata = NixProfileCacheMetadata::read(nix_profile_cache.profile_metadata())
+        .unwrap()
+        .unwrap();
+    assert_eq!(used_metadata, metadata);
+
+    let last_used_at = metadata.last_used_at - Duration::from_secs(2 * 60 * 60);
+    NixProfileCacheMetadata {
+        last_used_at,
+        ..metadata.clone()
+    }
+    .write(nix_profile_cache.profile_metadata())
+    .unwrap();
+    nix_profile_cache.record_usage().unwrap();
+    let used_metadata = NixProfileCacheMetadata::read(nix_profile_cache.profile_metadata())
+        .unwrap()
+        .unwrap();
+    assert_eq!(used_metadata.built_at, metadata.built_at);
+    assert!(used_metadata.last_used_at > last_used_at);
+
     let exit_status = Command::new("bash")
         .args([nix_profile_cache.profile_rc()])
         .spawn()
@@ -193,3 +238,126 @@ fn test_nix_profile_cache() {
 fn test_nix_profile_cache_with_hash_fragment() {
     run_profile_cache_test(Some("myDevShell"));
 }
+
+#[test]
+fn test_nix_profile_cache_gcroots_modes() {
+    for gcroots_mode in [GcRootsMode::None, GcRootsMode::Profile] {
+        let env = TestEnv::new();
+
+        let flake_dir_str = env.flake_dir_path().to_string_lossy();
+        let tmp_profile = env
+            .cache_dir_path()
+            .join(format!("flake-tmp-profile.{}", process::id()));
+        let profile_symlink = env
+            .cache_dir_path()
+            .join(format!("flake-profile-{}", PROFILE_HASH));
+
+        let nix_profile_cache = NixProfileCache::new(
+            PathBuf::from(env.cache_dir_path()),
+            &format!("path:{flake_dir_str}"),
+            EvaluationMode::Impure,
+        )
+        .unwrap()
+        .with_gcroots_mode(gcroots_mode);
+
+        assert!(nix_profile_cache.needs_update().unwrap());
+        nix_profile_cache.update().unwrap();
+        assert!(!nix_profile_cache.needs_update().unwrap());
+
+        let mut expected_log_lines = vec![format!(
+            "--extra-experimental-features nix-command flakes print-dev-env --impure --no-write-lock-file --profile {} {}",
+            tmp_profile.to_string_lossy(),
+            flake_dir_str
+        )];
+        let mut expected_gcroots = vec![];
+        if gcroots_mode == GcRootsMode::Profile {
+            expected_log_lines.push(format!(
+                "--extra-experimental-features nix-command flakes build --impure --out-link {} {}",
+                profile_symlink.to_string_lossy(),
+                tmp_profile.to_string_lossy()
+            ));
+            expected_gcroots.push(profile_symlink.clone());
+        }
+        assert_eq!(env.read_log_lines(), expected_log_lines);
+
+        let metadata = NixProfileCacheMetadata::find(env.cache_dir_path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.gcroots_mode, gcroots_mode);
+        assert_eq!(metadata.gcroots, expected_gcroots);
+    }
+}
+
+#[test]
+fn test_nix_profile_cache_changing_gcroots_mode_reroots_profile() {
+    let env = TestEnv::new();
+
+    let flake_reference = format!("path:{}", env.flake_dir_path().to_string_lossy());
+    let profile_symlink = env
+        .cache_dir_path()
+        .join(format!("flake-profile-{}", PROFILE_HASH));
+    let new_nix_profile_cache = |gcroots_mode| {
+        NixProfileCache::new(
+            PathBuf::from(env.cache_dir_path()),
+            &flake_reference,
+            EvaluationMode::Impure,
+        )
+        .unwrap()
+        .with_gcroots_mode(gcroots_mode)
+    };
+
+    let nix_profile_cache = new_nix_profile_cache(GcRootsMode::Profile);
+    nix_profile_cache.update().unwrap();
+    assert!(profile_symlink.is_symlink());
+
+    let nix_profile_cache = new_nix_profile_cache(GcRootsMode::None);
+    assert!(nix_profile_cache.needs_update().unwrap());
+    nix_profile_cache.update().unwrap();
+    assert!(!nix_profile_cache.needs_update().unwrap());
+    assert!(!profile_symlink.is_symlink());
+    let metadata = NixProfileCacheMetadata::find(env.cache_dir_path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.gcroots_mode, GcRootsMode::None);
+    assert_eq!(metadata.gcroots, Vec::<PathBuf>::new());
+
+    let nix_profile_cache = new_nix_profile_cache(GcRootsMode::Profile);
+    assert!(nix_profile_cache.needs_update().unwrap());
+    nix_profile_cache.update().unwrap();
+    assert!(profile_symlink.is_symlink());
+}
+
+#[test]
+fn test_nix_profile_cache_offline_reuses_cached_profile() {
+    let env = TestEnv::new();
+
+    let flake_reference = format!("path:{}", env.flake_dir_path().to_string_lossy());
+    let nix_profile_cache = NixProfileCache::new(
+        PathBuf::from(env.cache_dir_path()),
+        &flake_reference,
+        EvaluationMode::Impure,
+    )
+    .unwrap();
+    nix_profile_cache.update().unwrap();
+
+    fs::write(env.flake_dir_path().join("flake.nix"), "{ }").unwrap();
+
+    let changed_nix_profile_cache = NixProfileCache::new(
+        PathBuf::from(env.cache_dir_path()),
+        &flake_reference,
+        EvaluationMode::Impure,
+    )
+    .unwrap();
+    assert!(changed_nix_profile_cache.needs_update().unwrap());
+    assert_ne!(
+        changed_nix_profile_cache.profile_rc(),
+        nix_profile_cache.profile_rc()
+    );
+
+    let offline_nix_profile_cache = changed_nix_profile_cache.with_offline(true);
+    assert!(!offline_nix_profile_cache.needs_update().unwrap());
+    assert_eq!(
+        offline_nix_profile_cache.profile_rc(),
+        nix_profile_cache.profile_rc()
+    );
+}